@@ -0,0 +1,334 @@
+//! Access control of the proxy listener.
+//!
+//! Requests are checked against the access control of the matched rule, or the
+//! global one, before they are resolved. Clients are identified by their address,
+//! which is taken from `X-Forwarded-For` if the request comes from a trusted
+//! proxy, and by HTTP Basic credentials or bearer tokens.
+use crate::error::{Error, Result};
+use crate::metric;
+use crate::settings;
+
+use base64::Engine;
+use ipnet::IpNet;
+use metrics::increment_counter;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use warp::http::header::WWW_AUTHENTICATE;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::Reply;
+
+/// Maximum number of verified Basic credentials remembered, so that their
+/// hashes are not verified again for every request
+const MAX_VERIFIED: usize = 1024;
+
+/// Realm of the Basic authentication challenge
+const REALM: &str = "mirror-cache";
+
+pub struct AccessControl {
+    /// User -> bcrypt hash of the password
+    users: HashMap<String, String>,
+    tokens: Vec<String>,
+    allow: Vec<IpNet>,
+    /// SHA-256 digests of verified `Authorization` headers
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+/// A rejected request
+#[derive(Debug)]
+pub enum Denied {
+    /// The client address is not allowed
+    Forbidden,
+    /// Credentials are missing or invalid. Carries the `WWW-Authenticate` challenge.
+    Unauthorized(String),
+}
+
+impl warp::reject::Reject for Denied {}
+
+impl Denied {
+    pub fn to_response(&self) -> Response {
+        match self {
+            Denied::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            Denied::Unauthorized(challenge) => warp::reply::with_header(
+                StatusCode::UNAUTHORIZED,
+                WWW_AUTHENTICATE,
+                challenge.as_str(),
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// Parse addresses in CIDR notation or plain addresses.
+pub fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>> {
+    networks
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| Error::ConfigInvalid(format!("invalid network: {}", network)))
+        })
+        .collect()
+}
+
+fn is_trusted(ip: &IpAddr, trusted: &[IpNet]) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+/// Whether the peer is a trusted proxy, whose `X-Forwarded-*` headers are honoured
+pub fn is_trusted_proxy(remote: Option<SocketAddr>, trusted: &[IpNet]) -> bool {
+    remote.is_some_and(|remote| is_trusted(&remote.ip().to_canonical(), trusted))
+}
+
+/// The address of the client. If the peer is a trusted proxy, the rightmost
+/// untrusted address in `X-Forwarded-For` is taken.
+pub fn client_ip(
+    remote: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpNet],
+) -> Option<IpAddr> {
+    let mut ip = remote?.ip().to_canonical();
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !is_trusted(&ip, trusted) {
+                break;
+            }
+            match hop.trim().parse::<IpAddr>() {
+                Ok(hop) => ip = hop.to_canonical(),
+                // an invalid hop is not trusted to tell the client either
+                Err(_) => break,
+            }
+        }
+    }
+    Some(ip)
+}
+
+impl AccessControl {
+    pub fn new(config: &settings::Access) -> Result<Self> {
+        let mut users = HashMap::new();
+        if let Some(path) = &config.basic_auth_file {
+            for line in std::fs::read_to_string(path)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (user, hash) = line.split_once(':').ok_or_else(|| {
+                    Error::ConfigInvalid(format!("invalid line in {}: {}", path, line))
+                })?;
+                if !hash.starts_with("$2") {
+                    return Err(Error::ConfigInvalid(format!(
+                        "password of {} in {} is not hashed with bcrypt",
+                        user, path
+                    )));
+                }
+                users.insert(user.to_string(), hash.to_string());
+            }
+        }
+        let mut tokens = config.bearer_tokens.clone().unwrap_or_default();
+        if let Some(path) = &config.bearer_tokens_file {
+            tokens.extend(
+                std::fs::read_to_string(path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(String::from),
+            );
+        }
+        Ok(Self {
+            users,
+            tokens,
+            allow: parse_networks(config.allow.as_deref().unwrap_or_default())?,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Check a request from `client_ip` with the `Authorization` header.
+    pub async fn check(
+        &self,
+        client_ip: Option<IpAddr>,
+        authorization: Option<&str>,
+    ) -> std::result::Result<(), Denied> {
+        if !self.allow.is_empty()
+            && !client_ip.is_some_and(|ip| self.allow.iter().any(|network| network.contains(&ip)))
+        {
+            increment_counter!(metric::CNT_ACCESS_DENIED);
+            return Err(Denied::Forbidden);
+        }
+        if self.users.is_empty() && self.tokens.is_empty() {
+            return Ok(());
+        }
+        let authorized = match authorization.and_then(|value| value.split_once(' ')) {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                let token = token.trim().as_bytes();
+                // check all tokens, so that the time does not tell which one matches
+                self.tokens.iter().fold(false, |found, t| {
+                    constant_time_eq(t.as_bytes(), token) | found
+                })
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                self.verify_basic(credentials.trim()).await
+            }
+            _ => false,
+        };
+        if authorized {
+            Ok(())
+        } else {
+            increment_counter!(metric::CNT_ACCESS_DENIED);
+            Err(Denied::Unauthorized(self.challenge()))
+        }
+    }
+
+    async fn verify_basic(&self, credentials: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(credentials.as_bytes()).into();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return true;
+        }
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(credentials) {
+            Ok(decoded) => String::from_utf8_lossy(&decoded).into_owned(),
+            Err(_) => return false,
+        };
+        let (user, password) = match decoded.split_once(':') {
+            Some((user, password)) => (user.to_string(), password.to_string()),
+            None => return false,
+        };
+        let hash = match self.users.get(&user) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+        // bcrypt is slow by design, keep it off the async workers
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .is_ok_and(|result| result.unwrap_or(false));
+        if valid {
+            let mut verified = self.verified.lock().unwrap();
+            if verified.len() >= MAX_VERIFIED {
+                verified.clear();
+            }
+            verified.insert(digest);
+        }
+        valid
+    }
+
+    fn challenge(&self) -> String {
+        if self.users.is_empty() {
+            "Bearer".to_string()
+        } else {
+            format!("Basic realm=\"{}\"", REALM)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    fn networks(networks: &[&str]) -> Vec<IpNet> {
+        let networks: Vec<String> = networks.iter().map(|x| x.to_string()).collect();
+        parse_networks(&networks).unwrap()
+    }
+
+    #[test]
+    fn resolve_client_ip() {
+        let remote = Some("10.0.0.1:1234".parse().unwrap());
+        let trusted = networks(&["10.0.0.0/8"]);
+        let ip = |remote, forwarded_for| client_ip(remote, forwarded_for, &trusted);
+        assert_eq!(ip(remote, None), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(
+            ip(remote, Some("1.1.1.1, 2.2.2.2, 10.0.0.2")),
+            Some("2.2.2.2".parse().unwrap())
+        );
+        // an untrusted peer may forge the header
+        let remote = Some("3.3.3.3:1234".parse().unwrap());
+        assert_eq!(
+            ip(remote, Some("1.1.1.1")),
+            Some("3.3.3.3".parse().unwrap())
+        );
+        let remote = Some("[::ffff:10.0.0.1]:1234".parse().unwrap());
+        assert_eq!(
+            ip(remote, Some("1.1.1.1")),
+            Some("1.1.1.1".parse().unwrap())
+        );
+        assert!(is_trusted_proxy(remote, &trusted));
+        assert!(!is_trusted_proxy(
+            Some("3.3.3.3:1234".parse().unwrap()),
+            &trusted
+        ));
+        assert!(!is_trusted_proxy(None, &trusted));
+        assert!(parse_networks(&["not an address".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn allowlist() {
+        let access = Arc::new(
+            AccessControl::new(&settings::Access {
+                allow: Some(vec!["192.168.0.0/16".to_string(), "::1".to_string()]),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        assert!(access
+            .check(Some("192.168.1.1".parse().unwrap()), None)
+            .await
+            .is_ok());
+        assert!(access
+            .check(Some("::1".parse().unwrap()), None)
+            .await
+            .is_ok());
+        assert!(matches!(
+            access.check(Some("8.8.8.8".parse().unwrap()), None).await,
+            Err(Denied::Forbidden)
+        ));
+        assert!(access.check(None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn credentials() {
+        let path = std::env::temp_dir().join("mirror-cache-test-htpasswd");
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        std::fs::write(&path, format!("# users\nalice:{}\n", hash)).unwrap();
+        let access = Arc::new(
+            AccessControl::new(&settings::Access {
+                basic_auth_file: Some(path.to_str().unwrap().to_string()),
+                bearer_tokens: Some(vec!["t0ken".to_string()]),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let check = |authorization: String| {
+            let access = access.clone();
+            async move { access.check(None, Some(&authorization)).await.is_ok() }
+        };
+        let basic = |credentials: &str| {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        };
+        assert!(check(basic("alice:secret")).await);
+        // verified again from memory
+        assert!(check(basic("alice:secret")).await);
+        assert!(!check(basic("alice:wrong")).await);
+        assert!(!check(basic("bob:secret")).await);
+        assert!(check("Bearer t0ken".to_string()).await);
+        assert!(!check("Bearer t0ke".to_string()).await);
+        assert!(matches!(
+            access.check(None, None).await,
+            Err(Denied::Unauthorized(challenge)) if challenge.starts_with("Basic")
+        ));
+
+        std::fs::write(&path, "bob:plaintext\n").unwrap();
+        assert!(AccessControl::new(&settings::Access {
+            basic_auth_file: Some(path.to_str().unwrap().to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
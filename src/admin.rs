@@ -0,0 +1,266 @@
+//! Admin API for cache inspection and purging, served on a separate listener.
+//!
+//! - `GET /policies`: list policy names
+//! - `GET /policies/{policy}/entries?cursor={cursor}&limit={limit}`: list a page of keys of a policy
+//! - `GET /policies/{policy}/stats`: show the number of entries and their total size
+//! - `GET /policies/{policy}/entry?key={key}`: show the metadata of an entry
+//! - `DELETE /policies/{policy}/entry?key={key}`: purge an entry
+//! - `DELETE /policies/{policy}/entries?prefix={prefix}`: purge entries by key prefix
+//! - `DELETE /policies/{policy}/entries?regex={regex}`: purge entries by key regex
+//! - `DELETE /policies/{policy}`: flush a policy
+//! - `POST /policies/{policy}/reconcile`: reconcile the metadata of a policy with its storage
+use crate::cache::Cache;
+use crate::TASK_MANAGER;
+
+use regex::Regex;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Deserialize)]
+struct EntryQuery {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Default number of keys in a page
+const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    prefix: Option<String>,
+    regex: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Removed {
+    removed: usize,
+}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+pub fn routes() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let policies = warp::path!("policies")
+        .and(warp::get())
+        .and_then(list_policies);
+    let list = warp::path!("policies" / String / "entries")
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and_then(list_entries);
+    let stats = warp::path!("policies" / String / "stats")
+        .and(warp::get())
+        .and_then(show_stats);
+    let show = warp::path!("policies" / String / "entry")
+        .and(warp::get())
+        .and(warp::query::<EntryQuery>())
+        .and_then(show_entry);
+    let purge_one = warp::path!("policies" / String / "entry")
+        .and(warp::delete())
+        .and(warp::query::<EntryQuery>())
+        .and_then(purge_entry);
+    let purge_many = warp::path!("policies" / String / "entries")
+        .and(warp::delete())
+        .and(warp::query::<PurgeQuery>())
+        .and_then(purge_entries);
+    let flush = warp::path!("policies" / String)
+        .and(warp::delete())
+        .and_then(flush_policy);
+    let reconcile = warp::path!("policies" / String / "reconcile")
+        .and(warp::post())
+        .and_then(reconcile_policy);
+    policies
+        .or(list)
+        .or(stats)
+        .or(show)
+        .or(purge_one)
+        .or(purge_many)
+        .or(flush)
+        .or(reconcile)
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+    warp::reply::with_status(warp::reply::json(&ErrorMessage { error }), status).into_response()
+}
+
+async fn get_cache(policy: &str) -> std::result::Result<Arc<RwLock<dyn Cache>>, Response> {
+    TASK_MANAGER
+        .read()
+        .await
+        .cache_map
+        .get(policy)
+        .cloned()
+        .ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                format!("no active policy named {}", policy),
+            )
+        })
+}
+
+/// Remove all entries whose key satisfies `predicate`. Returns the number of
+/// removed entries.
+pub async fn purge(cache: &RwLock<dyn Cache>, predicate: impl Fn(&str) -> bool) -> usize {
+    let keys = cache.read().await.all_keys().await;
+    let mut removed = 0;
+    for key in keys.iter().filter(|key| predicate(key)) {
+        if cache.write().await.remove(key).await {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+async fn list_policies() -> Result<Response, Rejection> {
+    let mut policies: Vec<String> = TASK_MANAGER
+        .read()
+        .await
+        .cache_map
+        .keys()
+        .cloned()
+        .collect();
+    policies.sort();
+    Ok(warp::reply::json(&policies).into_response())
+}
+
+async fn list_entries(policy: String, query: ListQuery) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let page = cache
+        .read()
+        .await
+        .keys(
+            query.cursor.as_deref(),
+            query.limit.unwrap_or(DEFAULT_LIMIT),
+        )
+        .await;
+    Ok(warp::reply::json(&page).into_response())
+}
+
+async fn show_stats(policy: String) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let stats = cache.read().await.stats().await;
+    Ok(warp::reply::json(&stats).into_response())
+}
+
+async fn show_entry(policy: String, query: EntryQuery) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let metadata = cache.read().await.metadata(&query.key).await;
+    Ok(match metadata {
+        Some(metadata) => warp::reply::json(&metadata).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("no entry with key {}", query.key),
+        ),
+    })
+}
+
+async fn purge_entry(policy: String, query: EntryQuery) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let removed = cache.write().await.remove(&query.key).await as usize;
+    info!("[ADMIN] purged {} from {}: {}", query.key, policy, removed);
+    Ok(warp::reply::json(&Removed { removed }).into_response())
+}
+
+async fn purge_entries(policy: String, query: PurgeQuery) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let removed = match (query.prefix, query.regex) {
+        (Some(prefix), None) => purge(&cache, |key| key.starts_with(&prefix)).await,
+        (None, Some(regex)) => match Regex::new(&regex) {
+            Ok(regex) => purge(&cache, |key| regex.is_match(key)).await,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, e.to_string())),
+        },
+        _ => {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "exactly one of prefix and regex is required".to_string(),
+            ))
+        }
+    };
+    info!("[ADMIN] purged {} entries from {}", removed, policy);
+    Ok(warp::reply::json(&Removed { removed }).into_response())
+}
+
+async fn flush_policy(policy: String) -> Result<Response, Rejection> {
+    let cache = match get_cache(&policy).await {
+        Ok(cache) => cache,
+        Err(resp) => return Ok(resp),
+    };
+    let removed = purge(&cache, |_| true).await;
+    info!("[ADMIN] flushed {}: {} entries", policy, removed);
+    Ok(warp::reply::json(&Removed { removed }).into_response())
+}
+
+async fn reconcile_policy(policy: String) -> Result<Response, Rejection> {
+    // do not hold the lock of the task manager, which blocks configuration reloads
+    let tm = TASK_MANAGER.read().await.clone();
+    if !tm.cache_map.contains_key(&policy) {
+        return Ok(error_response(
+            StatusCode::NOT_FOUND,
+            format!("no active policy named {}", policy),
+        ));
+    }
+    Ok(match tm.reconcile(&policy).await {
+        Ok(report) => {
+            info!("[ADMIN] reconciled {}: {:?}", policy, report);
+            warp::reply::json(&report).into_response()
+        }
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::{LruCache, ResponseHeaders, SledMetadataDb};
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn purge_by_predicate() {
+        let cache = LruCache::new(
+            1024,
+            Arc::new(SledMetadataDb::new_lru(
+                "cache/test/admin/sled",
+                "admin_purge",
+            )),
+            Arc::new(Storage::new_mem()),
+            "admin_purge",
+        );
+        let cache: Arc<RwLock<dyn Cache>> = Arc::new(RwLock::new(cache));
+        for key in ["a/1", "a/2", "b/1"] {
+            cache
+                .write()
+                .await
+                .put(key, vec![0].into(), ResponseHeaders::default())
+                .await
+                .unwrap();
+        }
+        assert_eq!(purge(&cache, |key| key.starts_with("a/")).await, 2);
+        assert_eq!(cache.read().await.all_keys().await, vec!["b/1"]);
+        assert_eq!(purge(&cache, |_| true).await, 1);
+        assert!(cache.read().await.all_keys().await.is_empty());
+    }
+}
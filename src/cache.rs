@@ -1,9 +1,11 @@
+use crate::conditional;
 use crate::error::Error;
 use crate::error::Result;
+use crate::freshness::HttpFreshness;
 use crate::metric;
 use crate::models;
-use crate::models::SledMetadata;
-use crate::storage::Storage;
+use crate::models::{SledEvictionState, SledMetadata, SledTtlMetadata, SledTtlUsage};
+use crate::storage::{self, Storage, StoredObject};
 use crate::util;
 
 use async_trait::async_trait;
@@ -11,18 +13,21 @@ use bytes::Bytes;
 use futures::{future, stream, Stream, StreamExt};
 use metrics::{describe_histogram, histogram, increment_counter};
 use redis::Commands;
-use sled::transaction::{TransactionError, TransactionResult};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionResult};
 use sled::Transactional;
+use std::collections::HashMap;
 use std::convert::AsRef;
-use std::convert::TryInto;
 use std::fmt;
 use std::marker::Send;
+use std::ops::Bound;
 use std::path::Path;
 use std::str;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::vec::Vec;
+use tokio::io::AsyncReadExt;
+use warp::http::header::{ETAG, LAST_MODIFIED};
 
 /// Datatype of cache size.
 /// Note: It is persistent in some database, so changes may not be backward compatible.
@@ -40,6 +45,8 @@ pub enum CacheData {
         Box<dyn Stream<Item = Result<Bytes>> + Send + Unpin>,
         Option<CacheSizeType>,
     ), // stream and size
+    /// A stored file and its size, which can be read from any offset
+    File(tokio::fs::File, CacheSizeType),
 }
 
 impl CacheData {
@@ -48,6 +55,7 @@ impl CacheData {
             CacheData::TextData(text) => text.len() as CacheSizeType,
             CacheData::BytesData(bytes) => bytes.len() as CacheSizeType,
             CacheData::ByteStream(_, size) => size.unwrap(),
+            CacheData::File(_, size) => *size,
         }
     }
 
@@ -65,6 +73,11 @@ impl CacheData {
                     .await;
                 vec
             }
+            CacheData::File(mut file, size) => {
+                let mut vec: Vec<u8> = Vec::with_capacity(size as usize);
+                file.read_to_end(&mut vec).await.unwrap();
+                vec
+            }
         }
     }
 
@@ -80,6 +93,7 @@ impl CacheData {
                 Box::new(stream)
             }
             CacheData::ByteStream(stream, _) => stream,
+            CacheData::File(file, _) => Box::new(storage::file_stream(file)),
         }
     }
 }
@@ -127,24 +141,204 @@ impl fmt::Debug for CacheData {
                         .unwrap_or_else(|| "unknown".to_string())
                 ),
             ),
+            CacheData::File(_, size) => f.field("File", &format!("(file of size {})", size)),
         };
         f.finish()
     }
 }
 
+/// Names of upstream response headers that are stored along with cache entries,
+/// and replayed on cache hits.
+pub const REPLAYED_HEADERS: [&str; 6] = [
+    "content-type",
+    "content-encoding",
+    "content-language",
+    "content-disposition",
+    "etag",
+    "last-modified",
+];
+
+/// Names of upstream response headers that are stored along with cache entries
+/// to derive their freshness, but not replayed.
+pub const FRESHNESS_HEADERS: [&str; 4] = ["cache-control", "expires", "date", "age"];
+
+/// Upstream response headers of a cache entry.
+///
+/// It is persisted in metadata databases in the HTTP/1 wire format, e.g.
+/// `etag: "abc"\r\nlast-modified: Thu, 01 Jan 1970 00:00:00 GMT\r\n`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResponseHeaders(Vec<(String, String)>);
+
+impl ResponseHeaders {
+    /// Pick the headers in `REPLAYED_HEADERS` and `FRESHNESS_HEADERS` from an
+    /// upstream response.
+    pub fn from_header_map(headers: &warp::http::HeaderMap) -> Self {
+        let mut picked = Vec::new();
+        for name in REPLAYED_HEADERS.iter().chain(&FRESHNESS_HEADERS) {
+            for value in headers.get_all(*name) {
+                if let Ok(value) = value.to_str() {
+                    picked.push((name.to_string(), value.to_string()));
+                }
+            }
+        }
+        Self(picked)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn encode(&self) -> String {
+        self.0
+            .iter()
+            .map(|(k, v)| format!("{}: {}\r\n", k, v))
+            .collect()
+    }
+
+    /// Replace the stored values of the headers present in `other`, e.g. with
+    /// the headers of a `304 Not Modified` response.
+    pub fn update(&mut self, other: ResponseHeaders) {
+        self.0
+            .retain(|(k, _)| !other.0.iter().any(|(name, _)| name.eq_ignore_ascii_case(k)));
+        self.0.extend(other.0);
+    }
+
+    /// Validators of a body rewritten from the upstream one. The ETag is made
+    /// weak, so that it never matches `If-Range`, and `Last-Modified` is dropped,
+    /// as a date cannot tell the rewritten bytes apart.
+    pub fn weaken_validators(mut self) -> Self {
+        self.0
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(LAST_MODIFIED.as_str()));
+        for (k, v) in self.0.iter_mut() {
+            if k.eq_ignore_ascii_case(ETAG.as_str()) {
+                *v = conditional::weak_etag(v);
+            }
+        }
+        self
+    }
+
+    /// Decode headers encoded by `encode`. Malformed lines are skipped.
+    pub fn decode(s: &str) -> Self {
+        Self(
+            s.split("\r\n")
+                .filter_map(|line| line.split_once(": "))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+}
+
 /// Cache is a trait that defines the shared beshaviors of all cache policies.
-/// - `put`: put a key-value pair into the cache
-/// - `get`: get a value from the cache
+/// - `put`: put a key-value pair into the cache, along with upstream response headers
+/// - `get`: get a value and its headers from the cache
+/// - `peek`: like `get`, but the entry is not accessed, e.g. its LRU atime is not updated
+/// - `contains`: whether `get` would hit, without accessing the entry
+/// - `get_stale`: get an expired entry that is retained for revalidation or as a fallback
+/// - `refresh`: make a revalidated entry fresh again, with updated headers
+/// - `keys`: list a page of at most `limit` keys, starting from the cursor of the previous page
+/// - `all_keys`: list the keys of all entries
+/// - `metadata`: get the metadata of an entry without accessing it
+/// - `remove`: remove an entry and its data, returns whether it existed
+/// - `stats`: get the number of entries and their total size
+/// - `reconcile`: make the metadata consistent with `objects`, the objects of this cache in the storage
 #[async_trait]
 pub trait Cache: Sync + Send {
-    async fn put(&mut self, key: &str, entry: CacheData);
-    async fn get(&self, key: &str) -> Option<CacheData>;
+    /// Store the entry, unless the policy skips it. Returns an error if it
+    /// fails to be written.
+    async fn put(&mut self, key: &str, entry: CacheData, headers: ResponseHeaders) -> Result<()>;
+    async fn get(&self, key: &str) -> Option<(CacheData, ResponseHeaders)>;
+    async fn peek(&self, key: &str) -> Option<(CacheData, ResponseHeaders)>;
+    async fn contains(&self, key: &str) -> bool;
+    async fn keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage;
+    async fn metadata(&self, key: &str) -> Option<EntryMetadata>;
+    async fn remove(&mut self, key: &str) -> bool;
+    async fn stats(&self) -> CacheStats;
+    async fn reconcile(&mut self, objects: Vec<StoredObject>) -> ReconcileReport;
+    async fn all_keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.keys(cursor.as_deref(), KEY_PAGE_SIZE).await;
+            keys.extend(page.keys);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => return keys,
+            }
+        }
+    }
+    async fn get_stale(&self, _key: &str) -> Option<StaleEntry> {
+        None
+    }
+    async fn refresh(&mut self, _key: &str, _headers: ResponseHeaders) {}
 }
 
-/// `LruMetadataStore` defines required behavior for an LRU cache
+/// Page size used to iterate over all keys
+const KEY_PAGE_SIZE: usize = 1000;
+
+/// A page of cache keys
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct KeyPage {
+    pub keys: Vec<String>,
+    /// Opaque cursor of the next page, `None` if this is the last page
+    pub next: Option<String>,
+}
+
+/// Statistics of a cache
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    /// Total size of entries in bytes
+    pub bytes: CacheSizeType,
+}
+
+/// Result of a reconciliation between the metadata and the storage
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReconcileReport {
+    /// Entries whose data is missing, and whose metadata is dropped
+    pub dropped: u64,
+    /// Orphan objects that are added to the cache
+    pub adopted: u64,
+    /// Orphan or incomplete objects that are deleted
+    pub deleted: u64,
+    /// Entries evicted to fit in the size limit after adoption
+    pub evicted: u64,
+}
+
+/// Order in which a size-bounded cache evicts its entries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    /// The least recently used entry first
+    #[default]
+    Lru,
+    /// The least frequently used entry first, with dynamic aging (LFU-DA): the
+    /// priority of an entry is its number of hits plus the priority of the last
+    /// evicted entry, so that entries popular long ago are evicted eventually.
+    Lfu,
+    /// SIEVE: entries are queued in insertion order, and a hit marks an entry
+    /// visited. A hand sweeps from the oldest entry to the newest, unmarking
+    /// visited entries and evicting the first unvisited one.
+    Sieve,
+}
+
+/// `LruMetadataStore` defines required behavior for a size-bounded cache, whose
+/// eviction order is one of `Eviction`
 pub trait LruMetadataStore: Sync + Send {
-    fn get_lru_entry(&self, key: &str) -> CacheHitMiss;
-    fn set_lru_entry(&self, key: &str, value: &CacheData);
+    /// Returns the stored headers on a hit, or `None` on a miss.
+    fn get_lru_entry(&self, key: &str) -> Option<ResponseHeaders>;
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, headers: &ResponseHeaders);
+    fn get_lru_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage;
+    fn get_lru_entry_count(&self) -> u64;
+    /// Returns the metadata without updating the atime. The atime is in seconds.
+    fn peek_lru_entry(&self, key: &str) -> Option<LruCacheMetadata>;
+    /// Remove an entry and update the total size. Returns whether it existed.
+    fn remove_lru_entry(&self, key: &str) -> bool;
     /// Run eviction policy if needed, reserve at least `size` for new cache entry.
     /// Return a list of evicted keys.
     fn evict(
@@ -154,12 +348,89 @@ pub trait LruMetadataStore: Sync + Send {
         size_limit: CacheSizeType,
     ) -> Vec<String>;
     fn get_total_size(&self) -> CacheSizeType;
+    /// Overwrite the total size, e.g. after it is recomputed.
+    fn set_total_size(&self, size: CacheSizeType);
+}
+
+/// Metadata of a TTL cache entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TtlEntry {
+    /// Unix timestamp in nanoseconds after which the entry is expired
+    pub expire_time: i64,
+    pub headers: ResponseHeaders,
+}
+
+impl TtlEntry {
+    pub fn is_fresh(&self) -> bool {
+        self.expire_time > util::now_nanos()
+    }
+}
+
+/// Metadata of a cache entry for inspection
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct EntryMetadata {
+    pub key: String,
+    pub size: Option<CacheSizeType>,
+    /// Last access time in seconds since the epoch
+    pub atime: Option<i64>,
+    /// Expiration time in seconds since the epoch
+    pub expire_time: Option<i64>,
+}
+
+/// An expired entry that is still retained by a cache
+pub struct StaleEntry {
+    pub data: CacheData,
+    pub headers: ResponseHeaders,
+    /// Whether the entry can be served while it is being refreshed
+    pub while_revalidate: bool,
+    /// Whether the entry can be served if the upstream fetch fails
+    pub if_error: bool,
+}
+
+/// Windows in seconds after expiration, during which a TTL cache entry is retained
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaleWindows {
+    /// Entries with validators are retained for revalidation
+    pub revalidate: u64,
+    /// Entries can be served while they are refreshed in the background
+    pub while_revalidate: u64,
+    /// Entries can be served if the upstream fetch fails
+    pub if_error: u64,
 }
 
 /// `TtlMetadataStore` defines required behavior for a TTL cache
 pub trait TtlMetadataStore: Sync + Send {
-    fn get_ttl_entry(&self, key: &str) -> CacheHitMiss;
-    fn set_ttl_entry(&self, key: &str, value: &CacheData, ttl: u64);
+    /// Returns the entry as long as it is retained, even if it is expired.
+    fn get_ttl_entry(&self, key: &str) -> Option<TtlEntry>;
+    /// Set an entry that expires after `ttl` seconds, and is retained for
+    /// `retention` seconds (no less than `ttl`).
+    fn set_ttl_entry(
+        &self,
+        key: &str,
+        size: CacheSizeType,
+        headers: &ResponseHeaders,
+        ttl: u64,
+        retention: u64,
+    );
+    /// Reset the expiration of an existing entry, and replace its headers. The
+    /// recorded size is kept.
+    fn renew_ttl_entry(&self, key: &str, headers: &ResponseHeaders, ttl: u64, retention: u64);
+    /// Record an access of an entry, for eviction in LRU order.
+    fn touch_ttl_entry(&self, key: &str);
+    /// Record the size of an existing entry, e.g. after it is read from the storage.
+    fn set_ttl_entry_size(&self, key: &str, size: CacheSizeType);
+    /// The recorded size of an entry, if any.
+    fn get_ttl_entry_size(&self, key: &str) -> Option<CacheSizeType>;
+    fn get_ttl_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage;
+    /// Remove an entry and update the total size. Returns whether it existed.
+    fn remove_ttl_entry(&self, key: &str) -> bool;
+    /// Evict expired entries first and then the least recently used ones, until
+    /// `new_size` fits in `size_limit`. Returns a list of evicted keys.
+    fn evict_ttl(&self, new_size: CacheSizeType, size_limit: CacheSizeType) -> Vec<String>;
+    /// Total size of the entries, including retained expired entries.
+    fn get_ttl_total_size(&self) -> CacheSizeType;
+    /// Overwrite the total size, e.g. after it is recomputed.
+    fn set_ttl_total_size(&self, size: CacheSizeType);
     fn spawn_expiration_cleanup_thread(
         &self,
         storage: &Storage,
@@ -167,7 +438,8 @@ pub trait TtlMetadataStore: Sync + Send {
     ) -> Result<JoinHandle<()>>;
 }
 
-/// Wrapper of an LRU cache object
+/// Wrapper of a size-bounded cache object, which evicts entries in the order of
+/// its metadata store, i.e. LRU, LFU or SIEVE
 pub struct LruCache {
     pub size_limit: CacheSizeType,
     metadata_db: Arc<dyn LruMetadataStore>,
@@ -192,11 +464,28 @@ impl LruCache {
             storage,
         }
     }
+
+    /// Run eviction to reserve `size` for `key`. Returns the number of evicted entries.
+    async fn evict(&self, size: CacheSizeType, key: &str) -> u64 {
+        let evicted_keys = self.metadata_db.evict(size, key, self.size_limit);
+        for file in &evicted_keys {
+            match self.storage.remove(file).await {
+                Ok(_) => {
+                    increment_counter!(metric::CNT_RM_FILES);
+                    info!("LRU cache removed {}", file);
+                }
+                Err(e) => {
+                    warn!("failed to remove file: {:?}", e);
+                }
+            };
+        }
+        evicted_keys.len() as u64
+    }
 }
 
 #[async_trait]
 impl Cache for LruCache {
-    async fn put(&mut self, key: &str, entry: CacheData) {
+    async fn put(&mut self, key: &str, entry: CacheData, headers: ResponseHeaders) -> Result<()> {
         let file_size = entry.len() as CacheSizeType;
 
         if file_size > self.size_limit {
@@ -204,47 +493,155 @@ impl Cache for LruCache {
                 "skip cache for {}, because its size exceeds cache size limit({})",
                 key, self.size_limit
             );
-            return;
-        }
-        // Run eviction, set new entry
-        let evicted_keys = self.metadata_db.evict(file_size, key, self.size_limit);
-        for file in evicted_keys {
-            match self.storage.remove(&file).await {
-                Ok(_) => {
-                    increment_counter!(metric::CNT_RM_FILES);
-                    info!("LRU cache removed {}", &file);
-                }
-                Err(e) => {
-                    warn!("failed to remove file: {:?}", e);
-                }
-            };
+            return Ok(());
         }
-        self.metadata_db.set_lru_entry(key, &entry);
-        // self.metadata_db.set(key, &mut entry);
-        self.storage.persist(key, entry).await;
+        // Run eviction, set new entry once its data is persisted. An existing
+        // entry of the key is replaced, so only the growth is reserved.
+        let existing_size = self
+            .metadata_db
+            .peek_lru_entry(key)
+            .map_or(0, |metadata| metadata.size);
+        self.evict(file_size.saturating_sub(existing_size), key)
+            .await;
+        self.storage.persist(key, entry).await?;
+        self.metadata_db.set_lru_entry(key, file_size, &headers);
+        Ok(())
     }
 
-    async fn get(&self, key: &str) -> Option<CacheData> {
+    async fn get(&self, key: &str) -> Option<(CacheData, ResponseHeaders)> {
         match self.metadata_db.get_lru_entry(key) {
-            CacheHitMiss::Hit => {
+            Some(headers) => {
                 return match self.storage.read(key).await {
                     Ok(data) => {
                         // trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &cache_result);
-                        Some(data)
+                        Some((data, headers))
                     }
                     Err(_) => None,
                 };
             }
-            CacheHitMiss::Miss => {
+            None => {
                 // trace!("CACHE GET [MISS] {} -> {:?} ", redis_key, &cache_result);
                 None
             }
         }
     }
+
+    async fn peek(&self, key: &str) -> Option<(CacheData, ResponseHeaders)> {
+        let metadata = self.metadata_db.peek_lru_entry(key)?;
+        self.storage
+            .read(key)
+            .await
+            .ok()
+            .map(|data| (data, metadata.headers))
+    }
+
+    async fn contains(&self, key: &str) -> bool {
+        self.metadata_db.peek_lru_entry(key).is_some()
+    }
+
+    async fn keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        self.metadata_db.get_lru_keys(cursor, limit)
+    }
+
+    async fn metadata(&self, key: &str) -> Option<EntryMetadata> {
+        self.metadata_db
+            .peek_lru_entry(key)
+            .map(|metadata| EntryMetadata {
+                key: key.to_string(),
+                size: Some(metadata.size),
+                atime: Some(metadata.atime),
+                expire_time: None,
+            })
+    }
+
+    async fn remove(&mut self, key: &str) -> bool {
+        let existed = self.metadata_db.remove_lru_entry(key);
+        if existed {
+            remove_data(&self.storage, key).await;
+        }
+        existed
+    }
+
+    async fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.metadata_db.get_lru_entry_count(),
+            bytes: self.metadata_db.get_total_size(),
+        }
+    }
+
+    /// Entries whose object is missing are dropped, and entries whose object size
+    /// differs from the metadata are incomplete writes, which are removed.
+    /// Orphan objects are adopted as new entries, unless they are larger than the
+    /// size limit. The total size is then recomputed, and eviction is run.
+    async fn reconcile(&mut self, objects: Vec<StoredObject>) -> ReconcileReport {
+        let mut report = ReconcileReport::default();
+        let mut objects: HashMap<String, StoredObject> = objects
+            .into_iter()
+            .map(|object| (object.name.clone(), object))
+            .collect();
+        for key in self.all_keys().await {
+            let object = objects.remove(&key);
+            let metadata = match self.metadata_db.peek_lru_entry(&key) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            match object {
+                None => {
+                    info!("LRU cache dropped {}, whose data is missing", key);
+                    self.metadata_db.remove_lru_entry(&key);
+                    report.dropped += 1;
+                }
+                Some(object) if object.size != metadata.size => {
+                    info!("LRU cache removed incomplete {}", key);
+                    self.remove(&key).await;
+                    report.deleted += 1;
+                }
+                Some(_) => {}
+            }
+        }
+        for (key, object) in objects {
+            if object.size > self.size_limit {
+                remove_data(&self.storage, &key).await;
+                report.deleted += 1;
+            } else {
+                self.metadata_db
+                    .set_lru_entry(&key, object.size, &ResponseHeaders::default());
+                report.adopted += 1;
+            }
+        }
+        let mut total_size = 0;
+        for key in self.all_keys().await {
+            if let Some(metadata) = self.metadata_db.peek_lru_entry(&key) {
+                total_size += metadata.size;
+            }
+        }
+        self.metadata_db.set_total_size(total_size);
+        report.evicted = self.evict(0, "").await;
+        report
+    }
 }
 
+/// Remove the data of a removed cache entry from the storage.
+async fn remove_data(storage: &Storage, key: &str) {
+    match storage.remove(key).await {
+        Ok(_) => {
+            increment_counter!(metric::CNT_RM_FILES);
+            info!("removed {}", key);
+        }
+        Err(e) => {
+            warn!("failed to remove {}: {}", key, e);
+        }
+    }
+}
+
+/// Wrapper of a TTL cache object, which may also be size-bounded
 pub struct TtlCache {
     pub ttl: u64,
+    pub stale_windows: StaleWindows,
+    /// Entries are evicted to fit in the limit, if any
+    pub size_limit: Option<CacheSizeType>,
+    /// HTTP: the TTL of an entry is derived from its upstream headers instead
+    pub freshness: Option<HttpFreshness>,
     metadata_db: Arc<dyn TtlMetadataStore>,
     storage: Arc<Storage>,
     pub pending_close: Arc<AtomicBool>,
@@ -252,9 +649,19 @@ pub struct TtlCache {
 }
 
 impl TtlCache {
-    pub fn new(ttl: u64, metadata_db: Arc<dyn TtlMetadataStore>, storage: Arc<Storage>) -> Self {
+    pub fn new(
+        ttl: u64,
+        stale_windows: StaleWindows,
+        size_limit: Option<CacheSizeType>,
+        freshness: Option<HttpFreshness>,
+        metadata_db: Arc<dyn TtlMetadataStore>,
+        storage: Arc<Storage>,
+    ) -> Self {
         let mut cache = Self {
             ttl,
+            stale_windows,
+            size_limit,
+            freshness,
             metadata_db,
             storage,
             pending_close: Arc::new(AtomicBool::new(false)),
@@ -267,36 +674,229 @@ impl TtlCache {
         cache.expiration_thread_handler = Some(thread_handler);
         cache
     }
+
+    /// The TTL of an entry with `headers`, or `None` if it must not be stored.
+    fn ttl(&self, headers: &ResponseHeaders) -> Option<u64> {
+        match &self.freshness {
+            Some(freshness) => freshness.ttl(headers),
+            None => Some(self.ttl),
+        }
+    }
+
+    /// Entries are retained after they are expired for the longest window that
+    /// applies to them.
+    fn retention(&self, ttl: u64, headers: &ResponseHeaders) -> u64 {
+        let windows = &self.stale_windows;
+        let revalidate = if conditional::has_validator(headers) {
+            windows.revalidate
+        } else {
+            0
+        };
+        ttl + revalidate
+            .max(windows.while_revalidate)
+            .max(windows.if_error)
+    }
+
+    /// Run eviction to reserve `size` if the cache is size-bounded. Returns the
+    /// number of evicted entries.
+    async fn evict(&self, size: CacheSizeType) -> u64 {
+        let size_limit = match self.size_limit {
+            Some(size_limit) => size_limit,
+            None => return 0,
+        };
+        let evicted_keys = self.metadata_db.evict_ttl(size, size_limit);
+        for key in &evicted_keys {
+            match self.storage.remove(key).await {
+                Ok(_) => {
+                    increment_counter!(metric::CNT_RM_FILES);
+                    info!("TTL cache evicted {}", key);
+                }
+                Err(e) => {
+                    warn!("failed to remove file: {:?}", e);
+                }
+            };
+        }
+        evicted_keys.len() as u64
+    }
+
+    async fn read_entry(&self, key: &str, fresh: bool) -> Option<(CacheData, TtlEntry)> {
+        let entry = self.metadata_db.get_ttl_entry(key)?;
+        if entry.is_fresh() != fresh {
+            return None;
+        }
+        self.storage.read(key).await.ok().map(|data| (data, entry))
+    }
 }
 
 #[async_trait]
 impl Cache for TtlCache {
-    async fn get(&self, key: &str) -> Option<CacheData> {
-        match self.metadata_db.get_ttl_entry(key) {
-            CacheHitMiss::Hit => {
-                return match self.storage.read(key).await {
-                    Ok(data) => {
-                        trace!("CACHE GET [HIT] {} -> {:?} ", key, data);
-                        Some(data)
-                    }
-                    Err(_) => None,
-                };
+    async fn get(&self, key: &str) -> Option<(CacheData, ResponseHeaders)> {
+        match self.read_entry(key, true).await {
+            Some((data, entry)) => {
+                trace!("CACHE GET [HIT] {} -> {:?} ", key, data);
+                if self.size_limit.is_some() {
+                    self.metadata_db.touch_ttl_entry(key);
+                }
+                Some((data, entry.headers))
             }
-            CacheHitMiss::Miss => {
+            None => {
                 trace!("CACHE GET [MISS] {}", key);
                 None
             }
         }
     }
-    async fn put(&mut self, key: &str, entry: CacheData) {
-        self.metadata_db.set_ttl_entry(key, &entry, self.ttl);
-        self.storage.persist(key, entry).await;
+    async fn put(&mut self, key: &str, entry: CacheData, headers: ResponseHeaders) -> Result<()> {
+        let ttl = match self.ttl(&headers) {
+            Some(ttl) => ttl,
+            None => {
+                info!(
+                    "skip cache for {}, because upstream forbids storing it",
+                    key
+                );
+                return Ok(());
+            }
+        };
+        let retention = self.retention(ttl, &headers);
+        let size = entry.len();
+        if let Some(size_limit) = self.size_limit.filter(|limit| size > *limit) {
+            info!(
+                "skip cache for {}, because its size exceeds cache size limit({})",
+                key, size_limit
+            );
+            return Ok(());
+        }
+        // an existing entry of the key is replaced, so only the growth is reserved
+        if self.size_limit.is_some() {
+            let existing_size = self.metadata_db.get_ttl_entry_size(key).unwrap_or(0);
+            self.evict(size.saturating_sub(existing_size)).await;
+        }
+        self.storage.persist(key, entry).await?;
+        self.metadata_db
+            .set_ttl_entry(key, size, &headers, ttl, retention);
+        Ok(())
+    }
+    async fn get_stale(&self, key: &str) -> Option<StaleEntry> {
+        let (data, entry) = self.read_entry(key, false).await?;
+        let stale_for = (util::now_nanos() - entry.expire_time) as u64;
+        let within = |window: u64| stale_for < window * 1_000_000_000;
+        Some(StaleEntry {
+            data,
+            headers: entry.headers,
+            while_revalidate: within(self.stale_windows.while_revalidate),
+            if_error: within(self.stale_windows.if_error),
+        })
+    }
+    async fn refresh(&mut self, key: &str, headers: ResponseHeaders) {
+        match self.ttl(&headers) {
+            Some(ttl) => {
+                let retention = self.retention(ttl, &headers);
+                self.metadata_db
+                    .renew_ttl_entry(key, &headers, ttl, retention);
+            }
+            None => {
+                self.remove(key).await;
+            }
+        }
+    }
+    async fn peek(&self, key: &str) -> Option<(CacheData, ResponseHeaders)> {
+        self.read_entry(key, true)
+            .await
+            .map(|(data, entry)| (data, entry.headers))
+    }
+    async fn contains(&self, key: &str) -> bool {
+        self.metadata_db
+            .get_ttl_entry(key)
+            .is_some_and(|entry| entry.is_fresh())
+    }
+    async fn keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        self.metadata_db.get_ttl_keys(cursor, limit)
+    }
+    async fn metadata(&self, key: &str) -> Option<EntryMetadata> {
+        let entry = self.metadata_db.get_ttl_entry(key)?;
+        Some(EntryMetadata {
+            key: key.to_string(),
+            // entries of old versions have no recorded size
+            size: match self.metadata_db.get_ttl_entry_size(key) {
+                Some(size) => Some(size),
+                None => self.storage.size(key).await.ok(),
+            },
+            atime: None,
+            expire_time: Some(entry.expire_time / 1_000_000_000),
+        })
+    }
+    async fn remove(&mut self, key: &str) -> bool {
+        let existed = self.metadata_db.remove_ttl_entry(key);
+        if existed {
+            remove_data(&self.storage, key).await;
+        }
+        existed
+    }
+    /// Retained expired entries are counted as well.
+    async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for _ in self.all_keys().await {
+            stats.entries += 1;
+        }
+        stats.bytes = self.metadata_db.get_ttl_total_size();
+        stats
+    }
+    /// Entries whose object is missing are dropped, and the size of other entries
+    /// is recorded again. Orphan objects are adopted as if they were put at their
+    /// modification time, or deleted if they would have been removed by now. The
+    /// total size is then recomputed, and eviction is run if the cache is
+    /// size-bounded.
+    async fn reconcile(&mut self, objects: Vec<StoredObject>) -> ReconcileReport {
+        let mut report = ReconcileReport::default();
+        let mut objects: HashMap<String, StoredObject> = objects
+            .into_iter()
+            .map(|object| (object.name.clone(), object))
+            .collect();
+        let mut total_size = 0;
+        for key in self.all_keys().await {
+            match objects.remove(&key) {
+                Some(object) => {
+                    self.metadata_db.set_ttl_entry_size(&key, object.size);
+                    total_size += object.size;
+                }
+                None => {
+                    if self.metadata_db.remove_ttl_entry(&key) {
+                        info!("TTL cache dropped {}, whose data is missing", key);
+                        report.dropped += 1;
+                    }
+                }
+            }
+        }
+        let headers = ResponseHeaders::default();
+        let ttl = self.ttl(&headers).unwrap_or(0);
+        let retention = self.retention(ttl, &headers);
+        let now = util::now();
+        for (key, object) in objects {
+            let age = now.saturating_sub(object.mtime).max(0) as u64;
+            if age >= retention || self.size_limit.is_some_and(|limit| object.size > limit) {
+                remove_data(&self.storage, &key).await;
+                report.deleted += 1;
+            } else {
+                self.metadata_db.set_ttl_entry(
+                    &key,
+                    object.size,
+                    &headers,
+                    ttl.saturating_sub(age),
+                    retention - age,
+                );
+                total_size += object.size;
+                report.adopted += 1;
+            }
+        }
+        self.metadata_db.set_ttl_total_size(total_size);
+        report.evicted = self.evict(0).await;
+        report
     }
 }
 
 pub struct RedisMetadataDb {
     redis_client: redis::Client,
     id: String,
+    eviction: Eviction,
 }
 
 impl RedisMetadataDb {
@@ -304,7 +904,58 @@ impl RedisMetadataDb {
         Self {
             redis_client,
             id: id.into(),
+            eviction: Eviction::Lru,
+        }
+    }
+
+    /// A metadata database of a size-bounded cache evicting in the given order.
+    /// Entries set under another eviction policy are rescored.
+    pub fn new_bounded(redis_client: redis::Client, id: &str, eviction: Eviction) -> Result<Self> {
+        let metadata_db = Self {
+            redis_client,
+            id: id.into(),
+            eviction,
+        };
+        metadata_db.rescore()?;
+        Ok(metadata_db)
+    }
+
+    /// Score the entries for the eviction policy, if they were scored by another
+    /// one. Entries are rescored in their old order.
+    fn rescore(&self) -> Result<()> {
+        let mut con = models::get_sync_con(&self.redis_client)?;
+        let scored_by: Option<String> = con.get(self.eviction_key())?;
+        let eviction = match self.eviction {
+            Eviction::Lru => "lru",
+            Eviction::Lfu => "lfu",
+            Eviction::Sieve => "sieve",
+        };
+        // older versions only scored entries by LRU
+        if scored_by.as_deref().unwrap_or("lru") == eviction {
+            return Ok(());
+        }
+        let keys: Vec<String> = con.zrange(self.entries_zlist_key(), 0, -1)?;
+        if self.eviction == Eviction::Sieve {
+            con.del::<_, ()>(self.sieve_hand_key())?;
+        }
+        for key in &keys {
+            models::rescore_cache_entry(
+                &mut con,
+                key,
+                self.eviction,
+                &self.entries_zlist_key(),
+                &self.lfu_age_key(),
+                &self.sieve_seq_key(),
+            )?;
         }
+        con.set::<_, _, ()>(self.eviction_key(), eviction)?;
+        info!(
+            "rescored {} entries of {} for {:?}",
+            keys.len(),
+            self.id,
+            self.eviction
+        );
+        Ok(())
     }
 
     #[allow(clippy::wrong_self_convention)]
@@ -321,11 +972,162 @@ impl RedisMetadataDb {
         self.to_prefixed_key("total_size")
     }
 
-    /// returns the key to the zlist that stores the cache entries
+    /// returns the key to the zlist that stores the cache entries. Entries are
+    /// scored by atime (LRU), priority (LFU) or insertion sequence (SIEVE).
     fn entries_zlist_key(&self) -> String {
         self.to_prefixed_key("cache_keys")
     }
 
+    /// returns the key to the eviction policy by which the zlist is scored
+    fn eviction_key(&self) -> String {
+        self.to_prefixed_key("eviction")
+    }
+
+    /// LFU: priority of the last evicted entry
+    fn lfu_age_key(&self) -> String {
+        self.to_prefixed_key("lfu_age")
+    }
+
+    /// SIEVE: insertion sequence of the last inserted entry
+    fn sieve_seq_key(&self) -> String {
+        self.to_prefixed_key("sieve_seq")
+    }
+
+    /// SIEVE: insertion sequence from which the hand looks for an entry to evict
+    fn sieve_hand_key(&self) -> String {
+        self.to_prefixed_key("sieve_hand")
+    }
+
+    /// SIEVE: evict entries until `new_size` fits in `size_limit`
+    fn evict_sieve(
+        &self,
+        con: &mut redis::Connection,
+        new_size: CacheSizeType,
+        size_limit: CacheSizeType,
+    ) -> redis::RedisResult<Vec<String>> {
+        let mut files_to_remove = Vec::new();
+        while self.get_total_size() + new_size > size_limit {
+            let hand: Option<u64> = con.get(self.sieve_hand_key())?;
+            let mut candidates: Vec<(String, u64)> = con.zrangebyscore_limit_withscores(
+                self.entries_zlist_key(),
+                hand.unwrap_or_default(),
+                "+inf",
+                0,
+                1,
+            )?;
+            if candidates.is_empty() {
+                // wrap around to the oldest entry
+                candidates = con.zrange_withscores(self.entries_zlist_key(), 0, 0)?;
+            }
+            let (entry, seq) = match candidates.pop() {
+                Some(candidate) => candidate,
+                None => {
+                    info!("some files need to be evicted but they are missing from redis filelist. The cache metadata is inconsistent.");
+                    break;
+                }
+            };
+            con.set::<_, _, ()>(self.sieve_hand_key(), seq + 1)?;
+            let visited: Option<u8> = con.hget(&entry, "visited")?;
+            if visited == Some(1) {
+                con.hset::<_, _, _, ()>(&entry, "visited", 0)?;
+                continue;
+            }
+            match models::remove_lru_cache_entry(
+                con,
+                &entry,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+            ) {
+                Ok(true) => files_to_remove.push(self.from_prefixed_key(&entry)),
+                Ok(false) => {}
+                Err(e) => {
+                    error!("failed to evict {}: {}", entry, e);
+                    break;
+                }
+            }
+        }
+        Ok(files_to_remove)
+    }
+
+    /// TTL: keys to account for the size of entries and to evict them
+    fn ttl_index_keys(&self) -> models::TtlIndexKeys {
+        models::TtlIndexKeys {
+            total_size: self.total_size_key(),
+            sizes: self.to_prefixed_key("ttl_sizes"),
+            atimes: self.to_prefixed_key("ttl_atimes"),
+            expire_times: self.to_prefixed_key("ttl_expire_times"),
+        }
+    }
+
+    /// TTL: set an entry, whose key is a hash of the expiration time and encoded
+    /// headers, which is removed by redis after `retention` seconds. The recorded
+    /// size is kept if `size` is `None`.
+    fn put_ttl_entry(
+        &self,
+        key: &str,
+        size: Option<CacheSizeType>,
+        headers: &ResponseHeaders,
+        ttl: u64,
+        retention: u64,
+    ) {
+        let redis_key = Self::get_redis_key(&self.id, key);
+        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
+        let entry = TtlEntry {
+            expire_time: util::now_nanos() + ttl as i64 * 1_000_000_000,
+            headers: headers.clone(),
+        };
+        if let Err(e) = models::set_ttl_cache_entry(
+            &mut sync_con,
+            &redis_key,
+            key,
+            &entry,
+            size,
+            retention as usize,
+            &self.ttl_index_keys(),
+        ) {
+            error!("set cache entry for {} failed: {}", key, e);
+        }
+        trace!("CACHE SET {} TTL={} RETENTION={}", &key, ttl, retention);
+    }
+
+    /// TTL: evict expired entries and then the least recently used ones, until
+    /// `new_size` fits in `size_limit`
+    fn evict_ttl_entries(
+        &self,
+        con: &mut redis::Connection,
+        new_size: CacheSizeType,
+        size_limit: CacheSizeType,
+        evicted: &mut Vec<String>,
+    ) -> Result<()> {
+        let index = self.ttl_index_keys();
+        loop {
+            let total_size: Option<CacheSizeType> = con.get(&index.total_size)?;
+            if total_size.unwrap_or(0) + new_size <= size_limit {
+                return Ok(());
+            }
+            let mut candidates: Vec<String> =
+                con.zrangebyscore_limit(&index.expire_times, "-inf", util::now_nanos(), 0, 1)?;
+            if candidates.is_empty() {
+                candidates = con.zrange(&index.atimes, 0, 0)?;
+            }
+            let key = match candidates.pop() {
+                Some(key) => key,
+                None => {
+                    warn!(
+                        "no entry to evict for {}, the total size is inconsistent",
+                        self.id
+                    );
+                    return Ok(());
+                }
+            };
+            // the entry is already removed by redis if it is not found
+            let redis_key = Self::get_redis_key(&self.id, &key);
+            if models::remove_ttl_cache_entry(con, &redis_key, &key, &index, false)? {
+                evicted.push(key);
+            }
+        }
+    }
+
     pub fn get_redis_key(id: &str, cache_key: &str) -> String {
         format!("{}/{}", id, cache_key)
     }
@@ -336,48 +1138,135 @@ impl RedisMetadataDb {
 }
 
 impl LruMetadataStore for RedisMetadataDb {
-    fn get_lru_entry(&self, key: &str) -> CacheHitMiss {
+    fn get_lru_entry(&self, key: &str) -> Option<ResponseHeaders> {
         let redis_key = &self.to_prefixed_key(key);
         let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
         let cache_result = models::get_cache_entry(&mut sync_con, redis_key).unwrap();
         match cache_result {
-            Some(_) => {
+            Some(entry) => {
                 // cache hit
                 // update cache entry in db
                 let new_atime = util::now();
-                match models::update_cache_entry_atime(
-                    &mut sync_con,
-                    redis_key,
-                    new_atime,
-                    &self.entries_zlist_key(),
-                ) {
+                let updated = match self.eviction {
+                    Eviction::Lru => models::update_cache_entry_atime(
+                        &mut sync_con,
+                        redis_key,
+                        new_atime,
+                        &self.entries_zlist_key(),
+                    )
+                    .map(|_| ()),
+                    Eviction::Lfu => models::hit_lfu_cache_entry(
+                        &mut sync_con,
+                        redis_key,
+                        new_atime,
+                        &self.entries_zlist_key(),
+                        &self.lfu_age_key(),
+                    ),
+                    Eviction::Sieve => models::hit_sieve_cache_entry(&mut sync_con, redis_key),
+                };
+                match updated {
                     Ok(_) => {}
                     Err(e) => {
                         info!("Failed to update cache entry atime: {}", e);
                     }
                 }
-                trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &cache_result);
-                CacheHitMiss::Hit
+                trace!("CACHE GET [HIT] {} -> {:?} ", redis_key, &entry);
+                Some(entry.metadata.headers)
             }
             None => {
-                trace!("CACHE GET [MISS] {} -> {:?} ", redis_key, &cache_result);
-                CacheHitMiss::Miss
+                trace!("CACHE GET [MISS] {}", redis_key);
+                None
             }
         }
     }
 
-    fn set_lru_entry(&self, key: &str, value: &CacheData) {
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, headers: &ResponseHeaders) {
         let redis_key = &self.to_prefixed_key(key);
         let mut con = models::get_sync_con(&self.redis_client).unwrap();
-        let entry = &CacheEntry::new(redis_key, value.len() as CacheSizeType);
-        let _redis_resp_str = models::set_lru_cache_entry(
+        let entry = &CacheEntry::new(redis_key, size, headers.clone());
+        let result = match self.eviction {
+            Eviction::Lru => models::set_lru_cache_entry(
+                &mut con,
+                redis_key,
+                entry,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+            ),
+            Eviction::Lfu => models::set_lfu_cache_entry(
+                &mut con,
+                redis_key,
+                entry,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+                &self.lfu_age_key(),
+            ),
+            Eviction::Sieve => models::set_sieve_cache_entry(
+                &mut con,
+                redis_key,
+                entry,
+                &self.total_size_key(),
+                &self.entries_zlist_key(),
+                &self.sieve_seq_key(),
+            ),
+        };
+        if let Err(e) = result {
+            error!("failed to set cache entry {}: {}", key, e);
+        }
+        trace!("CACHE SET {} -> {} bytes", &redis_key, size);
+    }
+
+    /// The cursor is the cursor of `ZSCAN` on the zlist of entries.
+    fn get_lru_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        match models::zscan_members(&mut con, &self.entries_zlist_key(), cursor, limit) {
+            Ok(page) => KeyPage {
+                keys: page
+                    .keys
+                    .iter()
+                    .map(|k| self.from_prefixed_key(k))
+                    .collect(),
+                next: page.next,
+            },
+            Err(e) => {
+                error!("failed to list cache entries: {}", e);
+                KeyPage::default()
+            }
+        }
+    }
+
+    fn get_lru_entry_count(&self) -> u64 {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        con.zcard(self.entries_zlist_key()).unwrap_or_else(|e| {
+            error!("failed to count cache entries: {}", e);
+            0
+        })
+    }
+
+    fn peek_lru_entry(&self, key: &str) -> Option<LruCacheMetadata> {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        match models::get_cache_entry(&mut con, &self.to_prefixed_key(key)) {
+            Ok(entry) => entry.map(|entry| entry.metadata),
+            Err(e) => {
+                error!("failed to get cache entry {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn remove_lru_entry(&self, key: &str) -> bool {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        match models::remove_lru_cache_entry(
             &mut con,
-            redis_key,
-            entry,
+            &self.to_prefixed_key(key),
             &self.total_size_key(),
             &self.entries_zlist_key(),
-        );
-        trace!("CACHE SET {} -> {:?}", &redis_key, value);
+        ) {
+            Ok(existed) => existed,
+            Err(e) => {
+                error!("failed to remove cache entry {}: {}", key, e);
+                false
+            }
+        }
     }
 
     fn evict(
@@ -390,6 +1279,14 @@ impl LruMetadataStore for RedisMetadataDb {
         let redis_key = &self.to_prefixed_key(new_key);
         let file_size = new_size;
         let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
+        if self.eviction == Eviction::Sieve {
+            return self
+                .evict_sieve(&mut sync_con, new_size, size_limit)
+                .unwrap_or_else(|e| {
+                    error!("failed to evict cache entries: {}", e);
+                    Vec::new()
+                });
+        }
         // evict cache entry if necessary
         let _tx_result = redis::transaction(
             &mut sync_con,
@@ -407,12 +1304,11 @@ impl LruMetadataStore for RedisMetadataDb {
                         size_limit
                     );
                     let pkg_to_remove: Vec<(String, CacheSizeType)> =
-                        con.zpopmin(&self.entries_zlist_key(), 1).unwrap();
+                        con.zpopmin(self.entries_zlist_key(), 1).unwrap();
                     trace!("pkg_to_remove: {:?}", pkg_to_remove);
                     if pkg_to_remove.is_empty() {
                         info!("some files need to be evicted but they are missing from redis filelist. The cache metadata is inconsistent.");
-                        return Err(redis::RedisError::from(std::io::Error::new(
-                            std::io::ErrorKind::Other,
+                        return Err(redis::RedisError::from(std::io::Error::other(
                             "cache metadata inconsistent",
                         )));
                     }
@@ -422,6 +1318,11 @@ impl LruMetadataStore for RedisMetadataDb {
                             .map(|(k, _)| self.from_prefixed_key(k))
                             .collect(),
                     );
+                    // LFU: the cache ages to the priority of the evicted entry
+                    if self.eviction == Eviction::Lfu {
+                        let (_, priority) = &pkg_to_remove[0];
+                        con.set::<_, _, ()>(self.lfu_age_key(), priority)?;
+                    }
                     // remove metadata in redis
                     for (f, _) in pkg_to_remove {
                         let pkg_size: Option<CacheSizeType> = con.hget(&f, "size").unwrap();
@@ -451,39 +1352,133 @@ impl LruMetadataStore for RedisMetadataDb {
         histogram!(metric::get_cache_size_metrics_key(&self.id), size as f64);
         size
     }
+
+    fn set_total_size(&self, size: CacheSizeType) {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        if let Err(e) = con.set::<_, _, ()>(self.total_size_key(), size) {
+            error!("failed to set total size: {}", e);
+        }
+        histogram!(metric::get_cache_size_metrics_key(&self.id), size as f64);
+    }
 }
 
 impl TtlMetadataStore for RedisMetadataDb {
-    fn get_ttl_entry(&self, key: &str) -> CacheHitMiss {
+    fn get_ttl_entry(&self, key: &str) -> Option<TtlEntry> {
         let redis_key = Self::get_redis_key(&self.id, key);
         let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        match models::get(&mut sync_con, &redis_key) {
-            Ok(res) => match res {
-                Some(_) => CacheHitMiss::Hit,
-                None => CacheHitMiss::Miss,
-            },
+        match models::get_ttl_cache_entry(&mut sync_con, &redis_key) {
+            Ok(res) => res,
             Err(e) => {
                 info!("get cache entry key={} failed: {}", key, e);
-                CacheHitMiss::Miss
+                None
             }
         }
     }
-    fn set_ttl_entry(&self, key: &str, _value: &CacheData, ttl: u64) {
-        let redis_key = Self::get_redis_key(&self.id, key);
-        let mut sync_con = models::get_sync_con(&self.redis_client).unwrap();
-        match models::set(&mut sync_con, &redis_key, "") {
-            Ok(_) => {}
+    fn set_ttl_entry(
+        &self,
+        key: &str,
+        size: CacheSizeType,
+        headers: &ResponseHeaders,
+        ttl: u64,
+        retention: u64,
+    ) {
+        self.put_ttl_entry(key, Some(size), headers, ttl, retention);
+    }
+
+    fn renew_ttl_entry(&self, key: &str, headers: &ResponseHeaders, ttl: u64, retention: u64) {
+        self.put_ttl_entry(key, None, headers, ttl, retention);
+    }
+
+    fn touch_ttl_entry(&self, key: &str) {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        // the entry may be removed meanwhile
+        let result = redis::cmd("ZADD")
+            .arg(self.ttl_index_keys().atimes)
+            .arg("XX")
+            .arg(util::now())
+            .arg(key)
+            .query::<()>(&mut con);
+        if let Err(e) = result {
+            info!("Failed to update cache entry atime: {}", e);
+        }
+    }
+
+    fn set_ttl_entry_size(&self, key: &str, size: CacheSizeType) {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        if let Err(e) = models::set_ttl_cache_entry_size(
+            &mut con,
+            &Self::get_redis_key(&self.id, key),
+            key,
+            size,
+            &self.ttl_index_keys(),
+        ) {
+            error!("failed to set the size of cache entry {}: {}", key, e);
+        }
+    }
+
+    fn get_ttl_entry_size(&self, key: &str) -> Option<CacheSizeType> {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        match models::get_ttl_cache_entry_size(&mut con, key, &self.ttl_index_keys()) {
+            Ok(size) => size,
             Err(e) => {
-                error!("set cache entry for {} failed: {}", key, e);
+                error!("failed to get the size of cache entry {}: {}", key, e);
+                None
             }
         }
-        match models::expire(&mut sync_con, &redis_key, ttl as usize) {
-            Ok(_) => {}
+    }
+
+    /// The cursor is the cursor of `SCAN` on keys of the cache.
+    fn get_ttl_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        let pattern = Self::get_redis_key(&self.id, "*");
+        match models::scan_keys(&mut con, &pattern, cursor, limit) {
+            Ok(page) => KeyPage {
+                keys: page
+                    .keys
+                    .iter()
+                    .map(|k| Self::from_redis_key(&self.id, k))
+                    .collect(),
+                next: page.next,
+            },
             Err(e) => {
-                error!("set cache entry ttl for {} failed: {}", key, e);
+                error!("failed to list cache entries: {}", e);
+                KeyPage::default()
             }
         }
-        trace!("CACHE SET {} TTL={}", &key, ttl);
+    }
+
+    fn remove_ttl_entry(&self, key: &str) -> bool {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        match models::remove_ttl_cache_entry(
+            &mut con,
+            &Self::get_redis_key(&self.id, key),
+            key,
+            &self.ttl_index_keys(),
+            false,
+        ) {
+            Ok(existed) => existed,
+            Err(e) => {
+                error!("failed to remove cache entry {}: {}", key, e);
+                false
+            }
+        }
+    }
+
+    fn evict_ttl(&self, new_size: CacheSizeType, size_limit: CacheSizeType) -> Vec<String> {
+        let mut con = models::get_sync_con(&self.redis_client).unwrap();
+        let mut evicted = Vec::new();
+        if let Err(e) = self.evict_ttl_entries(&mut con, new_size, size_limit, &mut evicted) {
+            error!("failed to evict cache entries: {}", e);
+        }
+        evicted
+    }
+
+    fn get_ttl_total_size(&self) -> CacheSizeType {
+        LruMetadataStore::get_total_size(self)
+    }
+
+    fn set_ttl_total_size(&self, size: CacheSizeType) {
+        LruMetadataStore::set_total_size(self, size)
     }
 
     fn spawn_expiration_cleanup_thread(
@@ -493,6 +1488,7 @@ impl TtlMetadataStore for RedisMetadataDb {
     ) -> Result<JoinHandle<()>> {
         let cloned_client = self.redis_client.clone();
         let id_clone = self.id.to_string();
+        let index = self.ttl_index_keys();
         let storage_clone = storage.clone();
         let pending_close_clone = pending_close;
 
@@ -537,6 +1533,15 @@ impl TtlMetadataStore for RedisMetadataDb {
                                         if payload != "expired" {
                                             continue;
                                         }
+                                        if let Err(e) = models::get_sync_con(&cloned_client)
+                                            .and_then(|mut con| {
+                                                models::remove_ttl_cache_entry(
+                                                    &mut con, redis_key, &file, &index, true,
+                                                )
+                                            })
+                                        {
+                                            warn!("Failed to remove the size of {}: {}", &file, e);
+                                        }
                                         match storage_clone.remove(&file).await {
                                             Ok(_) => {
                                                 increment_counter!(metric::CNT_RM_FILES);
@@ -591,52 +1596,662 @@ impl Drop for TtlCache {
 }
 
 /// A wrapper for Sled
+#[derive(Clone)]
 pub struct SledMetadataDb {
     db: sled::Db,
     metadata_tree: sled::Tree,
+    /// Order of eviction or expiration -> filename. LFU and SIEVE caches have
+    /// their own order trees.
     atime_tree: sled::Tree,
+    /// LFU and SIEVE: filename -> `SledEvictionState`
+    state_tree: Option<sled::Tree>,
+    eviction: Eviction,
     /// Column family name
     cf: String,
     // TTL
     /// interval of periodic cleanup of expired entries in seconds
     clean_interval: u64,
+    ttl_index: Option<SledTtlIndex>,
+}
+
+/// Trees of a TTL cache, which account for the size of its entries and order
+/// them for eviction
+#[derive(Clone)]
+struct SledTtlIndex {
+    /// filename -> `SledTtlUsage`
+    usage_tree: sled::Tree,
+    /// atime -> filename
+    lru_tree: sled::Tree,
+    /// expire time -> filename
+    expire_tree: sled::Tree,
 }
 
 impl SledMetadataDb {
+    #[cfg(test)]
     pub fn new_lru(path: &str, cf_name: &str) -> Self {
-        let db = Self::open_db(path).unwrap();
-        let metadata_tree = db.open_tree(cf_name).unwrap();
-        let atime_tree = db.open_tree(format!("{}_atime_tree", path)).unwrap();
-        db.transaction::<_, _, ()>(|tx_db| {
-            models::sled_try_init_current_size(tx_db, cf_name).unwrap();
-            Ok(())
-        })
-        .unwrap();
-        Self {
+        Self::new_bounded(path, cf_name, Eviction::Lru)
+    }
+
+    /// A metadata database of a size-bounded cache evicting in the given order
+    #[cfg(test)]
+    pub fn new_bounded(path: &str, cf_name: &str, eviction: Eviction) -> Self {
+        Self::bounded_from_db(Self::open_db(path).unwrap(), path, cf_name, eviction).unwrap()
+    }
+
+    /// `new_bounded` with the database at `path` already opened
+    pub fn bounded_from_db(
+        db: sled::Db,
+        path: &str,
+        cf_name: &str,
+        eviction: Eviction,
+    ) -> Result<Self> {
+        let metadata_tree = db.open_tree(cf_name).map_err(Error::SledError)?;
+        let order_tree = match eviction {
+            Eviction::Lru => "atime_tree",
+            Eviction::Lfu => "lfu_tree",
+            Eviction::Sieve => "sieve_tree",
+        };
+        let atime_tree = db
+            .open_tree(format!("{}_{}", path, order_tree))
+            .map_err(Error::SledError)?;
+        let state_tree = match eviction {
+            Eviction::Lru => None,
+            _ => Some(
+                db.open_tree(format!("{}_state_tree", path))
+                    .map_err(Error::SledError)?,
+            ),
+        };
+        Self::init_total_size(&db, cf_name)?;
+        let metadata_db = Self {
             db,
             metadata_tree,
             atime_tree,
+            state_tree,
+            eviction,
             cf: cf_name.to_string(),
             clean_interval: 0,
+            ttl_index: None,
+        };
+        metadata_db.reindex()?;
+        Ok(metadata_db)
+    }
+
+    #[cfg(test)]
+    pub fn new_ttl(path: &str, cf_name: &str, clean_interval: u64) -> Self {
+        Self::ttl_from_db(Self::open_db(path).unwrap(), path, cf_name, clean_interval).unwrap()
+    }
+
+    /// `new_ttl` with the database at `path` already opened
+    pub fn ttl_from_db(
+        db: sled::Db,
+        path: &str,
+        cf_name: &str,
+        clean_interval: u64,
+    ) -> Result<Self> {
+        let metadata_tree = db.open_tree(cf_name).map_err(Error::SledError)?;
+        let atime_tree = db
+            .open_tree(format!("{}_atime_tree", path))
+            .map_err(Error::SledError)?;
+        let ttl_index = SledTtlIndex {
+            usage_tree: db
+                .open_tree(format!("{}_ttl_usage_tree", path))
+                .map_err(Error::SledError)?,
+            lru_tree: db
+                .open_tree(format!("{}_ttl_lru_tree", path))
+                .map_err(Error::SledError)?,
+            expire_tree: db
+                .open_tree(format!("{}_ttl_expire_tree", path))
+                .map_err(Error::SledError)?,
+        };
+        Self::init_total_size(&db, cf_name)?;
+        Ok(Self {
+            db,
+            metadata_tree,
+            atime_tree,
+            state_tree: None,
+            eviction: Eviction::Lru,
+            cf: cf_name.to_string(),
+            clean_interval,
+            ttl_index: Some(ttl_index),
+        })
+    }
+
+    fn init_total_size(db: &sled::Db, cf_name: &str) -> Result<()> {
+        db.transaction(
+            |tx_db| match models::sled_try_init_current_size(tx_db, cf_name) {
+                Ok(()) => Ok(()),
+                Err(Error::SledUnabortableTransactionError(e)) => Err(e.into()),
+                Err(e) => Err(ConflictableTransactionError::Abort(e)),
+            },
+        )
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => Error::SledError(e),
+        })
+    }
+
+    /// Update the total size in a transaction, after an entry of `removed` bytes
+    /// is replaced by `added` bytes
+    fn update_total_size(
+        &self,
+        db: &sled::transaction::TransactionalTree,
+        removed: CacheSizeType,
+        added: CacheSizeType,
+    ) {
+        let current_size = models::sled_lru_get_current_size(db, &self.cf)
+            .unwrap()
+            .unwrap_or(0)
+            .saturating_sub(removed)
+            + added;
+        models::sled_lru_set_current_size(db, &self.cf, current_size);
+        histogram!(
+            metric::get_cache_size_metrics_key(&self.cf),
+            current_size as f64
+        );
+    }
+
+    /// TTL: set an entry, and keep its recorded size if `size` is `None`
+    fn put_ttl_entry(
+        &self,
+        key: &str,
+        size: Option<CacheSizeType>,
+        headers: &ResponseHeaders,
+        ttl: u64,
+        retention: u64,
+    ) {
+        let index = self.ttl_index.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> = (
+            db_tree,
+            &self.metadata_tree,
+            &self.atime_tree,
+            &index.usage_tree,
+            &index.lru_tree,
+            &index.expire_tree,
+        )
+            .transaction(
+                |(db, metadata_tree, atime_tree, usage_tree, lru_tree, expire_tree)| {
+                    let now = util::now_nanos();
+                    let metadata = SledTtlMetadata {
+                        expire_time: now + ttl as i64 * 1_000_000_000,
+                        remove_time: now + retention as i64 * 1_000_000_000,
+                        headers: headers.clone(),
+                    };
+                    let remove_time = metadata.remove_time.to_be_bytes();
+                    let expire_time = metadata.expire_time.to_be_bytes();
+                    if let Some(old) = metadata_tree.insert(key, metadata)? {
+                        // the old entry should not remove the new one
+                        let old: SledTtlMetadata = old.into();
+                        atime_tree.remove(&old.remove_time.to_be_bytes())?;
+                        expire_tree.remove(&old.expire_time.to_be_bytes())?;
+                    }
+                    atime_tree.insert(&remove_time, key)?;
+                    expire_tree.insert(&expire_time, key)?;
+                    let old_size = match usage_tree.get(key)? {
+                        Some(old) => {
+                            let old: SledTtlUsage = old.into();
+                            lru_tree.remove(&old.atime.to_be_bytes())?;
+                            old.size
+                        }
+                        None => 0,
+                    };
+                    let size = size.unwrap_or(old_size);
+                    usage_tree.insert(key, SledTtlUsage { size, atime: now })?;
+                    lru_tree.insert(&now.to_be_bytes(), key)?;
+                    self.update_total_size(db, old_size, size);
+                    Ok(())
+                },
+            );
+        if let Err(e) = tx_result {
+            error!("Failed to set_ttl_entry: {:?}", e);
+        }
+        trace!("CACHE SET {} TTL={} RETENTION={}", &key, ttl, retention);
+    }
+
+    /// TTL: remove an entry and its size. With `remove_key`, the entry is only
+    /// removed if the key is still in `atime_tree`, i.e. it is not renewed.
+    /// Returns whether the entry existed.
+    fn remove_ttl_metadata(&self, key: &str, remove_key: Option<&[u8]>) -> bool {
+        let index = self.ttl_index.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> = (
+            db_tree,
+            &self.metadata_tree,
+            &self.atime_tree,
+            &index.usage_tree,
+            &index.lru_tree,
+            &index.expire_tree,
+        )
+            .transaction(
+                |(db, metadata_tree, atime_tree, usage_tree, lru_tree, expire_tree)| {
+                    if let Some(remove_key) = remove_key {
+                        if atime_tree.remove(remove_key)?.is_none() {
+                            return Ok(false);
+                        }
+                    }
+                    let existed = match metadata_tree.remove(key)? {
+                        Some(old) => {
+                            let old: SledTtlMetadata = old.into();
+                            atime_tree.remove(&old.remove_time.to_be_bytes())?;
+                            expire_tree.remove(&old.expire_time.to_be_bytes())?;
+                            true
+                        }
+                        None => false,
+                    };
+                    if let Some(usage) = usage_tree.remove(key)? {
+                        let usage: SledTtlUsage = usage.into();
+                        lru_tree.remove(&usage.atime.to_be_bytes())?;
+                        self.update_total_size(db, usage.size, 0);
+                    }
+                    Ok(existed)
+                },
+            );
+        tx_result.unwrap_or_else(|e| {
+            error!("Failed to remove_ttl_entry: {:?}", e);
+            false
+        })
+    }
+
+    /// Size-bounded: make the order tree consistent with the metadata, after
+    /// entries were set or evicted under another eviction policy. Stale keys are
+    /// dropped, and entries missing from the tree are indexed as if they were
+    /// just inserted, keeping their LFU frequency if any.
+    fn reindex(&self) -> Result<()> {
+        let mut stale = Vec::new();
+        for item in self.atime_tree.iter() {
+            let (order_key, filename) = item.map_err(Error::SledError)?;
+            if self.order_key(&filename)?.as_deref() != Some(&order_key[..]) {
+                stale.push((order_key, filename));
+            }
+        }
+        for (order_key, filename) in stale {
+            // the key is left alone if it has been reused meanwhile
+            let _ = self
+                .atime_tree
+                .compare_and_swap(order_key, Some(filename), None::<&[u8]>)
+                .map_err(Error::SledError)?;
+        }
+        let mut reindexed = 0;
+        for item in self.metadata_tree.iter() {
+            let (filename, metadata) = item.map_err(Error::SledError)?;
+            let indexed = match self.order_key(&filename)? {
+                Some(order_key) => self.atime_tree.get(order_key).map_err(Error::SledError)?,
+                None => None,
+            };
+            if indexed.as_deref() == Some(&filename[..]) {
+                continue;
+            }
+            let metadata: SledMetadata = metadata.into();
+            match &self.state_tree {
+                None => {
+                    self.atime_tree
+                        .insert(metadata.atime.to_be_bytes(), filename)
+                        .map_err(Error::SledError)?;
+                }
+                Some(state_tree) => self.reindex_ordered(state_tree, &filename, metadata.atime)?,
+            }
+            reindexed += 1;
+        }
+        if reindexed > 0 {
+            info!(
+                "indexed {} entries of {} for {:?}",
+                reindexed, self.cf, self.eviction
+            );
+        }
+        Ok(())
+    }
+
+    /// Size-bounded: the key of an entry in the order tree, if it is indexed
+    fn order_key(&self, filename: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match &self.state_tree {
+            None => self
+                .metadata_tree
+                .get(filename)
+                .map_err(Error::SledError)?
+                .map(|metadata| SledMetadata::from(metadata).atime.to_be_bytes().to_vec()),
+            Some(state_tree) => {
+                if !self
+                    .metadata_tree
+                    .contains_key(filename)
+                    .map_err(Error::SledError)?
+                {
+                    return Ok(None);
+                }
+                state_tree
+                    .get(filename)
+                    .map_err(Error::SledError)?
+                    .map(|state| SledEvictionState::from(state).order_key)
+            }
+        })
+    }
+
+    /// LFU and SIEVE: index an entry missing from the order tree
+    fn reindex_ordered(&self, state_tree: &sled::Tree, filename: &[u8], atime: i64) -> Result<()> {
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> = (db_tree, &self.atime_tree, state_tree)
+            .transaction(|(db, order_tree, state_tree)| {
+                let state: Option<SledEvictionState> = state_tree.get(filename)?.map(Into::into);
+                let state = match self.eviction {
+                    Eviction::Sieve => {
+                        // a SIEVE key is unique, bump it past entries with the same atime
+                        let mut order_key = atime;
+                        while order_tree.get(order_key.to_be_bytes())?.is_some() {
+                            order_key += 1;
+                        }
+                        SledEvictionState {
+                            freq: state.map_or(0, |state| state.freq),
+                            visited: false,
+                            order_key: order_key.to_be_bytes().to_vec(),
+                        }
+                    }
+                    _ => {
+                        let freq = state.map_or(1, |state| state.freq.max(1));
+                        SledEvictionState {
+                            freq,
+                            visited: false,
+                            order_key: self.lfu_order_key(db, freq, atime)?,
+                        }
+                    }
+                };
+                order_tree.insert(state.order_key.as_slice(), filename)?;
+                state_tree.insert(filename, state)?;
+                Ok(())
+            });
+        tx_result.map_err(|e| match e {
+            TransactionError::Abort(()) => {
+                Error::OtherError(format!("failed to index {}", self.cf))
+            }
+            TransactionError::Storage(e) => Error::SledError(e),
+        })
+    }
+
+    /// LFU: priority of the last evicted entry
+    fn lfu_age_key(&self) -> String {
+        format!("{}_lfu_age", self.cf)
+    }
+
+    /// SIEVE: key in the order tree from which the hand looks for an entry to evict
+    fn sieve_hand_key(&self) -> String {
+        format!("{}_sieve_hand", self.cf)
+    }
+
+    /// LFU: the key in the order tree, by priority and then by atime
+    fn lfu_order_key(
+        &self,
+        db: &sled::transaction::TransactionalTree,
+        freq: u64,
+        atime: i64,
+    ) -> std::result::Result<Vec<u8>, sled::transaction::UnabortableTransactionError> {
+        let age = db
+            .get(self.lfu_age_key())?
+            .map_or(0, |age| util::ivec_to_u64(&age));
+        Ok([
+            &age.saturating_add(freq).to_be_bytes()[..],
+            &atime.to_be_bytes()[..],
+        ]
+        .concat())
+    }
+
+    /// LFU and SIEVE: count a hit of the entry, and return its headers
+    fn hit_ordered(&self, key: &str) -> Option<ResponseHeaders> {
+        let state_tree = self.state_tree.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> =
+            (db_tree, &self.metadata_tree, &self.atime_tree, state_tree).transaction(
+                |(db, metadata_tree, order_tree, state_tree)| {
+                    let mut metadata: SledMetadata = match metadata_tree.get(key)? {
+                        Some(metadata) => metadata.into(),
+                        None => return Ok(None),
+                    };
+                    let headers = metadata.headers.clone();
+                    let mut state: SledEvictionState =
+                        state_tree.get(key)?.map(Into::into).unwrap_or_default();
+                    match self.eviction {
+                        Eviction::Lfu => {
+                            metadata.atime = util::now_nanos();
+                            order_tree.remove(state.order_key.as_slice())?;
+                            state.freq = state.freq.saturating_add(1);
+                            state.order_key = self.lfu_order_key(db, state.freq, metadata.atime)?;
+                            order_tree.insert(state.order_key.as_slice(), key)?;
+                            state_tree.insert(key, state)?;
+                            metadata_tree.insert(key, metadata)?;
+                        }
+                        // a hit is only marked, so that it is cheap
+                        Eviction::Sieve if !state.visited => {
+                            state.visited = true;
+                            state_tree.insert(key, state)?;
+                        }
+                        _ => {}
+                    }
+                    Ok(Some(headers))
+                },
+            );
+        tx_result.unwrap_or_else(|e| {
+            error!("Failed to get_lru_entry: {:?}", e);
+            None
+        })
+    }
+
+    /// LFU and SIEVE: set an entry. An existing LFU entry counts as a hit, and
+    /// an existing SIEVE entry keeps its position and is marked visited.
+    fn set_ordered(&self, key: &str, size: CacheSizeType, headers: &ResponseHeaders) {
+        let atime = util::now_nanos();
+        let state_tree = self.state_tree.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> =
+            (db_tree, &self.metadata_tree, &self.atime_tree, state_tree).transaction(
+                |(db, metadata_tree, order_tree, state_tree)| {
+                    let metadata = SledMetadata {
+                        atime,
+                        size,
+                        headers: headers.clone(),
+                    };
+                    let old_size = metadata_tree
+                        .insert(key, metadata)?
+                        .map_or(0, |old| SledMetadata::from(old).size);
+                    let current_size = models::sled_lru_get_current_size(db, &self.cf)
+                        .unwrap()
+                        .unwrap_or(0)
+                        .saturating_sub(old_size)
+                        + size;
+                    models::sled_lru_set_current_size(db, &self.cf, current_size);
+                    histogram!(
+                        metric::get_cache_size_metrics_key(&self.cf),
+                        current_size as f64
+                    );
+                    let state: Option<SledEvictionState> = state_tree.get(key)?.map(Into::into);
+                    let state = match (self.eviction, state) {
+                        (Eviction::Sieve, Some(state)) => SledEvictionState {
+                            visited: true,
+                            ..state
+                        },
+                        (Eviction::Sieve, None) => SledEvictionState {
+                            freq: 0,
+                            visited: false,
+                            order_key: atime.to_be_bytes().to_vec(),
+                        },
+                        (_, state) => {
+                            let freq = match state {
+                                Some(state) => {
+                                    order_tree.remove(state.order_key.as_slice())?;
+                                    state.freq.saturating_add(1)
+                                }
+                                None => 1,
+                            };
+                            SledEvictionState {
+                                freq,
+                                visited: false,
+                                order_key: self.lfu_order_key(db, freq, atime)?,
+                            }
+                        }
+                    };
+                    order_tree.insert(state.order_key.as_slice(), key)?;
+                    state_tree.insert(key, state)?;
+                    Ok(())
+                },
+            );
+        if let Err(e) = tx_result {
+            error!("Failed to set_lru_entry: {:?}", e);
+        }
+    }
+
+    /// LFU and SIEVE: evict entries until `new_size` fits in `size_limit`
+    fn evict_ordered(&self, new_size: CacheSizeType, size_limit: CacheSizeType) -> Vec<String> {
+        let mut files_to_remove = Vec::new();
+        let state_tree = self.state_tree.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        while models::sled_lru_get_current_size_notx(&self.db, &self.cf)
+            .unwrap()
+            .unwrap_or(0)
+            + new_size
+            > size_limit
+        {
+            // read a possible eviction candidate, multiple threads may read the same one
+            let candidate = match self.eviction {
+                Eviction::Sieve => {
+                    let hand = self
+                        .db
+                        .get(self.sieve_hand_key())
+                        .ok()
+                        .flatten()
+                        .map(|hand| hand.to_vec())
+                        .unwrap_or_default();
+                    // wrap around to the oldest entry
+                    self.atime_tree
+                        .range(hand.as_slice()..)
+                        .next()
+                        .or_else(|| self.atime_tree.iter().next())
+                }
+                _ => self.atime_tree.iter().next(),
+            };
+            let (order_key, filename) = match candidate {
+                Some(Ok(candidate)) => candidate,
+                _ => {
+                    warn!(
+                        "no entry to evict for {}, the total size is inconsistent",
+                        self.cf
+                    );
+                    break;
+                }
+            };
+            let filename = String::from_utf8_lossy(&filename).into_owned();
+            let tx_result: TransactionResult<_, ()> =
+                (db_tree, &self.metadata_tree, &self.atime_tree, state_tree).transaction(
+                    |(db, metadata_tree, order_tree, state_tree)| {
+                        if order_tree.get(&order_key)?.is_none() {
+                            // some other thread has moved or removed the entry
+                            return Ok(None);
+                        }
+                        match self.eviction {
+                            Eviction::Sieve => {
+                                // the key right after the candidate
+                                let next = [&order_key[..], &[0]].concat();
+                                db.insert(self.sieve_hand_key().as_str(), next)?;
+                                let state: Option<SledEvictionState> =
+                                    state_tree.get(filename.as_str())?.map(Into::into);
+                                if let Some(state) = state.filter(|state| state.visited) {
+                                    let state = SledEvictionState {
+                                        visited: false,
+                                        ..state
+                                    };
+                                    state_tree.insert(filename.as_str(), state)?;
+                                    return Ok(None);
+                                }
+                            }
+                            _ => {
+                                // the cache ages to the priority of the evicted entry
+                                db.insert(self.lfu_age_key().as_str(), &order_key[..8])?;
+                            }
+                        }
+                        order_tree.remove(&order_key)?;
+                        state_tree.remove(filename.as_str())?;
+                        if let Some(old) = metadata_tree.remove(filename.as_str())? {
+                            let old: SledMetadata = old.into();
+                            let current_size = models::sled_lru_get_current_size(db, &self.cf)
+                                .unwrap()
+                                .unwrap_or(0)
+                                .saturating_sub(old.size);
+                            models::sled_lru_set_current_size(db, &self.cf, current_size);
+                            histogram!(
+                                metric::get_cache_size_metrics_key(&self.cf),
+                                current_size as f64
+                            );
+                        }
+                        Ok(Some(filename.clone()))
+                    },
+                );
+            match tx_result {
+                Ok(Some(filename)) => files_to_remove.push(filename),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to evict {}: {:?}", filename, e);
+                    break;
+                }
+            }
         }
+        files_to_remove
     }
 
-    pub fn new_ttl(path: &str, cf_name: &str, clean_interval: u64) -> Self {
-        let db = Self::open_db(path).unwrap();
-        let metadata_tree = db.open_tree(cf_name).unwrap();
-        let atime_tree = db.open_tree(format!("{}_atime_tree", path)).unwrap();
-        Self {
-            db,
-            metadata_tree,
-            atime_tree,
-            cf: cf_name.to_string(),
-            clean_interval,
-        }
+    /// LFU and SIEVE: remove an entry and update the total size
+    fn remove_ordered(&self, key: &str) -> bool {
+        let state_tree = self.state_tree.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> =
+            (db_tree, &self.metadata_tree, &self.atime_tree, state_tree).transaction(
+                |(db, metadata_tree, order_tree, state_tree)| match metadata_tree.remove(key)? {
+                    Some(old) => {
+                        let old: SledMetadata = old.into();
+                        if let Some(state) = state_tree.remove(key)? {
+                            let state: SledEvictionState = state.into();
+                            order_tree.remove(state.order_key.as_slice())?;
+                        }
+                        let current_size = models::sled_lru_get_current_size(db, &self.cf)
+                            .unwrap()
+                            .unwrap_or(0)
+                            .saturating_sub(old.size);
+                        models::sled_lru_set_current_size(db, &self.cf, current_size);
+                        histogram!(
+                            metric::get_cache_size_metrics_key(&self.cf),
+                            current_size as f64
+                        );
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                },
+            );
+        tx_result.unwrap_or_else(|e| {
+            error!("Failed to remove_lru_entry: {:?}", e);
+            false
+        })
+    }
+
+    /// A page of keys in `metadata_tree`, in lexicographic order.
+    /// The cursor is the last key of the previous page.
+    fn sled_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        let lower = match cursor {
+            Some(cursor) => Bound::Excluded(cursor.as_bytes()),
+            None => Bound::Unbounded,
+        };
+        let mut keys: Vec<String> = self
+            .metadata_tree
+            .range::<&[u8], _>((lower, Bound::Unbounded))
+            .keys()
+            .filter_map(|key| key.ok())
+            .map(|key| String::from_utf8_lossy(&key).into_owned())
+            .take(limit + 1)
+            .collect();
+        let next = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().cloned()
+        } else {
+            None
+        };
+        KeyPage { keys, next }
     }
 
     /// Open db, and retry if fails
     /// Reference: https://github.com/spacejam/sled/issues/1234
-    fn open_db(path: impl AsRef<Path>) -> Result<sled::Db> {
+    pub fn open_db(path: impl AsRef<Path>) -> Result<sled::Db> {
         let mut sled_error = Error::OtherError("Unknown error: sled not initialized".into());
         for retry_attempt in 0..10 {
             match sled::open(&path) {
@@ -662,37 +2277,44 @@ impl SledMetadataDb {
 /// Two mappings are maintained:
 /// 1. filename -> (size, atime)
 /// 2. atime -> filename
+///
 /// The `filename` is the external cache key. Its `atime` is stored to remove old
 /// atime mapping.
 impl LruMetadataStore for SledMetadataDb {
-    fn get_lru_entry(&self, key: &str) -> CacheHitMiss {
+    fn get_lru_entry(&self, key: &str) -> Option<ResponseHeaders> {
+        if self.eviction != Eviction::Lru {
+            return self.hit_ordered(key);
+        }
         let tx_result: TransactionResult<_, TransactionError> =
             (&self.metadata_tree, &self.atime_tree).transaction(|(metadata_tree, atime_tree)| {
                 match metadata_tree.get(key) {
                     Ok(Some(_)) => {
                         // update cache entry in db
                         let new_atime = util::now_nanos();
-                        models::sled_update_cache_entry_atime(
+                        let entry = models::sled_update_cache_entry_atime(
                             metadata_tree,
                             atime_tree,
                             key,
                             new_atime,
                         );
-                        Ok(CacheHitMiss::Hit)
+                        Ok(Some(entry.headers))
                     }
-                    _ => Ok(CacheHitMiss::Miss),
+                    _ => Ok(None),
                 }
             });
         match tx_result {
-            Ok(hit_miss) => hit_miss,
+            Ok(headers) => headers,
             Err(e) => {
                 error!("Failed to get_lru_entry: {}", e);
-                CacheHitMiss::Miss
+                None
             }
         }
     }
 
-    fn set_lru_entry(&self, key: &str, value: &CacheData) {
+    fn set_lru_entry(&self, key: &str, size: CacheSizeType, headers: &ResponseHeaders) {
+        if self.eviction != Eviction::Lru {
+            return self.set_ordered(key, size, headers);
+        }
         let atime = util::now_nanos();
         let db_tree: &sled::Tree = &self.db;
         let tx_result: TransactionResult<_, TransactionError> =
@@ -704,13 +2326,16 @@ impl LruMetadataStore for SledMetadataDb {
                         metadata_tree,
                         atime_tree,
                         key,
-                        value.len() as CacheSizeType,
-                        atime,
+                        models::SledMetadata {
+                            atime,
+                            size,
+                            headers: headers.clone(),
+                        },
                     );
                     let current_size = models::sled_lru_get_current_size(db, &self.cf)
                         .unwrap()
                         .unwrap()
-                        + value.len() as CacheSizeType;
+                        + size;
                     models::sled_lru_set_current_size(db, &self.cf, current_size);
                     histogram!(
                         metric::get_cache_size_metrics_key(&self.cf),
@@ -734,6 +2359,9 @@ impl LruMetadataStore for SledMetadataDb {
         _new_key: &str,
         size_limit: CacheSizeType,
     ) -> Vec<String> {
+        if self.eviction != Eviction::Lru {
+            return self.evict_ordered(evict_size, size_limit);
+        }
         let mut files_to_remove = Vec::new();
         let db = &self.db;
         let prefix = &self.cf;
@@ -783,6 +2411,12 @@ impl LruMetadataStore for SledMetadataDb {
                 if let Some(filename) = tx_result.unwrap() {
                     files_to_remove.push(filename);
                 }
+            } else {
+                warn!(
+                    "no entry to evict for {}, the total size is inconsistent",
+                    prefix
+                );
+                break;
             }
         }
         files_to_remove
@@ -797,36 +2431,226 @@ impl LruMetadataStore for SledMetadataDb {
             })
             .unwrap()
     }
+
+    fn set_total_size(&self, size: CacheSizeType) {
+        let tx_result: TransactionResult<_, ()> = self.db.transaction(|db| {
+            models::sled_lru_set_current_size(db, &self.cf, size);
+            Ok(())
+        });
+        if let Err(e) = tx_result {
+            error!("Failed to set_total_size: {:?}", e);
+        }
+        histogram!(metric::get_cache_size_metrics_key(&self.cf), size as f64);
+    }
+
+    fn get_lru_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        self.sled_keys(cursor, limit)
+    }
+
+    fn get_lru_entry_count(&self) -> u64 {
+        self.metadata_tree.len() as u64
+    }
+
+    fn peek_lru_entry(&self, key: &str) -> Option<LruCacheMetadata> {
+        match self.metadata_tree.get(key) {
+            Ok(Some(val)) => {
+                let metadata: SledMetadata = val.into();
+                Some(LruCacheMetadata {
+                    size: metadata.size,
+                    atime: metadata.atime / 1_000_000_000,
+                    headers: metadata.headers,
+                })
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("failed to get lru entry {}: {:?}", key, e);
+                None
+            }
+        }
+    }
+
+    fn remove_lru_entry(&self, key: &str) -> bool {
+        if self.eviction != Eviction::Lru {
+            return self.remove_ordered(key);
+        }
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> = (db_tree, &self.metadata_tree, &self.atime_tree)
+            .transaction(|(db, metadata_tree, atime_tree)| {
+                match metadata_tree.remove(key).unwrap() {
+                    Some(old) => {
+                        let old: SledMetadata = old.into();
+                        atime_tree.remove(&old.atime.to_be_bytes()).unwrap();
+                        let current_size = models::sled_lru_get_current_size(db, &self.cf)
+                            .unwrap()
+                            .unwrap()
+                            .saturating_sub(old.size);
+                        models::sled_lru_set_current_size(db, &self.cf, current_size);
+                        histogram!(
+                            metric::get_cache_size_metrics_key(&self.cf),
+                            current_size as f64
+                        );
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            });
+        tx_result.unwrap_or_else(|e| {
+            error!("Failed to remove_lru_entry: {:?}", e);
+            false
+        })
+    }
 }
 
 impl TtlMetadataStore for SledMetadataDb {
-    fn get_ttl_entry(&self, key: &str) -> CacheHitMiss {
+    /// The value in `metadata_tree` is a `SledTtlMetadata`. `atime_tree` maps the
+    /// removal time to the key.
+    fn get_ttl_entry(&self, key: &str) -> Option<TtlEntry> {
         match self.metadata_tree.get(key) {
             Ok(Some(val)) => {
-                let exp_time: i64 = i64::from_be_bytes(val.as_ref().try_into().unwrap());
-                if exp_time > util::now_nanos() {
-                    CacheHitMiss::Hit
+                let metadata: SledTtlMetadata = val.into();
+                // the entry may not be cleaned up yet
+                if metadata.remove_time > util::now_nanos() {
+                    Some(TtlEntry {
+                        expire_time: metadata.expire_time,
+                        headers: metadata.headers,
+                    })
                 } else {
-                    CacheHitMiss::Miss
+                    None
                 }
             }
-            Ok(None) => CacheHitMiss::Miss,
+            Ok(None) => None,
             Err(e) => {
                 error!("failed to get ttl entry {}: {:?}", key, e);
-                CacheHitMiss::Miss
+                None
             }
         }
     }
 
-    fn set_ttl_entry(&self, key: &str, _value: &CacheData, ttl: u64) {
-        let _tx_result: TransactionResult<_, ()> = (&self.atime_tree, &self.metadata_tree)
-            .transaction(|(atime_tree, metadata_tree)| {
-                let expire_time = (util::now_nanos() + ttl as i64 * 1_000_000_000).to_be_bytes();
-                atime_tree.insert(&expire_time, key).unwrap();
-                metadata_tree.insert(key, &expire_time).unwrap();
+    fn set_ttl_entry(
+        &self,
+        key: &str,
+        size: CacheSizeType,
+        headers: &ResponseHeaders,
+        ttl: u64,
+        retention: u64,
+    ) {
+        self.put_ttl_entry(key, Some(size), headers, ttl, retention);
+    }
+
+    fn renew_ttl_entry(&self, key: &str, headers: &ResponseHeaders, ttl: u64, retention: u64) {
+        self.put_ttl_entry(key, None, headers, ttl, retention);
+    }
+
+    fn touch_ttl_entry(&self, key: &str) {
+        let index = self.ttl_index.as_ref().unwrap();
+        let tx_result: TransactionResult<_, ()> =
+            (&index.usage_tree, &index.lru_tree).transaction(|(usage_tree, lru_tree)| {
+                if let Some(usage) = usage_tree.get(key)? {
+                    let usage: SledTtlUsage = usage.into();
+                    let atime = util::now_nanos();
+                    lru_tree.remove(&usage.atime.to_be_bytes())?;
+                    lru_tree.insert(&atime.to_be_bytes(), key)?;
+                    usage_tree.insert(key, SledTtlUsage { atime, ..usage })?;
+                }
+                Ok(())
+            });
+        if let Err(e) = tx_result {
+            error!("Failed to touch_ttl_entry: {:?}", e);
+        }
+    }
+
+    fn set_ttl_entry_size(&self, key: &str, size: CacheSizeType) {
+        let index = self.ttl_index.as_ref().unwrap();
+        let db_tree: &sled::Tree = &self.db;
+        let tx_result: TransactionResult<_, ()> = (
+            db_tree,
+            &self.metadata_tree,
+            &index.usage_tree,
+            &index.lru_tree,
+            &index.expire_tree,
+        )
+            .transaction(|(db, metadata_tree, usage_tree, lru_tree, expire_tree)| {
+                let metadata: SledTtlMetadata = match metadata_tree.get(key)? {
+                    Some(metadata) => metadata.into(),
+                    None => return Ok(()),
+                };
+                // entries of old versions are not indexed
+                expire_tree.insert(&metadata.expire_time.to_be_bytes(), key)?;
+                let (old_size, atime) = match usage_tree.get(key)? {
+                    Some(old) => {
+                        let old: SledTtlUsage = old.into();
+                        (old.size, old.atime)
+                    }
+                    None => (0, util::now_nanos()),
+                };
+                lru_tree.insert(&atime.to_be_bytes(), key)?;
+                usage_tree.insert(key, SledTtlUsage { size, atime })?;
+                self.update_total_size(db, old_size, size);
                 Ok(())
             });
-        trace!("CACHE SET {} TTL={}", &key, ttl);
+        if let Err(e) = tx_result {
+            error!("Failed to set_ttl_entry_size: {:?}", e);
+        }
+    }
+
+    fn get_ttl_entry_size(&self, key: &str) -> Option<CacheSizeType> {
+        let index = self.ttl_index.as_ref().unwrap();
+        match index.usage_tree.get(key) {
+            Ok(usage) => usage.map(|usage| SledTtlUsage::from(usage).size),
+            Err(e) => {
+                error!("failed to get the size of ttl entry {}: {:?}", key, e);
+                None
+            }
+        }
+    }
+
+    fn get_ttl_keys(&self, cursor: Option<&str>, limit: usize) -> KeyPage {
+        self.sled_keys(cursor, limit)
+    }
+
+    fn remove_ttl_entry(&self, key: &str) -> bool {
+        self.remove_ttl_metadata(key, None)
+    }
+
+    fn evict_ttl(&self, new_size: CacheSizeType, size_limit: CacheSizeType) -> Vec<String> {
+        let index = self.ttl_index.as_ref().unwrap();
+        let mut files_to_remove = Vec::new();
+        while self.get_ttl_total_size() + new_size > size_limit {
+            let now = util::now_nanos().to_be_bytes();
+            let (order_tree, candidate) = match index.expire_tree.range(..now).next() {
+                Some(expired) => (&index.expire_tree, Some(expired)),
+                None => (&index.lru_tree, index.lru_tree.iter().next()),
+            };
+            let (order_key, filename) = match candidate {
+                Some(Ok(candidate)) => candidate,
+                _ => {
+                    warn!(
+                        "no entry to evict for {}, the total size is inconsistent",
+                        self.cf
+                    );
+                    break;
+                }
+            };
+            let filename = String::from_utf8_lossy(&filename).into_owned();
+            if self.remove_ttl_metadata(&filename, None) {
+                files_to_remove.push(filename);
+            } else if let Err(e) = order_tree.remove(&order_key) {
+                // the entry is removed, but not its key in the order
+                error!("Failed to evict {}: {:?}", filename, e);
+                break;
+            }
+        }
+        files_to_remove
+    }
+
+    fn get_ttl_total_size(&self) -> CacheSizeType {
+        models::sled_lru_get_current_size_notx(&self.db, &self.cf)
+            .unwrap()
+            .unwrap_or(0)
+    }
+
+    fn set_ttl_total_size(&self, size: CacheSizeType) {
+        LruMetadataStore::set_total_size(self, size)
     }
 
     fn spawn_expiration_cleanup_thread(
@@ -836,8 +2660,7 @@ impl TtlMetadataStore for SledMetadataDb {
     ) -> Result<JoinHandle<()>> {
         let storage_clone = storage.clone();
         let pending_close_clone = pending_close;
-        let atime_tree = self.atime_tree.clone();
-        let metadata_tree = self.metadata_tree.clone();
+        let db = self.clone();
         let clean_interval = self.clean_interval;
         let expiration_thread_handler = std::thread::spawn(move || {
             futures::executor::block_on(async move {
@@ -847,20 +2670,15 @@ impl TtlMetadataStore for SledMetadataDb {
                         return;
                     }
                     let time = util::now_nanos();
-                    let files_to_remove: Vec<String> = atime_tree
+                    let files_to_remove: Vec<String> = db
+                        .atime_tree
                         .range(..time.to_be_bytes())
-                        .map(|e| {
+                        .filter_map(|e| {
                             let e = e.unwrap();
                             let key = std::str::from_utf8(e.1.as_ref()).unwrap();
-                            let _tx_result: TransactionResult<_, ()> =
-                                (&atime_tree, &metadata_tree).transaction(
-                                    |(atime_tree, metadata_tree)| {
-                                        atime_tree.remove(&e.0).unwrap();
-                                        metadata_tree.remove(&e.1).unwrap();
-                                        Ok(())
-                                    },
-                                );
-                            key.to_string()
+                            // skip if the entry is renewed meanwhile
+                            db.remove_ttl_metadata(key, Some(&e.0))
+                                .then(|| key.to_string())
                         })
                         .collect();
                     for key in files_to_remove {
@@ -895,14 +2713,20 @@ pub struct CacheEntry<Metadata, Key, Value> {
 pub struct LruCacheMetadata {
     pub size: CacheSizeType,
     pub atime: i64, // last access timestamp
+    pub headers: ResponseHeaders,
 }
 
 impl CacheEntry<LruCacheMetadata, String, ()> {
-    pub fn new(path: &str, size: u64) -> CacheEntry<LruCacheMetadata, String, ()> {
+    pub fn new(
+        path: &str,
+        size: u64,
+        headers: ResponseHeaders,
+    ) -> CacheEntry<LruCacheMetadata, String, ()> {
         CacheEntry {
             metadata: LruCacheMetadata {
                 size,
                 atime: util::now(),
+                headers,
             },
             key: String::from(path),
             value: (),
@@ -917,18 +2741,48 @@ impl CacheEntry<LruCacheMetadata, String, ()> {
             ("path", self.key.clone()),
             ("size", self.metadata.size.to_string()),
             ("atime", self.metadata.atime.to_string()),
+            ("headers", self.metadata.headers.encode()),
         ]
     }
 }
 
+#[allow(dead_code)]
 pub struct NoCache {}
 
 #[async_trait]
 impl Cache for NoCache {
-    async fn put(&mut self, _key: &str, _entry: CacheData) {}
-    async fn get(&self, _key: &str) -> Option<CacheData> {
+    async fn put(
+        &mut self,
+        _key: &str,
+        _entry: CacheData,
+        _headers: ResponseHeaders,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn get(&self, _key: &str) -> Option<(CacheData, ResponseHeaders)> {
+        None
+    }
+    async fn peek(&self, _key: &str) -> Option<(CacheData, ResponseHeaders)> {
+        None
+    }
+    async fn contains(&self, _key: &str) -> bool {
+        false
+    }
+    async fn keys(&self, _cursor: Option<&str>, _limit: usize) -> KeyPage {
+        KeyPage::default()
+    }
+    async fn metadata(&self, _key: &str) -> Option<EntryMetadata> {
         None
     }
+    async fn remove(&mut self, _key: &str) -> bool {
+        false
+    }
+    async fn stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+    async fn reconcile(&mut self, _objects: Vec<StoredObject>) -> ReconcileReport {
+        ReconcileReport::default()
+    }
 }
 
 #[cfg(test)]
@@ -945,6 +2799,7 @@ mod tests {
     use tokio::sync::RwLock;
 
     impl CacheData {
+        #[allow(clippy::wrong_self_convention)]
         pub async fn to_vec(self) -> Vec<u8> {
             match self {
                 CacheData::TextData(text) => text.into_bytes(),
@@ -959,6 +2814,7 @@ mod tests {
                     }
                     v
                 }
+                data @ CacheData::File(..) => data.into_vec_u8().await,
             }
         }
     }
@@ -1022,6 +2878,19 @@ mod tests {
         };
     }
 
+    macro_rules! new_bounded_redis_cache {
+        ($dir: expr, $size: expr, $redis_client: expr, $id: expr, $eviction: expr) => {
+            LruCache::new(
+                $size,
+                Arc::new(RedisMetadataDb::new_bounded($redis_client, $id, $eviction).unwrap()),
+                Arc::new(Storage::FileSystem {
+                    root_dir: $dir.to_string(),
+                }),
+                $id,
+            )
+        };
+    }
+
     macro_rules! new_lru_sled_cache {
         ($dir: expr, $size: expr, $id: expr) => {
             LruCache::new(
@@ -1035,10 +2904,30 @@ mod tests {
         };
     }
 
+    macro_rules! new_bounded_sled_cache {
+        ($dir: expr, $size: expr, $id: expr, $eviction: expr) => {
+            LruCache::new(
+                $size,
+                Arc::new(SledMetadataDb::new_bounded(
+                    &format!("{}/sled", $dir),
+                    $id,
+                    $eviction,
+                )),
+                Arc::new(Storage::FileSystem {
+                    root_dir: $dir.to_string(),
+                }),
+                $id,
+            )
+        };
+    }
+
     macro_rules! new_ttl_redis_cache {
         ($dir: expr, $ttl: expr, $redis_client:expr, $id: expr) => {
             TtlCache::new(
                 $ttl,
+                StaleWindows::default(),
+                None,
+                None,
                 Arc::new(RedisMetadataDb::new($redis_client, $id)),
                 Arc::new(Storage::FileSystem {
                     root_dir: $dir.to_string(),
@@ -1049,8 +2938,14 @@ mod tests {
 
     macro_rules! new_ttl_sled_cache {
         ($dir: expr, $ttl: expr, $id: expr, $interval:expr) => {
+            new_ttl_sled_cache!($dir, $ttl, $id, $interval, StaleWindows::default())
+        };
+        ($dir: expr, $ttl: expr, $id: expr, $interval:expr, $stale_windows: expr) => {
             TtlCache::new(
                 $ttl,
+                $stale_windows,
+                None,
+                None,
                 Arc::new(SledMetadataDb::new_ttl($dir, $id, $interval)),
                 Arc::new(Storage::FileSystem {
                     root_dir: $dir.to_string(),
@@ -1061,13 +2956,19 @@ mod tests {
 
     macro_rules! cache_put {
         ($cache: ident, $k: expr, $v: expr) => {
-            $cache.put($k, $v).await;
+            $cache
+                .put($k, $v, ResponseHeaders::default())
+                .await
+                .unwrap();
+        };
+        ($cache: ident, $k: expr, $v: expr, $headers: expr) => {
+            $cache.put($k, $v, $headers).await.unwrap();
         };
     }
 
     macro_rules! cache_get {
         ($cache: ident, $k: expr) => {
-            $cache.get($k).await
+            $cache.get($k).await.map(|(data, _)| data)
         };
     }
 
@@ -1230,6 +3131,172 @@ mod tests {
         assert_eq!(lru_cache.get_total_size(), 2);
     }
 
+    /// Remove the entries left by previous runs in redis
+    async fn clear_cache(cache: &mut LruCache) {
+        for key in cache.all_keys().await {
+            cache.remove(&key).await;
+        }
+    }
+
+    async fn lfu_cache_keep_frequent_tester(mut cache: LruCache) {
+        cache_put!(cache, "a", vec![1].into());
+        assert!(cache_get!(cache, "a").is_some());
+        assert!(cache_get!(cache, "a").is_some());
+        cache_put!(cache, "b", vec![2].into());
+        cache_put!(cache, "c", vec![3].into());
+        // a scan of new keys evicts the ones used once, in order
+        cache_put!(cache, "d", vec![4].into());
+        assert!(cache_get!(cache, "b").is_none());
+        cache_put!(cache, "e", vec![5].into());
+        assert!(cache_get!(cache, "c").is_none());
+        assert_eq!(cache_get!(cache, "a").unwrap().to_vec().await, vec![1]);
+        assert_eq!(cache.get_total_size(), 3);
+        // with aging, a key used often long ago is evicted at last
+        for i in 0..10 {
+            cache_put!(cache, &format!("scan{}", i), vec![0].into());
+        }
+        assert!(cache_get!(cache, "a").is_none());
+        assert_eq!(cache.get_total_size(), 3);
+    }
+
+    #[tokio::test]
+    async fn lfu_redis_cache_keep_frequent() {
+        let dir = format!("{}/lfu_keep_frequent_redis", TEST_CACHE_DIR);
+        let mut cache = new_bounded_redis_cache!(
+            &dir,
+            3,
+            new_redis_client(),
+            "lfu_keep_frequent",
+            Eviction::Lfu
+        );
+        clear_cache(&mut cache).await;
+        lfu_cache_keep_frequent_tester(cache).await;
+    }
+
+    #[tokio::test]
+    async fn lfu_sled_cache_keep_frequent() {
+        let dir = format!("{}/lfu_keep_frequent", TEST_CACHE_DIR);
+        let cache = new_bounded_sled_cache!(&dir, 3, "lfu_keep_frequent", Eviction::Lfu);
+        lfu_cache_keep_frequent_tester(cache).await;
+    }
+
+    async fn sieve_cache_keep_visited_tester(mut cache: LruCache) {
+        cache_put!(cache, "a", vec![1].into());
+        cache_put!(cache, "b", vec![2].into());
+        cache_put!(cache, "c", vec![3].into());
+        assert!(cache_get!(cache, "a").is_some());
+        // the hand passes the visited a and evicts b, then c
+        cache_put!(cache, "d", vec![4].into());
+        assert!(cache_get!(cache, "b").is_none());
+        cache_put!(cache, "e", vec![5].into());
+        assert!(cache_get!(cache, "c").is_none());
+        assert_eq!(cache_get!(cache, "a").unwrap().to_vec().await, vec![1]);
+        assert_eq!(cache.get_total_size(), 3);
+        // the hand continues after c, where d is not visited
+        cache_put!(cache, "f", vec![6].into());
+        assert!(cache_get!(cache, "d").is_none());
+        assert!(cache.remove("a").await);
+        assert_eq!(cache.get_total_size(), 2);
+    }
+
+    #[tokio::test]
+    async fn sieve_redis_cache_keep_visited() {
+        let dir = format!("{}/sieve_keep_visited_redis", TEST_CACHE_DIR);
+        let mut cache = new_bounded_redis_cache!(
+            &dir,
+            3,
+            new_redis_client(),
+            "sieve_keep_visited",
+            Eviction::Sieve
+        );
+        clear_cache(&mut cache).await;
+        sieve_cache_keep_visited_tester(cache).await;
+    }
+
+    #[tokio::test]
+    async fn sieve_sled_cache_keep_visited() {
+        let dir = format!("{}/sieve_keep_visited", TEST_CACHE_DIR);
+        let cache = new_bounded_sled_cache!(&dir, 3, "sieve_keep_visited", Eviction::Sieve);
+        sieve_cache_keep_visited_tester(cache).await;
+    }
+
+    #[test]
+    fn redis_hit_keeps_evicted_entry_out() {
+        for eviction in [Eviction::Lfu, Eviction::Sieve] {
+            let id = format!("hit_evicted_{:?}", eviction);
+            let db = RedisMetadataDb::new_bounded(new_redis_client(), &id, eviction).unwrap();
+            db.set_lru_entry("a", 1, &ResponseHeaders::default());
+            let redis_key = db.to_prefixed_key("a");
+            // the entry is evicted between the read and the update of a hit
+            assert!(db.remove_lru_entry("a"));
+            let mut con = models::get_sync_con(&db.redis_client).unwrap();
+            match eviction {
+                Eviction::Lfu => models::hit_lfu_cache_entry(
+                    &mut con,
+                    &redis_key,
+                    util::now(),
+                    &db.entries_zlist_key(),
+                    &db.lfu_age_key(),
+                ),
+                _ => models::hit_sieve_cache_entry(&mut con, &redis_key),
+            }
+            .unwrap();
+            assert!(!con.exists::<_, bool>(&redis_key).unwrap());
+            assert_eq!(db.get_lru_entry_count(), 0);
+            assert_eq!(db.get_total_size(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn switched_sled_eviction_respects_limit() {
+        for eviction in [Eviction::Lfu, Eviction::Sieve] {
+            let dir = format!("{}/switch_to_{:?}", TEST_CACHE_DIR, eviction);
+            let _ = fs::remove_dir_all(&dir);
+            let mut cache = new_lru_sled_cache!(&dir, 3, "switch_eviction");
+            cache_put!(cache, "a", vec![1].into());
+            cache_put!(cache, "b", vec![2].into());
+            cache_put!(cache, "c", vec![3].into());
+            drop(cache);
+            // entries set under LRU are indexed in their order
+            let mut cache = new_bounded_sled_cache!(&dir, 3, "switch_eviction", eviction);
+            cache_put!(cache, "d", vec![4].into());
+            assert!(cache_get!(cache, "a").is_none());
+            assert_eq!(cache.get_total_size(), 3);
+            drop(cache);
+            // and back, where the evicted a is no longer indexed
+            let mut cache = new_lru_sled_cache!(&dir, 3, "switch_eviction");
+            cache_put!(cache, "e", vec![5].into());
+            assert!(cache_get!(cache, "b").is_none());
+            assert_eq!(cache.stats().await.entries, 3);
+            assert_eq!(cache.get_total_size(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn switched_redis_eviction_respects_limit() {
+        for eviction in [Eviction::Lfu, Eviction::Sieve] {
+            let dir = format!("{}/switch_to_{:?}", TEST_CACHE_DIR, eviction);
+            let id = format!("switch_to_{:?}", eviction);
+            let mut cache =
+                new_bounded_redis_cache!(&dir, 3, new_redis_client(), &id, Eviction::Lru);
+            clear_cache(&mut cache).await;
+            cache_put!(cache, "a", vec![1].into());
+            cache_put!(cache, "b", vec![2].into());
+            cache_put!(cache, "c", vec![3].into());
+            // entries scored by atime are rescored in their order
+            let mut cache = new_bounded_redis_cache!(&dir, 3, new_redis_client(), &id, eviction);
+            cache_put!(cache, "d", vec![4].into());
+            assert!(cache_get!(cache, "a").is_none());
+            assert_eq!(cache.get_total_size(), 3);
+            // and back
+            let mut cache =
+                new_bounded_redis_cache!(&dir, 3, new_redis_client(), &id, Eviction::Lru);
+            cache_put!(cache, "e", vec![5].into());
+            assert_eq!(cache.stats().await.entries, 3);
+            assert_eq!(cache.get_total_size(), 3);
+        }
+    }
+
     async fn lru_cache_isolation_tester(mut lru_cache_1: LruCache, mut lru_cache_2: LruCache) {
         cache_put!(lru_cache_1, "1", vec![1].into());
         cache_put!(lru_cache_2, "2", vec![2].into());
@@ -1279,6 +3346,118 @@ mod tests {
         lru_cache_isolation_tester(cache1, cache2).await;
     }
 
+    #[tokio::test]
+    async fn lru_sled_cache_remove() {
+        let dir = format!("{}/sled/{}", TEST_CACHE_DIR, "remove");
+        let mut lru_cache = new_lru_sled_cache!(&dir, 16, "cache_remove");
+        cache_put!(lru_cache, "a", vec![1, 2].into());
+        cache_put!(lru_cache, "b", vec![3].into());
+        assert_eq!(lru_cache.all_keys().await, vec!["a", "b"]);
+        let metadata = lru_cache.metadata("a").await.unwrap();
+        assert_eq!(metadata.size, Some(2));
+        assert!(metadata.atime.is_some());
+        assert_eq!(metadata.expire_time, None);
+        assert!(lru_cache.remove("a").await);
+        assert!(!lru_cache.remove("a").await);
+        assert!(lru_cache.metadata("a").await.is_none());
+        assert!(file_not_exist(&format!("{}/a", dir)));
+        assert_eq!(lru_cache.all_keys().await, vec!["b"]);
+        assert_eq!(lru_cache.get_total_size(), 1);
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_peek_and_stats() {
+        let dir = format!("{}/sled/{}", TEST_CACHE_DIR, "peek");
+        let mut lru_cache = new_lru_sled_cache!(&dir, 16, "cache_peek");
+        cache_put!(lru_cache, "a", vec![1, 2].into());
+        cache_put!(lru_cache, "b", vec![3].into());
+        cache_put!(lru_cache, "c", vec![4, 5, 6].into());
+        let atime = lru_cache.metadata_db.peek_lru_entry("a").unwrap().atime;
+        util::sleep_ms(1000);
+        // peek does not update atime
+        assert!(lru_cache.contains("a").await);
+        assert_eq!(
+            lru_cache.peek("a").await.unwrap().0.to_vec().await,
+            vec![1, 2]
+        );
+        assert_eq!(
+            lru_cache.metadata_db.peek_lru_entry("a").unwrap().atime,
+            atime
+        );
+        assert!(!lru_cache.contains("d").await);
+        assert!(lru_cache.peek("d").await.is_none());
+        // keys are paginated
+        let page = lru_cache.keys(None, 2).await;
+        assert_eq!(page.keys, vec!["a", "b"]);
+        let page = lru_cache.keys(page.next.as_deref(), 2).await;
+        assert_eq!(page.keys, vec!["c"]);
+        assert_eq!(page.next, None);
+        assert_eq!(
+            lru_cache.stats().await,
+            CacheStats {
+                entries: 3,
+                bytes: 6
+            }
+        );
+    }
+
+    /// List the objects of a test cache, whose sled database is under its root.
+    async fn list_objects(dir: &str) -> Vec<StoredObject> {
+        Storage::FileSystem {
+            root_dir: dir.to_string(),
+        }
+        .list(&[format!("{}/sled", dir).into()])
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lru_sled_cache_reconcile() {
+        let dir = format!("{}/sled/{}", TEST_CACHE_DIR, "reconcile");
+        let _ = fs::remove_dir_all(&dir);
+        let mut lru_cache = new_lru_sled_cache!(&dir, 8, "cache_reconcile");
+        cache_put!(lru_cache, "a", vec![1, 2].into());
+        cache_put!(lru_cache, "b", vec![3].into());
+        cache_put!(lru_cache, "c", vec![4].into());
+        // missing
+        fs::remove_file(format!("{}/a", dir)).unwrap();
+        // incomplete
+        fs::write(format!("{}/b", dir), [3, 3]).unwrap();
+        // orphans
+        fs::write(format!("{}/d", dir), [5, 6, 7]).unwrap();
+        fs::write(format!("{}/e", dir), [0; 16]).unwrap();
+        let report = lru_cache.reconcile(list_objects(&dir).await).await;
+        assert_eq!(
+            report,
+            ReconcileReport {
+                dropped: 1,
+                adopted: 1,
+                deleted: 2,
+                evicted: 0,
+            }
+        );
+        assert_eq!(lru_cache.all_keys().await, vec!["c", "d"]);
+        assert_eq!(lru_cache.get_total_size(), 4);
+        assert!(file_not_exist(&format!("{}/b", dir)));
+        assert!(file_not_exist(&format!("{}/e", dir)));
+    }
+
+    #[tokio::test]
+    async fn failed_put_is_reported() {
+        let dir = format!("{}/put_failure", TEST_CACHE_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        // a file where the object needs a directory
+        fs::write(format!("{}/blocker", dir), "").unwrap();
+        let mut cache = new_lru_sled_cache!(&dir, 1024, "put_failure");
+        assert!(cache
+            .put("blocker/pkg", vec![1].into(), ResponseHeaders::default())
+            .await
+            .is_err());
+        assert!(!cache.contains("blocker/pkg").await);
+        cache_put!(cache, "pkg", vec![1].into());
+        assert!(cache.contains("pkg").await);
+    }
+
     #[tokio::test]
     async fn lru_sled_cache_concurrency() {
         let cache = new_lru_sled_cache!(
@@ -1291,10 +3470,30 @@ mod tests {
         for _ in 0..256 {
             let cache = arc_cache.clone();
             threads.push(tokio::spawn(async move {
-                cache.write().await.put("k1", vec![1].into()).await;
-                cache.write().await.put("k2", vec![2].into()).await;
-                cache.write().await.put("k3", vec![3].into()).await;
-                cache.write().await.put("k4", vec![4].into()).await;
+                cache
+                    .write()
+                    .await
+                    .put("k1", vec![1].into(), ResponseHeaders::default())
+                    .await
+                    .unwrap();
+                cache
+                    .write()
+                    .await
+                    .put("k2", vec![2].into(), ResponseHeaders::default())
+                    .await
+                    .unwrap();
+                cache
+                    .write()
+                    .await
+                    .put("k3", vec![3].into(), ResponseHeaders::default())
+                    .await
+                    .unwrap();
+                cache
+                    .write()
+                    .await
+                    .put("k4", vec![4].into(), ResponseHeaders::default())
+                    .await
+                    .unwrap();
             }));
         }
         for t in threads {
@@ -1339,4 +3538,276 @@ mod tests {
         util::sleep_ms(1000);
         assert!(cache_get!(cache, "key").is_none());
     }
+
+    #[tokio::test]
+    async fn ttl_sled_cache_reconcile() {
+        let dir = format!("{}/ttl_reconcile", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let mut cache = TtlCache::new(
+            60,
+            StaleWindows::default(),
+            None,
+            None,
+            Arc::new(SledMetadataDb::new_ttl(
+                &format!("{}/sled", dir),
+                "ttl_sled_reconcile",
+                1,
+            )),
+            Arc::new(Storage::FileSystem {
+                root_dir: dir.to_string(),
+            }),
+        );
+        cache_put!(cache, "a", vec![1].into());
+        cache_put!(cache, "b", vec![2].into());
+        fs::remove_file(format!("{}/b", dir)).unwrap();
+        fs::write(format!("{}/c", dir), [3]).unwrap();
+        let old = fs::File::create(format!("{}/d", dir)).unwrap();
+        old.set_modified(time::SystemTime::now() - time::Duration::from_secs(3600))
+            .unwrap();
+        let report = cache.reconcile(list_objects(&dir).await).await;
+        assert_eq!(
+            report,
+            ReconcileReport {
+                dropped: 1,
+                adopted: 1,
+                deleted: 1,
+                evicted: 0,
+            }
+        );
+        assert_eq!(cache.all_keys().await, vec!["a", "c"]);
+        assert!(cache.contains("c").await);
+        assert!(file_not_exist(&format!("{}/d", dir)));
+        assert_eq!(cache.stats().await.bytes, 2);
+    }
+
+    #[test]
+    fn ttl_sled_store_accounting() {
+        let dir = format!("{}/ttl_accounting", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let db = SledMetadataDb::new_ttl(&format!("{}/sled", dir), "ttl_accounting", 60);
+        let headers = ResponseHeaders::default();
+        db.set_ttl_entry("fresh1", 2, &headers, 60, 60);
+        db.set_ttl_entry("stale", 1, &headers, 0, 60);
+        db.set_ttl_entry("fresh2", 1, &headers, 60, 60);
+        assert_eq!(db.get_ttl_total_size(), 4);
+        // renewal keeps the size, and setting again replaces it
+        db.renew_ttl_entry("fresh2", &headers, 60, 60);
+        assert_eq!(db.get_ttl_total_size(), 4);
+        assert_eq!(db.get_ttl_entry_size("fresh2"), Some(1));
+        db.set_ttl_entry("fresh2", 3, &headers, 60, 60);
+        assert_eq!(db.get_ttl_total_size(), 6);
+        // the expired entry is evicted first, and then the least recently used
+        db.touch_ttl_entry("fresh1");
+        assert_eq!(db.evict_ttl(1, 6), vec!["stale"]);
+        assert_eq!(db.evict_ttl(1, 5), vec!["fresh2"]);
+        assert_eq!(db.get_ttl_total_size(), 2);
+        db.set_ttl_entry_size("fresh1", 1);
+        assert_eq!(db.get_ttl_total_size(), 1);
+        assert!(db.remove_ttl_entry("fresh1"));
+        assert!(!db.remove_ttl_entry("fresh1"));
+        assert_eq!(db.get_ttl_total_size(), 0);
+        assert!(db.evict_ttl(1, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn http_sled_cache_freshness() {
+        let dir = format!("{}/http_freshness", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let policy: crate::settings::Policy = serde_json::from_value(serde_json::json!({
+            "name": "http_freshness",
+            "type": "HTTP",
+            "metadata_db": "sled",
+            "storage": "local",
+            "timeout": 60,
+            "max_ttl": 600,
+        }))
+        .unwrap();
+        let mut cache = TtlCache::new(
+            60,
+            StaleWindows::default(),
+            None,
+            Some(HttpFreshness::new(&policy)),
+            Arc::new(SledMetadataDb::new_ttl(
+                &format!("{}/sled", dir),
+                "http_freshness",
+                60,
+            )),
+            Arc::new(Storage::FileSystem {
+                root_dir: dir.to_string(),
+            }),
+        );
+        let headers = |s: &str| ResponseHeaders::decode(s);
+        cache_put!(
+            cache,
+            "immutable",
+            vec![1].into(),
+            headers("cache-control: max-age=31536000, immutable\r\n")
+        );
+        cache_put!(
+            cache,
+            "mutable",
+            vec![2].into(),
+            headers("cache-control: max-age=0\r\n")
+        );
+        cache_put!(cache, "default", vec![3].into());
+        cache_put!(
+            cache,
+            "private",
+            vec![4].into(),
+            headers("cache-control: private\r\n")
+        );
+        for (key, ttl) in [("immutable", 600), ("default", 60)] {
+            let expire_time = cache.metadata(key).await.unwrap().expire_time.unwrap();
+            assert!((ttl - 1..=ttl).contains(&(expire_time - util::now())));
+        }
+        assert!(!cache.contains("mutable").await);
+        assert!(cache.metadata("private").await.is_none());
+        assert!(file_not_exist(&format!("{}/private", dir)));
+        // the entry is removed if upstream forbids storing it on revalidation
+        cache
+            .refresh("immutable", headers("cache-control: no-store\r\n"))
+            .await;
+        assert!(cache.metadata("immutable").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ttl_lru_sled_cache_evict() {
+        let dir = format!("{}/ttl_lru_evict", TEST_CACHE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let mut cache = TtlCache::new(
+            60,
+            StaleWindows::default(),
+            Some(3),
+            None,
+            Arc::new(SledMetadataDb::new_ttl(
+                &format!("{}/sled", dir),
+                "ttl_lru_evict",
+                60,
+            )),
+            Arc::new(Storage::FileSystem {
+                root_dir: dir.to_string(),
+            }),
+        );
+        cache_put!(cache, "a", vec![1].into());
+        cache_put!(cache, "b", vec![2].into());
+        cache_put!(cache, "c", vec![3].into());
+        assert!(cache_get!(cache, "a").is_some());
+        cache_put!(cache, "d", vec![4].into());
+        assert!(cache_get!(cache, "b").is_none());
+        assert!(file_not_exist(&format!("{}/b", dir)));
+        assert_eq!(cache.stats().await.bytes, 3);
+        // too large to be cached
+        cache_put!(cache, "e", vec![5; 4].into());
+        assert!(cache_get!(cache, "e").is_none());
+        assert_eq!(cache.all_keys().await, vec!["a", "c", "d"]);
+        // replacing an entry only reserves its growth
+        cache_put!(cache, "d", vec![4].into());
+        assert_eq!(cache.all_keys().await, vec!["a", "c", "d"]);
+        cache_put!(cache, "d", vec![4, 4].into());
+        assert_eq!(cache.all_keys().await, vec!["a", "d"]);
+        assert_eq!(cache.stats().await.bytes, 3);
+    }
+
+    #[tokio::test]
+    async fn ttl_sled_cache_contains_and_stats() {
+        let mut cache = new_ttl_sled_cache!(
+            &format!("{}/sled_stats", TEST_CACHE_DIR),
+            1,
+            "ttl_sled_stats",
+            1,
+            StaleWindows {
+                if_error: 60,
+                ..Default::default()
+            }
+        );
+        cache_put!(cache, "a", vec![1, 2].into());
+        cache_put!(cache, "b", vec![3].into());
+        assert!(cache.contains("a").await);
+        assert_eq!(cache.all_keys().await, vec!["a", "b"]);
+        util::sleep_ms(1000);
+        // expired entries are retained, but not contained
+        assert!(!cache.contains("a").await);
+        assert!(cache.peek("a").await.is_none());
+        assert_eq!(
+            cache.stats().await,
+            CacheStats {
+                entries: 2,
+                bytes: 3
+            }
+        );
+        assert!(cache.remove("a").await);
+        assert_eq!(
+            cache.stats().await,
+            CacheStats {
+                entries: 1,
+                bytes: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn ttl_sled_cache_revalidate() {
+        setup();
+        let mut cache = new_ttl_sled_cache!(
+            &format!("{}/sled_revalidate", TEST_CACHE_DIR),
+            1,
+            "ttl_sled_revalidate",
+            1,
+            StaleWindows {
+                revalidate: 60,
+                ..Default::default()
+            }
+        );
+        let headers = ResponseHeaders::decode("etag: \"v1\"\r\n");
+        cache_put!(cache, "key", vec![1].into(), headers.clone());
+        cache_put!(cache, "no_validator", vec![2].into());
+        assert!(cache.get_stale("key").await.is_none());
+        util::sleep_ms(1500);
+        // expired, but retained for revalidation
+        assert!(cache_get!(cache, "key").is_none());
+        assert!(cache.get_stale("no_validator").await.is_none());
+        let stale = cache.get_stale("key").await.unwrap();
+        assert_eq!(stale.data.to_vec().await, vec![1]);
+        assert_eq!(stale.headers, headers);
+        assert!(!stale.while_revalidate && !stale.if_error);
+        let new_headers = ResponseHeaders::decode("etag: \"v2\"\r\n");
+        cache.refresh("key", new_headers.clone()).await;
+        let (data, fresh_headers) = cache.get("key").await.unwrap();
+        assert_eq!(data.to_vec().await, vec![1]);
+        assert_eq!(fresh_headers, new_headers);
+    }
+
+    #[test]
+    fn response_headers_update() {
+        let mut headers = ResponseHeaders::decode("content-type: text/plain\r\nETag: \"v1\"\r\n");
+        headers.update(ResponseHeaders::decode("etag: \"v2\"\r\n"));
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("etag"), Some("\"v2\""));
+        assert_eq!(headers.iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn ttl_sled_cache_stale_windows() {
+        setup();
+        let mut cache = new_ttl_sled_cache!(
+            &format!("{}/sled_stale", TEST_CACHE_DIR),
+            1,
+            "ttl_sled_stale",
+            1,
+            StaleWindows {
+                while_revalidate: 1,
+                if_error: 60,
+                ..Default::default()
+            }
+        );
+        cache_put!(cache, "key", vec![1].into());
+        util::sleep_ms(1500);
+        assert!(cache_get!(cache, "key").is_none());
+        let stale = cache.get_stale("key").await.unwrap();
+        assert!(stale.while_revalidate && stale.if_error);
+        util::sleep_ms(1000);
+        let stale = cache.get_stale("key").await.unwrap();
+        assert!(!stale.while_revalidate && stale.if_error);
+        assert_eq!(stale.data.to_vec().await, vec![1]);
+    }
 }
@@ -0,0 +1,244 @@
+use crate::cache::ResponseHeaders;
+
+use chrono::{DateTime, FixedOffset};
+use warp::http::header::{
+    HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use warp::http::{Response, StatusCode};
+
+/// Parse an HTTP-date. Only the preferred IMF-fixdate format is supported,
+/// e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_http_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(s.trim()).ok()
+}
+
+/// Split a list of entity tags, e.g. `"a", W/"b"`.
+/// Parsing stops at the first malformed tag.
+fn entity_tags(s: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        let opaque = rest.strip_prefix("W/").unwrap_or(rest);
+        let prefix_len = rest.len() - opaque.len();
+        if !opaque.starts_with('"') {
+            break;
+        }
+        match opaque[1..].find('"') {
+            Some(end) => {
+                let len = prefix_len + end + 2;
+                tags.push(&rest[..len]);
+                rest = &rest[len..];
+            }
+            None => break,
+        }
+    }
+    tags
+}
+
+fn is_weak(tag: &str) -> bool {
+    tag.starts_with("W/")
+}
+
+/// Make an entity tag weak, e.g. of a body rewritten from the tagged one.
+pub fn weak_etag(tag: &str) -> String {
+    let tag = tag.trim();
+    if is_weak(tag) {
+        tag.to_string()
+    } else {
+        format!("W/{}", tag)
+    }
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    !is_weak(a) && !is_weak(b) && a == b
+}
+
+/// Evaluate `If-None-Match` and `If-Modified-Since` against the stored headers
+/// of a representation, as described in RFC 7232.
+/// Returns `true` if the client should be answered with `304 Not Modified`.
+pub fn is_not_modified(
+    headers: &ResponseHeaders,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        if if_none_match.trim() == "*" {
+            return true;
+        }
+        return match headers.get("etag") {
+            Some(etag) => entity_tags(if_none_match)
+                .iter()
+                .any(|tag| weak_eq(tag, etag.trim())),
+            None => false,
+        };
+    }
+    match (
+        if_modified_since.and_then(parse_http_date),
+        headers.get("last-modified").and_then(parse_http_date),
+    ) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// Evaluate `If-Range` against the stored headers of a representation.
+/// Returns `true` if the `Range` header should be honored.
+pub fn if_range_matches(headers: &ResponseHeaders, if_range: &str) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || is_weak(if_range) {
+        return headers
+            .get("etag")
+            .is_some_and(|etag| strong_eq(if_range, etag.trim()));
+    }
+    match (
+        parse_http_date(if_range),
+        headers.get("last-modified").and_then(parse_http_date),
+    ) {
+        (Some(date), Some(last_modified)) => date == last_modified,
+        _ => false,
+    }
+}
+
+/// Create the headers of a conditional request that revalidates a stored
+/// representation with upstream.
+pub fn revalidation_headers(headers: &ResponseHeaders) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    if let Some(value) = headers
+        .get("etag")
+        .and_then(|x| HeaderValue::from_str(x).ok())
+    {
+        map.insert(IF_NONE_MATCH, value);
+    }
+    if let Some(value) = headers
+        .get("last-modified")
+        .and_then(|x| HeaderValue::from_str(x).ok())
+    {
+        map.insert(IF_MODIFIED_SINCE, value);
+    }
+    map
+}
+
+/// Whether a stored representation can be revalidated with upstream.
+pub fn has_validator(headers: &ResponseHeaders) -> bool {
+    headers.get("etag").is_some() || headers.get("last-modified").is_some()
+}
+
+/// Create a `304 Not Modified` response with the validators of the representation.
+pub fn not_modified_response(headers: &ResponseHeaders) -> warp::reply::Response {
+    let mut resp = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(warp::hyper::Body::empty())
+        .unwrap();
+    for name in [ETAG, LAST_MODIFIED] {
+        if let Some(value) = headers
+            .get(name.as_str())
+            .and_then(|x| HeaderValue::from_str(x).ok())
+        {
+            resp.headers_mut().insert(name, value);
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers() -> ResponseHeaders {
+        ResponseHeaders::decode("etag: \"v1\"\r\nlast-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n")
+    }
+
+    #[test]
+    fn parse_entity_tags() {
+        assert_eq!(
+            entity_tags("\"a\", W/\"b\",\"c,d\""),
+            vec!["\"a\"", "W/\"b\"", "\"c,d\""]
+        );
+        assert_eq!(entity_tags("\"a\", b"), vec!["\"a\""]);
+        assert!(entity_tags("").is_empty());
+    }
+
+    #[test]
+    fn if_none_match() {
+        let headers = headers();
+        assert!(is_not_modified(&headers, Some("\"v0\", \"v1\""), None));
+        assert!(is_not_modified(&headers, Some("W/\"v1\""), None));
+        assert!(is_not_modified(&headers, Some("*"), None));
+        assert!(!is_not_modified(&headers, Some("\"v2\""), None));
+        // If-Modified-Since is ignored if If-None-Match is present
+        assert!(!is_not_modified(
+            &headers,
+            Some("\"v2\""),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        ));
+        assert!(!is_not_modified(
+            &ResponseHeaders::default(),
+            Some("\"v1\""),
+            None
+        ));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let headers = headers();
+        assert!(is_not_modified(
+            &headers,
+            None,
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        ));
+        assert!(is_not_modified(
+            &headers,
+            None,
+            Some("Mon, 07 Nov 1994 00:00:00 GMT")
+        ));
+        assert!(!is_not_modified(
+            &headers,
+            None,
+            Some("Sat, 05 Nov 1994 00:00:00 GMT")
+        ));
+        assert!(!is_not_modified(&headers, None, Some("yesterday")));
+        assert!(!is_not_modified(&headers, None, None));
+    }
+
+    #[test]
+    fn if_range() {
+        let headers = headers();
+        assert!(if_range_matches(&headers, "\"v1\""));
+        assert!(!if_range_matches(&headers, "W/\"v1\""));
+        assert!(!if_range_matches(&headers, "\"v2\""));
+        assert!(if_range_matches(&headers, "Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!if_range_matches(&headers, "Mon, 07 Nov 1994 00:00:00 GMT"));
+        assert!(!if_range_matches(&ResponseHeaders::default(), "\"v1\""));
+    }
+
+    #[test]
+    fn rewritten_validators() {
+        let headers = headers().weaken_validators();
+        assert_eq!(headers.get("etag"), Some("W/\"v1\""));
+        assert_eq!(headers.get("last-modified"), None);
+        // the rewritten bytes are neither served as a range of the upstream ones...
+        assert!(!if_range_matches(&headers, "\"v1\""));
+        assert!(!if_range_matches(&headers, "Sun, 06 Nov 1994 08:49:37 GMT"));
+        // ...nor assumed unchanged by date
+        assert!(!is_not_modified(
+            &headers,
+            None,
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        ));
+        assert!(is_not_modified(&headers, Some("\"v1\""), None));
+        assert_eq!(weak_etag("W/\"v1\""), "W/\"v1\"");
+    }
+
+    #[test]
+    fn conditional_request_headers() {
+        let map = revalidation_headers(&headers());
+        assert_eq!(map[IF_NONE_MATCH], "\"v1\"");
+        assert_eq!(map[IF_MODIFIED_SINCE], "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(revalidation_headers(&ResponseHeaders::default()).is_empty());
+    }
+}
@@ -20,7 +20,9 @@ pub enum Error {
     #[error("outbound request failed: {0}")]
     RequestError(reqwest::Error),
     #[error("upstream request is not successful: {0:?}")]
-    UpstreamRequestError(reqwest::Response),
+    UpstreamRequestError(Box<reqwest::Response>),
+    #[error("upstream responded {0} recently")]
+    NegativeCacheHit(warp::http::StatusCode),
     #[error("{0}")]
     ConfigDeserializeError(config::ConfigError),
     #[error("invalid configuration: {0}")]
@@ -0,0 +1,178 @@
+//! Freshness of cached responses derived from upstream headers, for `HTTP`
+//! policies.
+//!
+//! The freshness lifetime of a response is taken from `Cache-Control`, `Expires`
+//! or a heuristic based on `Last-Modified`, as a shared cache does (RFC 9111),
+//! and clamped by the policy.
+use crate::cache::ResponseHeaders;
+use crate::conditional;
+use crate::settings;
+
+/// Fraction of the time since the last modification, which a response without
+/// explicit freshness is assumed to stay fresh
+const HEURISTIC_FRACTION: f64 = 0.1;
+
+/// Maximum TTL of a policy by default, one year
+const DEFAULT_MAX_TTL: u64 = 365 * 24 * 3600;
+
+#[derive(Clone, Debug)]
+pub struct HttpFreshness {
+    /// TTL of responses without any freshness information
+    default_ttl: u64,
+    min_ttl: u64,
+    max_ttl: u64,
+}
+
+impl HttpFreshness {
+    pub fn new(policy: &settings::Policy) -> Self {
+        Self {
+            default_ttl: policy.timeout.unwrap_or(0),
+            min_ttl: policy.min_ttl.unwrap_or(0),
+            max_ttl: policy.max_ttl.unwrap_or(DEFAULT_MAX_TTL),
+        }
+    }
+
+    /// The TTL in seconds of a response with `headers`, which is received now.
+    /// Returns `None` if the response must not be stored.
+    pub fn ttl(&self, headers: &ResponseHeaders) -> Option<u64> {
+        let directives = headers
+            .get("cache-control")
+            .map(cache_directives)
+            .unwrap_or_default();
+        let directive = |name: &str| {
+            directives
+                .iter()
+                .find(|(directive, _)| directive == name)
+                .map(|(_, value)| value.as_deref())
+        };
+        if directive("no-store").is_some() || directive("private").is_some() {
+            return None;
+        }
+        let seconds = |name: &str| directive(name).flatten()?.parse::<u64>().ok();
+        let now = chrono::Utc::now().timestamp();
+        let date = headers
+            .get("date")
+            .and_then(conditional::parse_http_date)
+            .map_or(now, |date| date.timestamp());
+        if directive("no-cache").is_some() {
+            // stored, but revalidated on every request, regardless of `min_ttl`
+            return Some(0);
+        }
+        let lifetime = if let Some(max_age) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+            Some(max_age)
+        } else if let Some(expires) = headers.get("expires") {
+            // an invalid date, e.g. `0`, means already expired
+            Some(
+                conditional::parse_http_date(expires)
+                    .map_or(0, |expires| (expires.timestamp() - date).max(0) as u64),
+            )
+        } else {
+            headers
+                .get("last-modified")
+                .and_then(conditional::parse_http_date)
+                .map(|last_modified| {
+                    ((date - last_modified.timestamp()).max(0) as f64 * HEURISTIC_FRACTION) as u64
+                })
+        };
+        let age = headers
+            .get("age")
+            .and_then(|age| age.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let ttl = lifetime.map_or(self.default_ttl, |lifetime| lifetime.saturating_sub(age));
+        Some(ttl.clamp(self.min_ttl, self.max_ttl.max(self.min_ttl)))
+    }
+}
+
+/// Parse `Cache-Control` directives into lowercase names and unquoted values,
+/// e.g. `max-age=60, no-cache="set-cookie"`.
+fn cache_directives(s: &str) -> Vec<(String, Option<String>)> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn freshness(default_ttl: u64, min_ttl: u64, max_ttl: u64) -> HttpFreshness {
+        HttpFreshness {
+            default_ttl,
+            min_ttl,
+            max_ttl,
+        }
+    }
+
+    fn ttl(freshness: &HttpFreshness, headers: &str) -> Option<u64> {
+        freshness.ttl(&ResponseHeaders::decode(headers))
+    }
+
+    #[test]
+    fn explicit_freshness() {
+        let f = freshness(5, 0, 3600);
+        assert_eq!(ttl(&f, "cache-control: public, max-age=60\r\n"), Some(60));
+        assert_eq!(
+            ttl(&f, "cache-control: max-age=60, s-maxage=120\r\n"),
+            Some(120)
+        );
+        assert_eq!(
+            ttl(&f, "cache-control: max-age=60\r\nage: 50\r\n"),
+            Some(10)
+        );
+        assert_eq!(ttl(&f, "cache-control: no-cache, max-age=60\r\n"), Some(0));
+        assert_eq!(ttl(&f, "cache-control: No-Store\r\n"), None);
+        assert_eq!(ttl(&f, "cache-control: private, max-age=60\r\n"), None);
+        assert_eq!(
+            ttl(
+                &f,
+                "date: Sun, 06 Nov 1994 08:49:37 GMT\r\nexpires: Sun, 06 Nov 1994 08:59:37 GMT\r\n"
+            ),
+            Some(600)
+        );
+        assert_eq!(ttl(&f, "expires: 0\r\n"), Some(0));
+        // max-age takes precedence over Expires
+        assert_eq!(
+            ttl(&f, "cache-control: max-age=60\r\nexpires: 0\r\n"),
+            Some(60)
+        );
+        assert_eq!(ttl(&f, ""), Some(5));
+    }
+
+    #[test]
+    fn heuristic_freshness() {
+        let f = freshness(5, 0, 3600);
+        // a tenth of a day, which is clamped
+        assert_eq!(
+            ttl(
+                &f,
+                "date: Mon, 07 Nov 1994 08:49:37 GMT\r\nlast-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n"
+            ),
+            Some(3600)
+        );
+        assert_eq!(
+            ttl(
+                &f,
+                "date: Sun, 06 Nov 1994 09:49:37 GMT\r\nlast-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n"
+            ),
+            Some(360)
+        );
+    }
+
+    #[test]
+    fn clamp_ttl() {
+        let f = freshness(0, 60, 600);
+        assert_eq!(ttl(&f, "cache-control: max-age=0\r\n"), Some(60));
+        assert_eq!(ttl(&f, "cache-control: max-age=31536000\r\n"), Some(600));
+        assert_eq!(ttl(&f, ""), Some(60));
+        assert_eq!(ttl(&f, "cache-control: no-store\r\n"), None);
+        assert_eq!(ttl(&f, "cache-control: no-cache, max-age=60\r\n"), Some(0));
+    }
+}
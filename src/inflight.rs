@@ -0,0 +1,505 @@
+use crate::cache::ResponseHeaders;
+use crate::error::{Error, Result};
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+/// Maximum number of bytes read from the spool file at a time.
+const SPOOL_READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+const SPOOL_EXTENSION: &str = "spool";
+
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownloadState {
+    /// Waiting for the upstream response
+    Pending,
+    Downloading,
+    Done,
+    /// The download broke after it was started
+    Failed(String),
+    /// The upstream response is not usable, no data will be spooled
+    Aborted,
+}
+
+/// An item of the body of a download
+pub enum Chunk {
+    Data(Bytes),
+    /// The body starts over from its first byte, e.g. when a download without
+    /// a validator is requested again
+    Restart,
+}
+
+impl From<Bytes> for Chunk {
+    fn from(bytes: Bytes) -> Self {
+        Chunk::Data(bytes)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub state: DownloadState,
+    pub content_length: Option<u64>,
+    /// Number of bytes written to the spool file
+    pub received: u64,
+    /// Number of times the spool file was truncated to start over
+    pub restarts: u32,
+}
+
+/// An upstream download shared by all requesters of the same key.
+///
+/// The upstream body is written to a spool file by a single task. Subscribers
+/// read the bytes already received from the spool, then follow the live
+/// download until it is done. The spool file is removed when the last handle is
+/// dropped.
+pub struct InflightDownload {
+    spool_path: PathBuf,
+    progress: watch::Sender<Progress>,
+    /// Upstream response headers, set when the download is started
+    headers: OnceLock<ResponseHeaders>,
+}
+
+impl InflightDownload {
+    pub fn new(spool_path: PathBuf) -> Arc<Self> {
+        let (progress, _) = watch::channel(Progress {
+            state: DownloadState::Pending,
+            content_length: None,
+            received: 0,
+            restarts: 0,
+        });
+        Arc::new(Self {
+            spool_path,
+            progress,
+            headers: OnceLock::new(),
+        })
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.borrow().clone()
+    }
+
+    /// Give up the download before it is started. Waiting subscribers get `None`.
+    pub fn abort(&self) {
+        self.progress
+            .send_modify(|progress| progress.state = DownloadState::Aborted);
+    }
+
+    /// Spawn a task that writes `stream` to the spool file. The spool file is
+    /// truncated when the stream restarts, and subscribers which already read
+    /// some of it fail.
+    pub fn start<S, T>(
+        self: &Arc<Self>,
+        stream: S,
+        content_length: Option<u64>,
+        headers: ResponseHeaders,
+    ) where
+        S: Stream<Item = Result<T>> + Send + 'static,
+        T: Into<Chunk> + Send,
+    {
+        let _ = self.headers.set(headers);
+        let download = self.clone();
+        tokio::spawn(async move {
+            let mut stream = Box::pin(stream);
+            let mut f = match File::create(&download.spool_path).await {
+                Ok(f) => f,
+                Err(e) => {
+                    error!(
+                        "failed to create spool file {}: {}",
+                        download.spool_path.display(),
+                        e
+                    );
+                    download.fail(e.to_string());
+                    return;
+                }
+            };
+            download.progress.send_modify(|progress| {
+                progress.state = DownloadState::Downloading;
+                progress.content_length = content_length;
+            });
+            while let Some(item) = stream.next().await {
+                let bytes = match item.map(Into::into) {
+                    Ok(Chunk::Data(bytes)) => bytes,
+                    Ok(Chunk::Restart) => {
+                        if let Err(e) = truncate_spool(&mut f).await {
+                            error!(
+                                "failed to truncate spool file {}: {}",
+                                download.spool_path.display(),
+                                e
+                            );
+                            download.fail(e.to_string());
+                            return;
+                        }
+                        download.progress.send_modify(|progress| {
+                            progress.received = 0;
+                            progress.restarts += 1;
+                        });
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("in-flight download failed: {}", e);
+                        download.fail(e.to_string());
+                        return;
+                    }
+                };
+                if let Err(e) = write_spool(&mut f, &bytes).await {
+                    error!(
+                        "failed to write spool file {}: {}",
+                        download.spool_path.display(),
+                        e
+                    );
+                    download.fail(e.to_string());
+                    return;
+                }
+                download
+                    .progress
+                    .send_modify(|progress| progress.received += bytes.len() as u64);
+            }
+            let received = download.progress().received;
+            if content_length.is_some_and(|len| len != received) {
+                warn!(
+                    "in-flight download is incomplete: {} of {:?} bytes",
+                    received, content_length
+                );
+                download.fail(format!("incomplete download: {} bytes", received));
+                return;
+            }
+            download
+                .progress
+                .send_modify(|progress| progress.state = DownloadState::Done);
+        });
+    }
+
+    /// Wait until the download is over, and return its final progress.
+    pub async fn finished(&self) -> Progress {
+        let mut rx = self.progress.subscribe();
+        let progress = rx
+            .wait_for(|progress| {
+                !matches!(
+                    progress.state,
+                    DownloadState::Pending | DownloadState::Downloading
+                )
+            })
+            .await
+            .map(|progress| progress.clone());
+        // the sender lives as long as `self`
+        progress.unwrap()
+    }
+
+    fn fail(&self, reason: String) {
+        self.progress
+            .send_modify(|progress| progress.state = DownloadState::Failed(reason));
+    }
+
+    /// Wait until the upstream response is available, and return a stream of
+    /// the whole body along with its content length and the upstream headers.
+    /// Returns `None` if the download is aborted or failed before any subscriber
+    /// could attach to it.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+    ) -> Option<(
+        impl Stream<Item = Result<Bytes>> + Send + Unpin,
+        Option<u64>,
+        ResponseHeaders,
+    )> {
+        let mut rx = self.progress.subscribe();
+        let progress = rx
+            .wait_for(|progress| progress.state != DownloadState::Pending)
+            .await
+            .ok()?
+            .clone();
+        match progress.state {
+            DownloadState::Downloading | DownloadState::Done => {}
+            _ => return None,
+        }
+        let f = match File::open(&self.spool_path).await {
+            Ok(f) => f,
+            Err(e) => {
+                error!(
+                    "failed to open spool file {}: {}",
+                    self.spool_path.display(),
+                    e
+                );
+                return None;
+            }
+        };
+        let state = SpoolReader {
+            download: self.clone(),
+            rx,
+            f,
+            offset: 0,
+            restarts: progress.restarts,
+            finished: false,
+        };
+        let stream = stream::unfold(state, |mut reader| async move {
+            reader.next_chunk().await.map(|item| (item, reader))
+        });
+        let headers = self.headers.get().cloned().unwrap_or_default();
+        Some((Box::pin(stream), progress.content_length, headers))
+    }
+}
+
+impl Drop for InflightDownload {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.spool_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(
+                    "failed to remove spool file {}: {}",
+                    self.spool_path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+async fn write_spool(f: &mut File, bytes: &[u8]) -> std::io::Result<()> {
+    f.write_all(bytes).await?;
+    // make the bytes visible to readers before announcing them
+    f.flush().await
+}
+
+async fn truncate_spool(f: &mut File) -> std::io::Result<()> {
+    f.set_len(0).await?;
+    f.seek(SeekFrom::Start(0)).await?;
+    Ok(())
+}
+
+struct SpoolReader {
+    /// keeps the spool file alive
+    #[allow(dead_code)]
+    download: Arc<InflightDownload>,
+    rx: watch::Receiver<Progress>,
+    f: File,
+    offset: u64,
+    /// Restarts of the download when the reader was created
+    restarts: u32,
+    finished: bool,
+}
+
+impl SpoolReader {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            let progress = self.rx.borrow_and_update().clone();
+            if self.restarts != progress.restarts {
+                // bytes already read may not match the restarted download
+                if self.offset > 0 {
+                    self.finished = true;
+                    return Some(Err(Error::OtherError(
+                        "in-flight download restarted".to_string(),
+                    )));
+                }
+                self.restarts = progress.restarts;
+            }
+            if self.offset < progress.received {
+                let len = std::cmp::min(progress.received - self.offset, SPOOL_READ_CHUNK_SIZE);
+                let mut buf = vec![0; len as usize];
+                if let Err(e) = self.f.read_exact(&mut buf).await {
+                    self.finished = true;
+                    return Some(Err(e.into()));
+                }
+                self.offset += len;
+                return Some(Ok(buf.into()));
+            }
+            match progress.state {
+                DownloadState::Done => return None,
+                DownloadState::Failed(reason) => {
+                    self.finished = true;
+                    return Some(Err(Error::OtherError(format!(
+                        "in-flight download failed: {}",
+                        reason
+                    ))));
+                }
+                _ => {}
+            }
+            if self.rx.changed().await.is_err() {
+                self.finished = true;
+                return Some(Err(Error::OtherError(
+                    "in-flight download is gone".to_string(),
+                )));
+            }
+        }
+    }
+}
+
+/// Create a unique spool file path in `dir`, which is not shared with other
+/// processes using the same directory.
+pub fn new_spool_path(dir: &Path) -> PathBuf {
+    dir.join(format!(
+        "{}-{}.{}",
+        std::process::id(),
+        SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed),
+        SPOOL_EXTENSION
+    ))
+}
+
+/// Remove spool files left in `dir`, e.g. after a crash. Returns the number of
+/// removed files. This must not run while downloads are in flight.
+pub fn cleanup_spool_dir(dir: &Path) -> std::io::Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == SPOOL_EXTENSION) {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::SinkExt;
+
+    fn spool_dir() -> PathBuf {
+        let dir = PathBuf::from("cache/test/spool");
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn collect(stream: impl Stream<Item = Result<Bytes>> + Unpin) -> Vec<u8> {
+        stream.map(|x| x.unwrap().to_vec()).concat().await
+    }
+
+    #[test]
+    fn cleanup_leftover_spools() {
+        let dir = PathBuf::from("cache/test/spool_cleanup");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let spool = new_spool_path(&dir);
+        assert_ne!(spool, new_spool_path(&dir));
+        std::fs::write(&spool, "foo").unwrap();
+        std::fs::write(dir.join("other"), "bar").unwrap();
+        assert_eq!(cleanup_spool_dir(&dir).unwrap(), 1);
+        assert!(!spool.exists());
+        assert!(dir.join("other").exists());
+        assert_eq!(cleanup_spool_dir(&dir.join("missing")).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_whole_body() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        let chunks: Vec<Result<Bytes>> = vec![Ok("foo".into()), Ok("bar".into())];
+        let headers = ResponseHeaders::decode("etag: \"foobar\"\r\n");
+        download.start(stream::iter(chunks), Some(6), headers.clone());
+        let (first, len, first_headers) = download.subscribe().await.unwrap();
+        assert_eq!(len, Some(6));
+        assert_eq!(first_headers, headers);
+        assert_eq!(collect(first).await, b"foobar");
+        let (second, _, _) = download.subscribe().await.unwrap();
+        assert_eq!(collect(second).await, b"foobar");
+    }
+
+    #[tokio::test]
+    async fn late_subscriber_follows_live_download() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        let (mut tx, rx) = mpsc::channel::<Result<Bytes>>(1);
+        download.start(rx, None, ResponseHeaders::default());
+        tx.send(Ok("spooled ".into())).await.unwrap();
+        let (early, _, _) = download.subscribe().await.unwrap();
+        let early = tokio::spawn(collect(early));
+        download
+            .progress
+            .subscribe()
+            .wait_for(|progress| progress.received == 8)
+            .await
+            .unwrap();
+        let (late, _, _) = download.subscribe().await.unwrap();
+        let late = tokio::spawn(collect(late));
+        tx.send(Ok("live".into())).await.unwrap();
+        drop(tx);
+        assert_eq!(early.await.unwrap(), b"spooled live");
+        assert_eq!(late.await.unwrap(), b"spooled live");
+    }
+
+    #[tokio::test]
+    async fn aborted_download_has_no_subscribers() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        download.abort();
+        assert!(download.subscribe().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_download_yields_error() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        let (mut tx, rx) = mpsc::channel::<Result<Bytes>>(1);
+        download.start(rx, None, ResponseHeaders::default());
+        tx.send(Ok("partial".into())).await.unwrap();
+        let (mut stream, _, _) = download.subscribe().await.unwrap();
+        tx.send(Err(Error::OtherError("reset".into())))
+            .await
+            .unwrap();
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Bytes::from("partial")
+        );
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn restarted_download_truncates_spool() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        let (mut tx, rx) = mpsc::channel::<Result<Chunk>>(1);
+        download.start(rx, None, ResponseHeaders::default());
+        tx.send(Ok(Chunk::Data("stale".into()))).await.unwrap();
+        let (mut early, _, _) = download.subscribe().await.unwrap();
+        assert_eq!(early.next().await.unwrap().unwrap(), Bytes::from("stale"));
+        tx.send(Ok(Chunk::Restart)).await.unwrap();
+        tx.send(Ok(Chunk::Data("new".into()))).await.unwrap();
+        drop(tx);
+        assert!(matches!(
+            download.finished().await.state,
+            DownloadState::Done
+        ));
+        // the bytes already read do not belong to the new body
+        assert!(early.next().await.unwrap().is_err());
+        let (late, _, _) = download.subscribe().await.unwrap();
+        assert_eq!(collect(late).await, b"new");
+    }
+
+    #[tokio::test]
+    async fn incomplete_download_fails() {
+        let download = InflightDownload::new(new_spool_path(&spool_dir()));
+        let chunks: Vec<Result<Bytes>> = vec![Ok("partial".into())];
+        download.start(stream::iter(chunks), Some(100), ResponseHeaders::default());
+        assert!(matches!(
+            download.finished().await.state,
+            DownloadState::Failed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn spool_file_removed_on_drop() {
+        let path = new_spool_path(&spool_dir());
+        let download = InflightDownload::new(path.clone());
+        let chunks: Vec<Result<Bytes>> = vec![Ok("x".into())];
+        download.start(stream::iter(chunks), None, ResponseHeaders::default());
+        let (stream, _, _) = download.subscribe().await.unwrap();
+        assert_eq!(collect(stream).await, b"x");
+        assert!(path.exists());
+        // wait for the download task to release its handle
+        while Arc::strong_count(&download) > 1 {
+            tokio::task::yield_now().await;
+        }
+        drop(download);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,378 @@
+//! Integrity verification of artifacts against digests published by upstream.
+//!
+//! Digests are learned from index responses of rules with the `digests` option,
+//! and kept in memory by the upstream URL of each artifact. A download whose URL
+//! has a known digest is verified before it is cached.
+use crate::error::Result;
+use crate::settings::DigestSource;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use md5::Md5;
+use regex::Regex;
+use reqwest::Url;
+use sha2::{Digest as _, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::RwLock;
+
+/// Maximum number of digests kept in memory. The earliest learned ones are
+/// forgotten first.
+const MAX_DIGESTS: usize = 1 << 20;
+
+lazy_static::lazy_static! {
+    static ref DIGESTS: RwLock<DigestRegistry> = RwLock::new(DigestRegistry::default());
+    static ref HREF: Regex = Regex::new(r#"href\s*=\s*["']([^"']+)["']"#).unwrap();
+}
+
+/// A digest in lowercase hex
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Digest {
+    Sha256(String),
+    Md5(String),
+}
+
+impl Digest {
+    pub fn sha256(hex: &str) -> Option<Self> {
+        parse_hex(hex, 32).map(Digest::Sha256)
+    }
+
+    pub fn md5(hex: &str) -> Option<Self> {
+        parse_hex(hex, 16).map(Digest::Md5)
+    }
+
+    /// Create a hasher of the same algorithm.
+    fn hasher(&self) -> Hasher {
+        match self {
+            Digest::Sha256(_) => Hasher::Sha256(Sha256::new()),
+            Digest::Md5(_) => Hasher::Md5(Md5::new()),
+        }
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Digest::Sha256(hex) => write!(f, "sha256:{}", hex),
+            Digest::Md5(hex) => write!(f, "md5:{}", hex),
+        }
+    }
+}
+
+fn parse_hex(hex: &str, len: usize) -> Option<String> {
+    let hex = hex.trim();
+    if hex.len() == len * 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hex.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+        }
+    }
+
+    fn finish(self) -> Digest {
+        match self {
+            Hasher::Sha256(h) => Digest::Sha256(format!("{:x}", h.finalize())),
+            Hasher::Md5(h) => Digest::Md5(format!("{:x}", h.finalize())),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DigestRegistry {
+    map: HashMap<String, Digest>,
+    order: VecDeque<String>,
+}
+
+impl DigestRegistry {
+    fn insert(&mut self, url: String, digest: Digest) {
+        if self.map.insert(url.clone(), digest).is_none() {
+            self.order.push_back(url);
+        }
+        while self.order.len() > MAX_DIGESTS {
+            if let Some(url) = self.order.pop_front() {
+                self.map.remove(&url);
+            }
+        }
+    }
+}
+
+/// The expected digest of the artifact at `url`, if it is known.
+pub fn expected_digest(url: &str) -> Option<Digest> {
+    DIGESTS.read().unwrap().map.get(url).cloned()
+}
+
+/// Whether the response at `url` is an index of `source` that can be parsed.
+/// Compressed indexes are not supported.
+pub fn is_index(source: DigestSource, url: &str) -> bool {
+    match source {
+        DigestSource::PyPI => true,
+        DigestSource::Conda => url.ends_with("/repodata.json"),
+        DigestSource::Apt => url.ends_with("/Packages"),
+    }
+}
+
+/// Learn the digests listed in an index. Returns the number of learned digests.
+pub fn learn(source: DigestSource, index_url: &str, body: &[u8]) -> usize {
+    let digests = match source {
+        DigestSource::PyPI => pypi_digests(index_url, &String::from_utf8_lossy(body)),
+        DigestSource::Conda => conda_digests(index_url, body),
+        DigestSource::Apt => apt_digests(index_url, &String::from_utf8_lossy(body)),
+    };
+    let len = digests.len();
+    let mut registry = DIGESTS.write().unwrap();
+    for (url, digest) in digests {
+        registry.insert(url, digest);
+    }
+    len
+}
+
+/// Compute the digest of `stream` with the algorithm of `expected`.
+pub async fn digest_stream(
+    expected: &Digest,
+    mut stream: impl Stream<Item = Result<Bytes>> + Unpin,
+) -> Result<Digest> {
+    let mut hasher = expected.hasher();
+    while let Some(bytes) = stream.next().await {
+        hasher.update(&bytes?);
+    }
+    Ok(hasher.finish())
+}
+
+/// Links in a PyPI simple index page, e.g.
+/// `<a href="../../packages/ab/cd/foo-1.0.tar.gz#sha256=...">`
+fn pypi_digests(page_url: &str, page: &str) -> Vec<(String, Digest)> {
+    let base = match Url::parse(page_url) {
+        Ok(base) => base,
+        Err(_) => return Vec::new(),
+    };
+    HREF.captures_iter(page)
+        .filter_map(|cap| {
+            let href = cap[1].replace("&amp;", "&");
+            let (link, fragment) = href.split_once('#')?;
+            let digest = match fragment.split_once('=')? {
+                ("sha256", hex) => Digest::sha256(hex)?,
+                ("md5", hex) => Digest::md5(hex)?,
+                _ => return None,
+            };
+            Some((base.join(link).ok()?.to_string(), digest))
+        })
+        .collect()
+}
+
+/// The fields of a conda `repodata.json` listing digests. Other fields are
+/// skipped while parsing, instead of being kept in memory.
+#[derive(Deserialize)]
+struct RepoData {
+    #[serde(default)]
+    packages: HashMap<String, CondaPackage>,
+    #[serde(default, rename = "packages.conda")]
+    packages_conda: HashMap<String, CondaPackage>,
+}
+
+#[derive(Deserialize)]
+struct CondaPackage {
+    sha256: Option<String>,
+    md5: Option<String>,
+}
+
+/// Packages in a conda `repodata.json`, which are in the same directory
+fn conda_digests(repodata_url: &str, repodata: &[u8]) -> Vec<(String, Digest)> {
+    let base = match Url::parse(repodata_url) {
+        Ok(base) => base,
+        Err(_) => return Vec::new(),
+    };
+    let repodata: RepoData = match serde_json::from_slice(repodata) {
+        Ok(repodata) => repodata,
+        Err(e) => {
+            // e.g. compressed repodata
+            debug!("failed to parse repodata {}: {}", repodata_url, e);
+            return Vec::new();
+        }
+    };
+    let mut digests = Vec::new();
+    for (filename, package) in repodata.packages.iter().chain(&repodata.packages_conda) {
+        let digest = package
+            .sha256
+            .as_deref()
+            .and_then(Digest::sha256)
+            .or_else(|| package.md5.as_deref().and_then(Digest::md5));
+        if let (Some(digest), Ok(url)) = (digest, base.join(filename)) {
+            digests.push((url.to_string(), digest));
+        }
+    }
+    digests
+}
+
+/// Packages in an apt `Packages` file, whose `Filename`s are relative to the
+/// archive root, i.e. the parent of `dists/`
+fn apt_digests(packages_url: &str, packages: &str) -> Vec<(String, Digest)> {
+    let root = match packages_url.rfind("/dists/") {
+        Some(pos) => &packages_url[..pos + 1],
+        None => return Vec::new(),
+    };
+    packages
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let field = |name: &str| {
+                stanza.lines().find_map(|line| {
+                    let (key, value) = line.split_once(':')?;
+                    (key == name).then(|| value.trim())
+                })
+            };
+            let digest = field("SHA256")
+                .and_then(Digest::sha256)
+                .or_else(|| field("MD5sum").and_then(Digest::md5))?;
+            Some((format!("{}{}", root, field("Filename")?), digest))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHA256_EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[tokio::test]
+    async fn digest_chunks() {
+        let digest = |expected: &str, chunks: &[&'static [u8]]| {
+            let expected = if expected.len() == 64 {
+                Digest::sha256(expected).unwrap()
+            } else {
+                Digest::md5(expected).unwrap()
+            };
+            let stream = futures::stream::iter(
+                chunks
+                    .iter()
+                    .map(|chunk| Ok(Bytes::from_static(chunk)))
+                    .collect::<Vec<_>>(),
+            );
+            async move { digest_stream(&expected, stream).await.unwrap() == expected }
+        };
+        assert!(digest(SHA256_EMPTY, &[]).await);
+        assert!(
+            digest(
+                "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+                &[b"a", b"bc"]
+            )
+            .await
+        );
+        assert!(digest("900150983cd24fb0d6963f7d28e17f72", &[b"ab", b"c"]).await);
+    }
+
+    #[test]
+    fn parse_digest() {
+        assert!(Digest::sha256("abc").is_none());
+        assert!(Digest::md5("z41d8cd98f00b204e9800998ecf8427e").is_none());
+        assert_eq!(
+            Digest::md5(" D41D8CD98F00B204E9800998ECF8427E ").unwrap(),
+            Digest::Md5("d41d8cd98f00b204e9800998ecf8427e".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_pypi() {
+        let page = format!(
+            r#"<a href="https://files.pythonhosted.org/packages/a/foo-1.0.tar.gz#sha256={}">foo</a>
+            <a href='../../packages/b/foo-0.9.tar.gz#md5=d41d8cd98f00b204e9800998ecf8427e'>foo</a>
+            <a href="https://files.pythonhosted.org/packages/c/foo-0.8.tar.gz">foo</a>"#,
+            SHA256_EMPTY
+        );
+        let digests = pypi_digests("https://pypi.org/simple/foo/", &page);
+        assert_eq!(
+            digests,
+            vec![
+                (
+                    "https://files.pythonhosted.org/packages/a/foo-1.0.tar.gz".to_string(),
+                    Digest::sha256(SHA256_EMPTY).unwrap()
+                ),
+                (
+                    "https://pypi.org/packages/b/foo-0.9.tar.gz".to_string(),
+                    Digest::md5("d41d8cd98f00b204e9800998ecf8427e").unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_conda() {
+        let repodata = format!(
+            r#"{{"packages": {{"a-1.0-0.tar.bz2": {{"md5": "d41d8cd98f00b204e9800998ecf8427e", "sha256": "{}"}}}},
+            "packages.conda": {{"b-1.0-0.conda": {{"md5": "d41d8cd98f00b204e9800998ecf8427e"}}}}}}"#,
+            SHA256_EMPTY
+        );
+        let mut digests = conda_digests(
+            "https://repo.anaconda.com/pkgs/main/linux-64/repodata.json",
+            repodata.as_bytes(),
+        );
+        digests.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            digests,
+            vec![
+                (
+                    "https://repo.anaconda.com/pkgs/main/linux-64/a-1.0-0.tar.bz2".to_string(),
+                    Digest::sha256(SHA256_EMPTY).unwrap()
+                ),
+                (
+                    "https://repo.anaconda.com/pkgs/main/linux-64/b-1.0-0.conda".to_string(),
+                    Digest::md5("d41d8cd98f00b204e9800998ecf8427e").unwrap()
+                ),
+            ]
+        );
+        assert!(conda_digests("https://example.com/repodata.json", b"BZh").is_empty());
+        assert!(!is_index(
+            DigestSource::Conda,
+            "https://example.com/repodata.json.bz2"
+        ));
+    }
+
+    #[test]
+    fn parse_apt() {
+        let packages = format!(
+            "Package: a\nFilename: pool/main/a/a_1.0_amd64.deb\nSHA256: {}\n\nPackage: b\nFilename: pool/main/b/b_1.0_amd64.deb\n",
+            SHA256_EMPTY
+        );
+        let digests = apt_digests(
+            "http://archive.ubuntu.com/ubuntu/dists/jammy/main/binary-amd64/Packages",
+            &packages,
+        );
+        assert_eq!(
+            digests,
+            vec![(
+                "http://archive.ubuntu.com/ubuntu/pool/main/a/a_1.0_amd64.deb".to_string(),
+                Digest::sha256(SHA256_EMPTY).unwrap()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn learn_and_verify() {
+        let url = "https://files.pythonhosted.org/packages/test/learn_and_verify.whl";
+        let page = format!(r#"<a href="{}#sha256={}">"#, url, SHA256_EMPTY);
+        assert_eq!(
+            learn(
+                DigestSource::PyPI,
+                "https://pypi.org/simple/test/",
+                page.as_bytes()
+            ),
+            1
+        );
+        let expected = expected_digest(url).unwrap();
+        let stream = futures::stream::iter(vec![Ok(Bytes::new())]);
+        assert_eq!(digest_stream(&expected, stream).await.unwrap(), expected);
+        let stream = futures::stream::iter(vec![Ok(Bytes::from_static(b"tampered"))]);
+        assert_ne!(digest_stream(&expected, stream).await.unwrap(), expected);
+    }
+}
@@ -0,0 +1,328 @@
+//! Listeners of the proxy and metrics endpoints, on TCP addresses, TCP
+//! addresses with TLS, or Unix domain sockets.
+use crate::error::{Error, Result};
+
+use futures::{future, stream, Future};
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::reply::Response;
+
+/// Prefix of Unix domain socket paths in listen addresses
+const UNIX_PREFIX: &str = "unix:";
+
+/// Prefix of TLS addresses in listen addresses
+const TLS_PREFIX: &str = "tls:";
+
+/// Maximum time for a client to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of the peer of a TLS connection, inserted in the extensions of its
+/// requests, as `warp::addr::remote()` is not available for them
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Marker of requests received on a TLS listener, inserted in their extensions,
+/// so that their scheme is `https` unless a proxy tells otherwise
+#[derive(Debug, Clone, Copy)]
+pub struct TlsConnection;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listener {
+    /// e.g. `0.0.0.0:9000` or `[::]:9000`
+    Tcp(SocketAddr),
+    /// e.g. `tls:0.0.0.0:443`
+    Tls(SocketAddr),
+    /// e.g. `unix:/run/mirror-cache.sock`
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = Error;
+
+    fn from_str(addr: &str) -> Result<Self> {
+        let invalid = || {
+            Error::ConfigInvalid(format!(
+                "invalid listen address {}, expecting host:port, tls:host:port or unix:path",
+                addr
+            ))
+        };
+        if let Some(addr) = addr.strip_prefix(TLS_PREFIX) {
+            return addr.parse().map(Listener::Tls).map_err(|_| invalid());
+        }
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) if !path.is_empty() => Ok(Listener::Unix(PathBuf::from(path))),
+            Some(_) => Err(Error::ConfigInvalid(format!(
+                "empty socket path in listen address {}",
+                addr
+            ))),
+            None => addr.parse().map(Listener::Tcp).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "http://{}", addr),
+            Listener::Tls(addr) => write!(f, "https://{}", addr),
+            Listener::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Parse listen addresses.
+pub fn parse_listeners(addrs: &[String]) -> Result<Vec<Listener>> {
+    addrs.iter().map(|addr| addr.parse()).collect()
+}
+
+/// Bind all `listeners`, and return a future serving `filter` on them until all
+/// of them are closed. Unix domain sockets are created with permissions `mode`
+/// if it is set. TLS addresses require the `tls` configuration.
+pub fn bind(
+    filter: BoxedFilter<(Response,)>,
+    listeners: &[Listener],
+    mode: Option<u32>,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<impl Future<Output = ()> + Send> {
+    let mut servers: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = Vec::new();
+    for listener in listeners {
+        let server = warp::serve(filter.clone());
+        match listener {
+            Listener::Tcp(addr) => {
+                let (_, serve) = server.try_bind_ephemeral(*addr).map_err(|e| {
+                    Error::OtherError(format!("failed to listen on {}: {}", listener, e))
+                })?;
+                servers.push(Box::pin(serve));
+            }
+            Listener::Tls(addr) => {
+                let config = tls.clone().ok_or_else(|| {
+                    Error::ConfigInvalid(format!("no TLS certificates for {}", listener))
+                })?;
+                let listener = std::net::TcpListener::bind(addr)
+                    .and_then(|listener| {
+                        listener.set_nonblocking(true)?;
+                        TcpListener::from_std(listener)
+                    })
+                    .map_err(|e| {
+                        Error::OtherError(format!("failed to listen on {}: {}", listener, e))
+                    })?;
+                servers.push(Box::pin(serve_tls(filter.clone(), listener, config)));
+            }
+            Listener::Unix(path) => {
+                let incoming = bind_unix(path, mode).map_err(|e| {
+                    Error::OtherError(format!("failed to listen on {}: {}", listener, e))
+                })?;
+                servers.push(Box::pin(server.serve_incoming(incoming)));
+            }
+        }
+        info!("listening on {}", listener);
+    }
+    Ok(async move {
+        future::join_all(servers).await;
+    })
+}
+
+/// Serve `filter` on the TLS connections of `listener`. Each connection is
+/// served in its own task.
+async fn serve_tls(
+    filter: BoxedFilter<(Response,)>,
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+) {
+    let acceptor = TlsAcceptor::from(config);
+    loop {
+        let (conn, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = warp::service(filter.clone());
+        tokio::spawn(async move {
+            let conn =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                    Ok(Ok(conn)) => conn,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", peer);
+                        return;
+                    }
+                };
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(PeerAddr(peer));
+                req.extensions_mut().insert(TlsConnection);
+                service.clone().call(req)
+            });
+            if let Err(e) = warp::hyper::server::conn::Http::new()
+                .serve_connection(conn, service)
+                .await
+            {
+                debug!("connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bind a Unix domain socket, replacing the socket left by a previous run, and
+/// return the stream of its connections.
+fn bind_unix(
+    path: &Path,
+    mode: Option<u32>,
+) -> std::io::Result<impl futures::Stream<Item = std::io::Result<tokio::net::UnixStream>>> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "the path exists and is not a socket",
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => return Some((Ok(conn), listener)),
+                Err(e) => {
+                    // e.g. too many open files, which must not stop the server
+                    error!("failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use warp::{Filter, Reply};
+
+    #[test]
+    fn parse_listen_addresses() {
+        let addrs: Vec<String> = [
+            "0.0.0.0:9000",
+            "[::]:9000",
+            "tls:[::]:443",
+            "unix:/run/mirror-cache.sock",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        assert_eq!(
+            parse_listeners(&addrs).unwrap(),
+            vec![
+                Listener::Tcp("0.0.0.0:9000".parse().unwrap()),
+                Listener::Tcp("[::]:9000".parse().unwrap()),
+                Listener::Tls("[::]:443".parse().unwrap()),
+                Listener::Unix(PathBuf::from("/run/mirror-cache.sock")),
+            ]
+        );
+        assert!("localhost".parse::<Listener>().is_err());
+        assert!("unix:".parse::<Listener>().is_err());
+        assert!("tls:localhost".parse::<Listener>().is_err());
+    }
+
+    #[tokio::test]
+    async fn serve_unix_socket() {
+        let path = std::env::temp_dir().join("mirror-cache-test.sock");
+        let filter = warp::any().map(|| "pong".into_response()).boxed();
+        let listeners = [Listener::Unix(path.clone())];
+        // a stale socket is replaced
+        for _ in 0..2 {
+            tokio::spawn(bind(filter.clone(), &listeners, Some(0o600), None).unwrap());
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let mut conn = tokio::net::UnixStream::connect(&path).await.unwrap();
+        conn.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        conn.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.0 200 OK"));
+        assert!(resp.ends_with("pong"));
+    }
+
+    #[tokio::test]
+    async fn serve_tls_with_reloaded_certificates() {
+        use crate::settings;
+        use crate::tls::{test::*, CertResolver};
+
+        let localhost = settings::Tls {
+            certificates: vec![write_certificate(
+                "serve-localhost",
+                LOCALHOST_CERT,
+                LOCALHOST_KEY,
+                None,
+            )],
+        };
+        let resolver = CertResolver::new(&localhost).unwrap();
+        let filter = warp::ext::optional::<PeerAddr>()
+            .and(warp::ext::optional::<TlsConnection>())
+            .map(|peer: Option<PeerAddr>, tls: Option<TlsConnection>| {
+                // requests are marked as received over TLS
+                format!("{} {}", peer.unwrap().0.ip(), tls.is_some()).into_response()
+            })
+            .boxed();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let listeners = [Listener::Tls(addr)];
+        assert!(bind(filter.clone(), &listeners, None, None).is_err());
+        tokio::spawn(bind(filter, &listeners, None, Some(resolver.server_config())).unwrap());
+
+        let get = |cert: &str, host: &str| {
+            let client = reqwest::Client::builder()
+                .add_root_certificate(reqwest::Certificate::from_pem(cert.as_bytes()).unwrap())
+                .resolve(host, addr)
+                .build()
+                .unwrap();
+            let url = format!("https://{}:{}/", host, addr.port());
+            async move { client.get(url).send().await?.text().await }
+        };
+        assert_eq!(
+            get(LOCALHOST_CERT, "localhost").await.unwrap(),
+            "127.0.0.1 true"
+        );
+        assert!(get(EXAMPLE_CERT, "a.example.test").await.is_err());
+
+        resolver
+            .reload(&settings::Tls {
+                certificates: vec![write_certificate(
+                    "serve-example",
+                    EXAMPLE_CERT,
+                    EXAMPLE_KEY,
+                    None,
+                )],
+            })
+            .unwrap();
+        assert_eq!(
+            get(EXAMPLE_CERT, "a.example.test").await.unwrap(),
+            "127.0.0.1 true"
+        );
+    }
+}
@@ -1,10 +1,23 @@
+mod access;
+mod admin;
 mod cache;
+mod conditional;
 mod error;
+mod freshness;
+mod inflight;
+mod integrity;
+mod listen;
 mod metric;
 mod models;
+mod negative;
+mod range;
+mod resume;
+mod rewrite;
 mod settings;
 mod storage;
 mod task;
+mod tls;
+mod upstream;
 mod util;
 
 use cache::CacheHitMiss;
@@ -14,10 +27,13 @@ use metrics_exporter_prometheus::PrometheusBuilder;
 use metrics_util::MetricKindMask;
 use notify::{Event, RecursiveMode, Watcher};
 use regex::{Regex, RegexSet};
+use rewrite::Origin;
 use settings::{rule_label, Rule};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use task::TaskManager;
 use tokio::sync::RwLock;
+use warp::{Filter, Reply};
 
 #[macro_use]
 extern crate serde_derive;
@@ -64,9 +80,15 @@ async fn main() {
     };
 
     let app_settings = settings::Settings::new(&config_filename).unwrap();
-    let port = app_settings.port;
-    let metrics_port = app_settings.metrics_port;
+    let listeners = listen::parse_listeners(&app_settings.get_listen()).unwrap();
+    let metrics_listeners = listen::parse_listeners(&app_settings.get_metrics_listen()).unwrap();
+    let unix_socket_mode = app_settings.get_unix_socket_mode().unwrap();
+    let admin_port = app_settings.admin_port;
     let hot_reload = app_settings.hot_reload.unwrap_or(false);
+    let cert_resolver = app_settings
+        .tls
+        .as_ref()
+        .map(|tls| tls::CertResolver::new(tls).expect("failed to load TLS certificates"));
     let api = filters::root();
 
     // initialize the logger
@@ -81,7 +103,8 @@ async fn main() {
 
     // initialize global static TASK_MANAGER and RE_SET_LIST
     let mut tm = TaskManager::new(app_settings.clone());
-    tm.refresh_config(&app_settings);
+    tm.refresh_config(&app_settings)
+        .expect("failed to apply the config");
     {
         let mut global_tm = TASK_MANAGER.write().await;
         *global_tm = tm;
@@ -91,22 +114,40 @@ async fn main() {
 
     // init metrics
     let builder = PrometheusBuilder::new();
-    builder
+    let prometheus = builder
         .idle_timeout(
             MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM,
             Some(std::time::Duration::from_secs(10)),
         )
-        .with_http_listener(([127, 0, 0, 1], metrics_port))
-        .install()
+        .install_recorder()
         .expect("failed to install Prometheus recorder");
+    let metrics_api = warp::any()
+        .map(move || prometheus.render().into_response())
+        .boxed();
+    tokio::spawn(
+        listen::bind(metrics_api, &metrics_listeners, unix_socket_mode, None)
+            .expect("failed to start metrics server"),
+    );
     metric::describe_counters();
     register_rules_metrics(&app_settings.rules);
 
+    // fix the drift between metadata and storage, e.g. after a crash
+    {
+        let tm = TASK_MANAGER.read().await.clone();
+        tm.cleanup_temp_files();
+        tm.reconcile_all().await;
+    }
+
     let config_filename_clone = config_filename.clone();
+    let cert_resolver_clone = cert_resolver.clone();
     // make watcher live long enough
     let mut watcher =
         notify::recommended_watcher(move |result: std::result::Result<Event, notify::Error>| {
-            file_watch_handler(&config_filename_clone, result)
+            file_watch_handler(
+                &config_filename_clone,
+                cert_resolver_clone.as_deref(),
+                result,
+            )
         })
         .unwrap();
     if hot_reload {
@@ -124,12 +165,42 @@ async fn main() {
             &config_filename
         );
     }
+    // certificates are watched in their directories, as they are usually
+    // replaced by renaming new files
+    let _cert_watcher = match (&cert_resolver, &app_settings.tls) {
+        (Some(resolver), Some(tls)) if hot_reload => Some(watch_certificates(resolver, tls)),
+        _ => None,
+    };
+
+    if let Some(admin_port) = admin_port {
+        info!("Admin API is listening on port {}", admin_port);
+        tokio::spawn(warp::serve(admin::routes()).run(([127, 0, 0, 1], admin_port)));
+    }
 
-    warp::serve(api).run(([127, 0, 0, 1], port)).await;
+    listen::bind(
+        api,
+        &listeners,
+        unix_socket_mode,
+        cert_resolver
+            .as_ref()
+            .map(|resolver| resolver.server_config()),
+    )
+    .expect("failed to start proxy server")
+    .await;
 }
 
-fn file_watch_handler(config_filename: &str, result: std::result::Result<Event, notify::Error>) {
-    let event = result.unwrap();
+fn file_watch_handler(
+    config_filename: &str,
+    cert_resolver: Option<&tls::CertResolver>,
+    result: std::result::Result<Event, notify::Error>,
+) {
+    let event = match result {
+        Ok(event) => event,
+        Err(e) => {
+            error!("failed to watch config: {}", e);
+            return;
+        }
+    };
     println!(" -- {:?}", event);
     if event.kind.is_modify() {
         util::sleep_ms(2000);
@@ -137,10 +208,17 @@ fn file_watch_handler(config_filename: &str, result: std::result::Result<Event,
         futures::executor::block_on(async {
             match settings::Settings::new(config_filename) {
                 Ok(settings) => {
-                    TASK_MANAGER.write().await.refresh_config(&settings);
+                    let refreshed = TASK_MANAGER.write().await.refresh_config(&settings);
+                    if let Err(e) = refreshed {
+                        error!("Failed to apply config: {}. Use the original config.", e);
+                        return;
+                    }
                     let mut re_set_list = RE_SET_LIST.write().await;
                     *re_set_list = create_re_set_list(&settings.rules);
                     register_rules_metrics(&settings.rules);
+                    if let (Some(resolver), Some(tls)) = (cert_resolver, &settings.tls) {
+                        reload_certificates(resolver, tls);
+                    }
                     info!("config updated");
                 }
                 Err(e) => {
@@ -151,6 +229,83 @@ fn file_watch_handler(config_filename: &str, result: std::result::Result<Event,
     }
 }
 
+/// Watch the directories of the certificates, and reload them from the current
+/// config when they change. Existing connections are not affected.
+fn watch_certificates(
+    resolver: &Arc<tls::CertResolver>,
+    tls: &settings::Tls,
+) -> notify::RecommendedWatcher {
+    let resolver = resolver.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |result: std::result::Result<Event, notify::Error>| {
+            let event = match result {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("failed to watch certificates: {}", e);
+                    return;
+                }
+            };
+            if event.kind.is_access() || event.kind.is_other() {
+                return;
+            }
+            let tls = futures::executor::block_on(TASK_MANAGER.read())
+                .config
+                .tls
+                .clone();
+            let tls = match tls {
+                Some(tls) => tls,
+                None => return,
+            };
+            let paths = watched_cert_paths(&tls);
+            if event.paths.iter().any(|path| paths.contains(path)) {
+                // wait for the other files of the certificate to be written
+                util::sleep_ms(1000);
+                reload_certificates(&resolver, &tls);
+            }
+        })
+        .unwrap();
+    let mut dirs: Vec<PathBuf> = watched_cert_paths(tls)
+        .into_iter()
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            Ok(_) => info!("Watching certificates in {}", dir.display()),
+            Err(e) => error!("failed to watch {}: {}", dir.display(), e),
+        }
+    }
+    watcher
+}
+
+/// Certificate and key paths as reported by the watcher, i.e. in canonical
+/// directories
+fn watched_cert_paths(tls: &settings::Tls) -> Vec<PathBuf> {
+    tls::cert_paths(tls)
+        .into_iter()
+        .filter_map(|path| {
+            let dir = path.parent()?;
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            Some(dir.canonicalize().ok()?.join(path.file_name()?))
+        })
+        .collect()
+}
+
+fn reload_certificates(resolver: &tls::CertResolver, tls: &settings::Tls) {
+    match resolver.reload(tls) {
+        Ok(_) => info!("TLS certificates reloaded"),
+        Err(e) => error!(
+            "Failed to reload TLS certificates: {}. Use the original certificates.",
+            e
+        ),
+    }
+}
+
 fn create_re_set_list(rules: &[Rule]) -> (RegexSet, Vec<Regex>) {
     let rules_strings: Vec<String> = rules.iter().map(|rule| rule.path.clone()).collect();
     let set = RegexSet::new(rules_strings).unwrap();
@@ -190,9 +345,11 @@ fn register_rules_metrics(rules: &[Rule]) {
 
 mod filters {
     use super::*;
-    use warp::Filter;
+    use std::net::SocketAddr;
+    use warp::filters::BoxedFilter;
+    use warp::reply::Response;
 
-    pub fn root() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    pub fn root() -> BoxedFilter<(Response,)> {
         let log = warp::log::custom(|info| {
             info!(
                 "🌐 {} {} Response: {}",
@@ -202,7 +359,36 @@ mod filters {
             );
         });
 
-        fallback_head().or(fallback().with(log))
+        authorized()
+            .and(fallback_head().or(fallback().with(log)))
+            .recover(handlers::handle_rejection)
+            .map(Reply::into_response)
+            .boxed()
+    }
+
+    /// Reject requests denied by the access control of the matched rule, or the
+    /// global one
+    fn authorized() -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::path::tail()
+            .map(|tail: warp::filters::path::Tail| tail.as_str().to_string())
+            .and(remote())
+            .and(warp::header::optional::<String>("x-forwarded-for"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and_then(handlers::authorize)
+            .untuple_one()
+    }
+
+    /// Address of the peer, of TCP or TLS connections
+    fn remote(
+    ) -> impl Filter<Extract = (Option<SocketAddr>,), Error = std::convert::Infallible> + Clone
+    {
+        warp::addr::remote()
+            .and(warp::ext::optional::<listen::PeerAddr>())
+            .map(
+                |remote: Option<SocketAddr>, peer: Option<listen::PeerAddr>| {
+                    remote.or(peer.map(|peer| peer.0))
+                },
+            )
     }
 
     fn fallback_head() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -210,15 +396,39 @@ mod filters {
             .and(
                 warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()),
             )
+            .and(warp::header::headers_cloned())
             .and_then(handlers::head_fallback_handler)
     }
 
+    /// Origin of the request, for rewrites with placeholders. Forwarded headers
+    /// are only honoured from trusted proxies.
+    fn origin() -> impl Filter<Extract = (Origin,), Error = std::convert::Infallible> + Clone {
+        warp::header::headers_cloned()
+            .and(warp::ext::optional::<listen::TlsConnection>())
+            .and(remote())
+            .then(
+                |headers, tls: Option<listen::TlsConnection>, remote: Option<SocketAddr>| async move {
+                    let forwarded = access::is_trusted_proxy(
+                        remote,
+                        TASK_MANAGER.read().await.trusted_proxies(),
+                    );
+                    Origin::from_headers(&headers, tls.is_some(), forwarded)
+                },
+            )
+    }
+
     /// fallback handler, matches all paths
     fn fallback() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::get()
             .and(
                 warp::path::tail().map(|tail: warp::filters::path::Tail| tail.as_str().to_string()),
             )
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::header::optional::<String>("if-range"))
+            .and(warp::header::optional::<String>("if-none-match"))
+            .and(warp::header::optional::<String>("if-modified-since"))
+            .and(origin())
+            .and(warp::header::headers_cloned())
             .and_then(handlers::fallback_handler)
     }
 }
@@ -227,26 +437,51 @@ mod handlers {
     use super::*;
     use crate::error::Error;
     use crate::task::Task;
+    use std::net::SocketAddr;
     use std::result::Result;
+    use warp::http::{header, HeaderMap};
     use warp::Rejection;
-    use warp::Reply;
 
-    pub async fn head_fallback_handler(path: String) -> Result<impl warp::Reply, Rejection> {
+    pub async fn head_fallback_handler(
+        path: String,
+        client_headers: HeaderMap,
+    ) -> Result<impl warp::Reply, Rejection> {
         // resolve path to upstream url
         let resolve_result = resolve_upstream(&path).await;
         if resolve_result.is_none() {
             return Err(warp::reject::not_found());
         }
-        let upstream = resolve_result.unwrap().0;
-        match util::make_request(&upstream, true).await {
-            Ok(up_resp) => {
-                // create a response and copy headers
+        let (upstream, mirrors, idx, _) = resolve_result.unwrap();
+        let tm = TASK_MANAGER.read().await.clone();
+        let headers = tm.upstream_headers(idx, &client_headers);
+        let rewritten = tm.rewrite_map.contains_key(&idx);
+        // fail over to the mirrors like a GET request
+        let task = Task {
+            rule_id: idx,
+            url: upstream,
+            mirrors,
+            variant: None,
+        };
+        match tm.request_upstream(&task, true, headers).await {
+            Ok((up_resp, _)) => {
+                // create a response and copy headers, except those describing the
+                // upstream bytes if the body is rewritten
                 let resp_builder = up_resp
                     .headers()
                     .iter()
-                    .fold(warp::http::Response::builder(), |prev, (key, value)| {
-                        prev.header(key, value)
-                    });
+                    .filter(|(key, _)| {
+                        !rewritten
+                            || (*key != header::CONTENT_LENGTH && *key != header::LAST_MODIFIED)
+                    })
+                    .fold(
+                        warp::http::Response::builder().status(up_resp.status()),
+                        |prev, (key, value)| match value.to_str() {
+                            Ok(etag) if rewritten && key == header::ETAG => {
+                                prev.header(key, conditional::weak_etag(etag))
+                            }
+                            _ => prev.header(key, value),
+                        },
+                    );
                 Ok(resp_builder.body("").unwrap())
             }
             Err(e) => match e {
@@ -261,20 +496,30 @@ mod handlers {
         }
     }
 
-    pub async fn fallback_handler(path: String) -> Result<impl warp::Reply, Rejection> {
+    pub async fn fallback_handler(
+        path: String,
+        range: Option<String>,
+        if_range: Option<String>,
+        if_none_match: Option<String>,
+        if_modified_since: Option<String>,
+        origin: Origin,
+        client_headers: HeaderMap,
+    ) -> Result<impl warp::Reply, Rejection> {
         let upstream = resolve_upstream(&path).await;
         if upstream.is_none() {
             return Err(warp::reject());
         }
-        let (upstream, idx, rule) = upstream.unwrap();
+        let (upstream, mirrors, idx, rule) = upstream.unwrap();
         trace!("matched by rule #{}: {}", idx, &rule.path);
         increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(&rule));
+        let tm = TASK_MANAGER.read().await.clone();
         let task = Task {
             rule_id: idx,
             url: upstream,
+            mirrors,
+            variant: tm.variant(idx, &client_headers),
         };
-        let tm = TASK_MANAGER.read().await.clone();
-        let tm_resp = tm.resolve_task(&task).await;
+        let tm_resp = tm.resolve_task(&task, &client_headers).await;
         match tm_resp.1 {
             CacheHitMiss::Hit => {
                 increment_counter!(metric::COUNTER_CACHE_HIT, "rule" => rule_label(&rule))
@@ -284,14 +529,27 @@ mod handlers {
             }
         };
         match tm_resp.0 {
-            Ok(data) => {
-                let mut resp = data.into_response();
-                if let Some(options) = &rule.options {
-                    if let Some(content_type) = &options.content_type {
-                        resp = warp::reply::with_header(resp, "content-type", content_type)
-                            .into_response();
-                    }
+            Ok((data, headers)) => {
+                let headers = tm.served_headers(&task, headers);
+                if conditional::is_not_modified(
+                    &headers,
+                    if_none_match.as_deref(),
+                    if_modified_since.as_deref(),
+                ) {
+                    return Ok(conditional::not_modified_response(&headers));
                 }
+                let content_type = rule
+                    .options
+                    .as_ref()
+                    .and_then(|options| options.content_type.as_deref());
+                // serve the whole body if the representation is changed
+                let range = range.as_deref().filter(|_| {
+                    if_range
+                        .as_deref()
+                        .is_none_or(|if_range| conditional::if_range_matches(&headers, if_range))
+                });
+                let data = tm.materialize(&task, data, &origin);
+                let resp = data.into_ranged_response(range, &headers, content_type);
                 increment_counter!(metric::COUNTER_REQ_FAILURE, "rule" => rule_label(&rule));
                 Ok(resp)
             }
@@ -305,14 +563,55 @@ mod handlers {
                             .unwrap();
                         Ok(resp)
                     }
+                    Error::NegativeCacheHit(status) => Ok(warp::http::Response::builder()
+                        .status(status)
+                        .body(warp::hyper::Body::empty())
+                        .unwrap()),
                     _ => Err(warp::reject::custom(e)),
                 }
             }
         }
     }
 
+    pub async fn authorize(
+        path: String,
+        remote: Option<SocketAddr>,
+        forwarded_for: Option<String>,
+        authorization: Option<String>,
+    ) -> Result<(), Rejection> {
+        let rule_id = RE_SET_LIST.read().await.0.matches(&path).into_iter().next();
+        let (access, client_ip) = {
+            let tm = TASK_MANAGER.read().await;
+            let client_ip =
+                access::client_ip(remote, forwarded_for.as_deref(), tm.trusted_proxies());
+            (tm.access_control(rule_id), client_ip)
+        };
+        match access {
+            Some(access) => access
+                .check(client_ip, authorization.as_deref())
+                .await
+                .map_err(|denied| {
+                    info!(
+                        "denied request of {:?} to {}: {:?}",
+                        client_ip, path, denied
+                    );
+                    warp::reject::custom(denied)
+                }),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn handle_rejection(
+        rejection: Rejection,
+    ) -> Result<warp::reply::Response, Rejection> {
+        match rejection.find::<access::Denied>() {
+            Some(denied) => Ok(denied.to_response()),
+            None => Err(rejection),
+        }
+    }
+
     /// Dynamically resolve upstream url as defined in config file
-    async fn resolve_upstream(path: &str) -> Option<(String, usize, Rule)> {
+    async fn resolve_upstream(path: &str) -> Option<(String, Vec<String>, usize, Rule)> {
         let tm = TASK_MANAGER.read().await.clone();
         let config = &tm.config;
         let rules_regex_set_list = RE_SET_LIST.read().await;
@@ -329,7 +628,13 @@ mod handlers {
         trace!("matched by rule #{}: {}", idx, &rule.path);
         increment_counter!(metric::COUNTER_REQ, "rule" => rule_label(rule));
         let replaced = re.replace_all(path, &upstream);
-        Some((String::from(replaced), idx, rule.clone()))
+        let mirrors = rule
+            .mirrors
+            .iter()
+            .flatten()
+            .map(|mirror| re.replace_all(path, mirror).into_owned())
+            .collect();
+        Some((String::from(replaced), mirrors, idx, rule.clone()))
     }
 }
 
@@ -357,7 +662,11 @@ mod test {
 
         let _ = &LOGGER;
         let settings = get_settings();
-        TASK_MANAGER.write().await.refresh_config(&settings);
+        TASK_MANAGER
+            .write()
+            .await
+            .refresh_config(&settings)
+            .unwrap();
         let mut global_re_set_list = RE_SET_LIST.write().await;
         *global_re_set_list = create_re_set_list(&settings.rules);
     }
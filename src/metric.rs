@@ -14,6 +14,19 @@ pub static CNT_OUT_REQUESTS_FAILURE: &str = "outbound_requests_failure";
 pub static HG_TASKS_LEN: &str = "current_download_tasks";
 pub static HG_CACHE_SIZE_PREFIX: &str = "cache_size";
 pub static CNT_RM_FILES: &str = "files_removed";
+pub static CNT_REVALIDATED: &str = "cache_revalidated";
+pub static CNT_STALE_SERVED: &str = "cache_stale_served";
+pub static CNT_INTEGRITY_VERIFIED: &str = "cache_integrity_verified";
+pub static CNT_INTEGRITY_MISMATCH: &str = "cache_integrity_mismatch";
+pub static CNT_UPSTREAM_REQUESTS: &str = "upstream_requests";
+pub static CNT_UPSTREAM_FAILURES: &str = "upstream_failures";
+pub static CNT_UPSTREAM_FAILOVERS: &str = "upstream_failovers";
+pub static CNT_UPSTREAM_RETRIES: &str = "upstream_retries";
+pub static CNT_DOWNLOAD_RETRIES: &str = "download_retries";
+pub static CNT_ACCESS_DENIED: &str = "requests_denied";
+pub static CNT_NEGATIVE_HIT: &str = "negative_cache_hit";
+pub static CNT_NEGATIVE_MISS: &str = "negative_cache_miss";
+pub static GAUGE_UPSTREAM_HEALTHY: &str = "upstream_healthy";
 
 pub fn describe_counters() {
     describe_counter!(
@@ -43,6 +56,58 @@ pub fn describe_counters() {
         "The current size of background download task set."
     );
     describe_counter!(CNT_RM_FILES, "The number of removed files.");
+    describe_counter!(
+        CNT_REVALIDATED,
+        "The number of expired cache entries revalidated by upstream."
+    );
+    describe_counter!(
+        CNT_STALE_SERVED,
+        "The number of expired cache entries served while refreshing or as upstream failed."
+    );
+    describe_counter!(
+        CNT_INTEGRITY_VERIFIED,
+        "The number of downloads matching the digests published by upstream."
+    );
+    describe_counter!(
+        CNT_INTEGRITY_MISMATCH,
+        "The number of downloads not cached as they mismatch the digests published by upstream."
+    );
+    describe_counter!(
+        CNT_UPSTREAM_REQUESTS,
+        "The number of requests to each upstream, including failed ones."
+    );
+    describe_counter!(
+        CNT_UPSTREAM_FAILURES,
+        "The number of failed requests or server errors of each upstream."
+    );
+    describe_counter!(
+        CNT_UPSTREAM_FAILOVERS,
+        "The number of requests retried with another upstream."
+    );
+    describe_counter!(
+        CNT_UPSTREAM_RETRIES,
+        "The number of requests retried after all upstreams failed."
+    );
+    describe_counter!(
+        CNT_DOWNLOAD_RETRIES,
+        "The number of attempts to resume interrupted upstream downloads."
+    );
+    describe_counter!(
+        CNT_ACCESS_DENIED,
+        "The number of requests denied by access control."
+    );
+    describe_counter!(
+        CNT_NEGATIVE_HIT,
+        "The number of requests answered with a remembered status that the object does not exist."
+    );
+    describe_counter!(
+        CNT_NEGATIVE_MISS,
+        "The number of requests looked up in the negative cache without a remembered status."
+    );
+    describe_gauge!(
+        GAUGE_UPSTREAM_HEALTHY,
+        "Whether each upstream is healthy (1) or not (0)."
+    );
 }
 
 pub fn get_cache_size_metrics_key(id: &str) -> String {
@@ -1,5 +1,9 @@
 use crate::cache::CacheEntry;
+use crate::cache::Eviction;
+use crate::cache::KeyPage;
 use crate::cache::LruCacheMetadata;
+use crate::cache::ResponseHeaders;
+use crate::cache::TtlEntry;
 use crate::error::Error::*;
 use crate::error::Result;
 use crate::util;
@@ -38,6 +42,7 @@ pub fn get_cache_entry(
             size: String::from(map.get("size").unwrap_or(&String::from("0")))
                 .parse::<u64>()
                 .unwrap_or(0),
+            headers: ResponseHeaders::decode(map.get("headers").map_or("", |x| x.as_str())),
         },
         key: String::from(map.get("path").unwrap_or(&String::from(""))),
         value: (),
@@ -56,24 +61,205 @@ pub fn set_lru_cache_entry(
     let kv_array = entry.to_redis_multiple_fields();
     let tx_result = redis::transaction(con, &[key, total_size_key, zlist_key], |con, pipe| {
         let pkg_size: Option<u64> = con.hget(key, "size").unwrap();
-        pipe.decr(
-            total_size_key,
-            if let Some(old_size) = pkg_size {
-                old_size
-            } else {
-                0
-            },
-        )
-        .incr(total_size_key, entry.metadata.size)
-        .hset_multiple::<&str, &str, String>(key, &kv_array)
-        .ignore()
-        .zadd(zlist_key, key, entry.metadata.atime)
-        .query(con)?;
+        pipe.decr(total_size_key, pkg_size.unwrap_or_default())
+            .incr(total_size_key, entry.metadata.size)
+            .hset_multiple::<&str, &str, String>(key, &kv_array)
+            .ignore()
+            .zadd(zlist_key, key, entry.metadata.atime)
+            .query::<()>(con)?;
         Ok(Some(()))
     });
     tx_result.map_err(RedisCMDError)
 }
 
+/// remove an lru cache entry, returns whether it existed
+pub fn remove_lru_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    total_size_key: &str,
+    zlist_key: &str,
+) -> Result<bool> {
+    redis::transaction(con, &[key, total_size_key, zlist_key], |con, pipe| {
+        let pkg_size: Option<u64> = con.hget(key, "size")?;
+        match pkg_size {
+            Some(size) => {
+                pipe.del(key)
+                    .ignore()
+                    .zrem(zlist_key, key)
+                    .ignore()
+                    .decr(total_size_key, size)
+                    .ignore()
+                    .query::<()>(con)?;
+                Ok(Some(true))
+            }
+            None => {
+                // the zlist may still have the key
+                pipe.zrem(zlist_key, key).ignore().query::<()>(con)?;
+                Ok(Some(false))
+            }
+        }
+    })
+    .map_err(RedisCMDError)
+}
+
+/// set an lfu cache entry. Its frequency is incremented if it exists, and its
+/// priority is the cache age plus the frequency.
+pub fn set_lfu_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    entry: &CacheEntry<LruCacheMetadata, String, ()>,
+    total_size_key: &str,
+    zlist_key: &str,
+    age_key: &str,
+) -> Result<()> {
+    let kv_array = entry.to_redis_multiple_fields();
+    let tx_result = redis::transaction(
+        con,
+        &[key, total_size_key, zlist_key, age_key],
+        |con, pipe| {
+            let pkg_size: Option<u64> = con.hget(key, "size")?;
+            let freq: Option<u64> = con.hget(key, "freq")?;
+            let age: Option<u64> = con.get(age_key)?;
+            let freq = freq.unwrap_or_default() + 1;
+            pipe.decr(total_size_key, pkg_size.unwrap_or_default())
+                .incr(total_size_key, entry.metadata.size)
+                .hset_multiple::<&str, &str, String>(key, &kv_array)
+                .ignore()
+                .hset(key, "freq", freq)
+                .ignore()
+                .zadd(zlist_key, key, age.unwrap_or_default() + freq)
+                .query::<()>(con)?;
+            Ok(Some(()))
+        },
+    );
+    tx_result.map_err(RedisCMDError)
+}
+
+/// Count a hit of an lfu cache entry, and update its priority. An entry evicted
+/// since it was read is left alone.
+pub fn hit_lfu_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    atime: i64,
+    zlist_key: &str,
+    age_key: &str,
+) -> Result<()> {
+    redis::transaction(con, &[key, age_key], |con, pipe| {
+        let pkg_size: Option<u64> = con.hget(key, "size")?;
+        if pkg_size.is_none() {
+            return Ok(Some(()));
+        }
+        let freq: Option<u64> = con.hget(key, "freq")?;
+        let age: Option<u64> = con.get(age_key)?;
+        let freq = freq.unwrap_or_default() + 1;
+        pipe.hset(key, "freq", freq)
+            .ignore()
+            .hset(key, "atime", atime)
+            .ignore()
+            .zadd(zlist_key, key, age.unwrap_or_default() + freq)
+            .ignore()
+            .query::<()>(con)?;
+        Ok(Some(()))
+    })
+    .map_err(RedisCMDError)
+}
+
+/// Mark a sieve cache entry visited, unless it has been evicted since it was read
+pub fn hit_sieve_cache_entry(con: &mut SyncConnection, key: &str) -> Result<()> {
+    redis::transaction(con, &[key], |con, pipe| {
+        let pkg_size: Option<u64> = con.hget(key, "size")?;
+        if pkg_size.is_some() {
+            pipe.hset(key, "visited", 1).ignore().query::<()>(con)?;
+        }
+        Ok(Some(()))
+    })
+    .map_err(RedisCMDError)
+}
+
+/// set a sieve cache entry. A new entry is queued as the newest one, while an
+/// existing one keeps its position and is marked visited.
+pub fn set_sieve_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    entry: &CacheEntry<LruCacheMetadata, String, ()>,
+    total_size_key: &str,
+    zlist_key: &str,
+    seq_key: &str,
+) -> Result<()> {
+    let kv_array = entry.to_redis_multiple_fields();
+    let tx_result = redis::transaction(con, &[key, total_size_key, zlist_key], |con, pipe| {
+        let pkg_size: Option<u64> = con.hget(key, "size")?;
+        pipe.decr(total_size_key, pkg_size.unwrap_or_default())
+            .incr(total_size_key, entry.metadata.size)
+            .hset_multiple::<&str, &str, String>(key, &kv_array)
+            .ignore();
+        match pkg_size {
+            Some(_) => {
+                pipe.hset(key, "visited", 1).ignore();
+            }
+            None => {
+                // a skipped sequence number does no harm if the transaction is retried
+                let seq: u64 = con.incr(seq_key, 1)?;
+                pipe.hset(key, "visited", 0)
+                    .ignore()
+                    .zadd(zlist_key, key, seq)
+                    .ignore();
+            }
+        }
+        pipe.query::<()>(con)?;
+        Ok(Some(()))
+    });
+    tx_result.map_err(RedisCMDError)
+}
+
+/// Score an entry set under another eviction policy for `eviction`, as if it
+/// was just inserted. An LFU entry keeps its frequency if any. A removed entry
+/// is dropped from the zlist.
+pub fn rescore_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    eviction: Eviction,
+    zlist_key: &str,
+    age_key: &str,
+    seq_key: &str,
+) -> Result<()> {
+    redis::transaction(con, &[key, age_key], |con, pipe| {
+        let atime: Option<i64> = con.hget(key, "atime")?;
+        let atime = match atime {
+            Some(atime) => atime,
+            None => {
+                pipe.zrem(zlist_key, key).ignore().query::<()>(con)?;
+                return Ok(Some(()));
+            }
+        };
+        match eviction {
+            Eviction::Lru => {
+                pipe.zadd(zlist_key, key, atime).ignore();
+            }
+            Eviction::Lfu => {
+                let freq: Option<u64> = con.hget(key, "freq")?;
+                let age: Option<u64> = con.get(age_key)?;
+                let freq = freq.unwrap_or_default().max(1);
+                pipe.hset(key, "freq", freq)
+                    .ignore()
+                    .zadd(zlist_key, key, age.unwrap_or_default() + freq)
+                    .ignore();
+            }
+            Eviction::Sieve => {
+                // a skipped sequence number does no harm if the transaction is retried
+                let seq: u64 = con.incr(seq_key, 1)?;
+                pipe.hset(key, "visited", 0)
+                    .ignore()
+                    .zadd(zlist_key, key, seq)
+                    .ignore();
+            }
+        }
+        pipe.query::<()>(con)?;
+        Ok(Some(()))
+    })
+    .map_err(RedisCMDError)
+}
+
 pub fn update_cache_entry_atime(
     con: &mut SyncConnection,
     key: &str,
@@ -91,6 +277,59 @@ pub fn update_cache_entry_atime(
     }
 }
 
+/// Convert a redis cursor to the cursor of the next page
+fn next_cursor(cursor: String) -> Option<String> {
+    if cursor == "0" {
+        None
+    } else {
+        Some(cursor)
+    }
+}
+
+/// scan a page of keys matching `pattern`. `limit` is only a hint, and keys
+/// may be duplicated across pages.
+pub fn scan_keys(
+    con: &mut SyncConnection,
+    pattern: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<KeyPage> {
+    let (cursor, keys): (String, Vec<String>) = redis::cmd("SCAN")
+        .arg(cursor.unwrap_or("0"))
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(limit)
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(KeyPage {
+        keys,
+        next: next_cursor(cursor),
+    })
+}
+
+/// scan a page of members of a sorted set. `limit` is only a hint, and
+/// members may be duplicated across pages.
+pub fn zscan_members(
+    con: &mut SyncConnection,
+    key: &str,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<KeyPage> {
+    let (cursor, members_with_scores): (String, Vec<String>) = redis::cmd("ZSCAN")
+        .arg(key)
+        .arg(cursor.unwrap_or("0"))
+        .arg("COUNT")
+        .arg(limit)
+        .query(con)
+        .map_err(RedisCMDError)?;
+    Ok(KeyPage {
+        keys: members_with_scores.into_iter().step_by(2).collect(),
+        next: next_cursor(cursor),
+    })
+}
+
+#[allow(dead_code)]
 pub fn set(con: &mut SyncConnection, key: &str, value: &str) -> Result<String> {
     match con.set(key, value) {
         Ok(res) => Ok(res),
@@ -98,6 +337,7 @@ pub fn set(con: &mut SyncConnection, key: &str, value: &str) -> Result<String> {
     }
 }
 
+#[allow(dead_code)]
 pub fn get(con: &mut SyncConnection, key: &str) -> Result<Option<String>> {
     match con.get(key) {
         Ok(val) => Ok(val),
@@ -105,9 +345,163 @@ pub fn get(con: &mut SyncConnection, key: &str) -> Result<Option<String>> {
     }
 }
 
+/// get a ttl cache entry, which is stored as a hash
+pub fn get_ttl_cache_entry(con: &mut SyncConnection, key: &str) -> Result<Option<TtlEntry>> {
+    let map: HashMap<String, String> = con.hgetall(key)?;
+    if map.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(TtlEntry {
+        expire_time: map
+            .get("expire_time")
+            .and_then(|x| x.parse::<i64>().ok())
+            .unwrap_or(0),
+        headers: ResponseHeaders::decode(map.get("headers").map_or("", |x| x.as_str())),
+    }))
+}
+
+/// Keys of a TTL cache, which account for the size of its entries and order
+/// them for eviction. Entries are members by their cache key.
+pub struct TtlIndexKeys {
+    pub total_size: String,
+    /// Hash of the size of entries
+    pub sizes: String,
+    /// Zlist of entries scored by atime
+    pub atimes: String,
+    /// Zlist of entries scored by expire time in nanoseconds
+    pub expire_times: String,
+}
+
+/// set a ttl cache entry of `size`, which is removed by redis after `retention`
+/// seconds. The recorded size of an existing entry is kept if `size` is `None`.
+pub fn set_ttl_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    member: &str,
+    entry: &TtlEntry,
+    size: Option<u64>,
+    retention: usize,
+    index: &TtlIndexKeys,
+) -> Result<()> {
+    redis::transaction(con, &[&index.sizes], |con, pipe| {
+        let old_size: Option<u64> = con.hget(&index.sizes, member)?;
+        let size = size.or(old_size).unwrap_or(0);
+        pipe
+            // entries of old versions are plain strings
+            .del(key)
+            .ignore()
+            .hset_multiple::<&str, &str, String>(
+                key,
+                &[
+                    ("expire_time", entry.expire_time.to_string()),
+                    ("headers", entry.headers.encode()),
+                ],
+            )
+            .ignore()
+            .expire(key, retention)
+            .ignore()
+            .hset(&index.sizes, member, size)
+            .ignore()
+            .decr(&index.total_size, old_size.unwrap_or(0))
+            .ignore()
+            .incr(&index.total_size, size)
+            .ignore()
+            .zadd(&index.atimes, member, util::now())
+            .ignore()
+            .zadd(&index.expire_times, member, entry.expire_time)
+            .ignore()
+            .query::<()>(con)?;
+        Ok(Some(()))
+    })
+    .map_err(RedisCMDError)
+}
+
+/// Record the size of an existing ttl cache entry, and index it if it is not
+/// yet, e.g. an entry of an old version
+pub fn set_ttl_cache_entry_size(
+    con: &mut SyncConnection,
+    key: &str,
+    member: &str,
+    size: u64,
+    index: &TtlIndexKeys,
+) -> Result<()> {
+    redis::transaction(con, &[key, &index.sizes], |con, pipe| {
+        let expire_time: Option<i64> = con.hget(key, "expire_time")?;
+        let expire_time = match expire_time {
+            Some(expire_time) => expire_time,
+            None => return Ok(Some(())),
+        };
+        let old_size: Option<u64> = con.hget(&index.sizes, member)?;
+        pipe.hset(&index.sizes, member, size)
+            .ignore()
+            .decr(&index.total_size, old_size.unwrap_or(0))
+            .ignore()
+            .incr(&index.total_size, size)
+            .ignore()
+            .cmd("ZADD")
+            .arg(&index.atimes)
+            .arg("NX")
+            .arg(util::now())
+            .arg(member)
+            .ignore()
+            .zadd(&index.expire_times, member, expire_time)
+            .ignore()
+            .query::<()>(con)?;
+        Ok(Some(()))
+    })
+    .map_err(RedisCMDError)
+}
+
+/// get the recorded size of a ttl cache entry
+pub fn get_ttl_cache_entry_size(
+    con: &mut SyncConnection,
+    member: &str,
+    index: &TtlIndexKeys,
+) -> Result<Option<u64>> {
+    Ok(con.hget(&index.sizes, member)?)
+}
+
+/// Remove a ttl cache entry and its size. If `if_missing`, only the size of an
+/// entry already removed by redis is removed.
+/// Returns whether the entry or its size is removed.
+pub fn remove_ttl_cache_entry(
+    con: &mut SyncConnection,
+    key: &str,
+    member: &str,
+    index: &TtlIndexKeys,
+    if_missing: bool,
+) -> Result<bool> {
+    redis::transaction(con, &[key, &index.sizes], |con, pipe| {
+        if if_missing && con.exists(key)? {
+            // the entry is set again
+            return Ok(Some(false));
+        }
+        let size: Option<u64> = con.hget(&index.sizes, member)?;
+        if let Some(size) = size {
+            pipe.decr(&index.total_size, size).ignore();
+        }
+        let (removed,): (usize,) = pipe
+            .del(key)
+            .hdel(&index.sizes, member)
+            .ignore()
+            .zrem(&index.atimes, member)
+            .ignore()
+            .zrem(&index.expire_times, member)
+            .ignore()
+            .query(con)?;
+        Ok(Some(if if_missing {
+            size.is_some()
+        } else {
+            removed > 0
+        }))
+    })
+    .map_err(RedisCMDError)
+}
+
 /**
  * Set the TTL of given key
  */
+#[allow(dead_code)]
 pub fn expire(con: &mut SyncConnection, key: &str, ttl: usize) -> Result<i32> {
     match con.expire(key, ttl) {
         Ok(res) => Ok(res),
@@ -115,9 +509,11 @@ pub fn expire(con: &mut SyncConnection, key: &str, ttl: usize) -> Result<i32> {
     }
 }
 
+/// Layout: atime (8 bytes) | size (8 bytes) | encoded headers
 pub struct SledMetadata {
     pub atime: i64,
     pub size: u64,
+    pub headers: ResponseHeaders,
 }
 
 impl From<sled::IVec> for SledMetadata {
@@ -125,35 +521,143 @@ impl From<sled::IVec> for SledMetadata {
         Self {
             atime: i64::from_be_bytes(vec.subslice(0, 8).as_ref().try_into().unwrap()),
             size: util::ivec_to_u64(&vec.subslice(8, 8)),
+            headers: ResponseHeaders::decode(&String::from_utf8_lossy(&vec[16..])),
         }
     }
 }
 
 impl From<SledMetadata> for sled::IVec {
     fn from(metadata: SledMetadata) -> Self {
-        [metadata.atime.to_be_bytes(), metadata.size.to_be_bytes()]
-            .concat()
-            .into()
+        [
+            &metadata.atime.to_be_bytes()[..],
+            &metadata.size.to_be_bytes()[..],
+            metadata.headers.encode().as_bytes(),
+        ]
+        .concat()
+        .into()
+    }
+}
+
+/// Eviction state of an entry of an LFU or SIEVE cache.
+///
+/// Layout: frequency (8 bytes) | visited (1 byte) | key in the order tree
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SledEvictionState {
+    pub freq: u64,
+    pub visited: bool,
+    pub order_key: Vec<u8>,
+}
+
+impl From<sled::IVec> for SledEvictionState {
+    fn from(vec: sled::IVec) -> Self {
+        Self {
+            freq: util::ivec_to_u64(&vec.subslice(0, 8)),
+            visited: vec[8] != 0,
+            order_key: vec[9..].to_vec(),
+        }
+    }
+}
+
+impl From<SledEvictionState> for sled::IVec {
+    fn from(state: SledEvictionState) -> Self {
+        [
+            &state.freq.to_be_bytes()[..],
+            &[state.visited as u8][..],
+            &state.order_key[..],
+        ]
+        .concat()
+        .into()
+    }
+}
+
+/// Layout: expire time (8 bytes) | removal time (8 bytes) | encoded headers
+///
+/// Entries of old versions only have the expire time.
+pub struct SledTtlMetadata {
+    pub expire_time: i64,
+    pub remove_time: i64,
+    pub headers: ResponseHeaders,
+}
+
+impl From<sled::IVec> for SledTtlMetadata {
+    fn from(vec: sled::IVec) -> Self {
+        let expire_time = i64::from_be_bytes(vec[..8].try_into().unwrap());
+        if vec.len() < 16 {
+            return Self {
+                expire_time,
+                remove_time: expire_time,
+                headers: ResponseHeaders::default(),
+            };
+        }
+        Self {
+            expire_time,
+            remove_time: i64::from_be_bytes(vec[8..16].try_into().unwrap()),
+            headers: ResponseHeaders::decode(&String::from_utf8_lossy(&vec[16..])),
+        }
+    }
+}
+
+impl From<SledTtlMetadata> for sled::IVec {
+    fn from(metadata: SledTtlMetadata) -> Self {
+        [
+            &metadata.expire_time.to_be_bytes()[..],
+            &metadata.remove_time.to_be_bytes()[..],
+            metadata.headers.encode().as_bytes(),
+        ]
+        .concat()
+        .into()
     }
 }
 
-/// Update the atime for the given cache key.
+/// Size and access of a TTL cache entry, to account for the size of a cache and
+/// to evict its entries in LRU order.
+///
+/// Layout: size (8 bytes) | atime (8 bytes)
+#[derive(Debug, PartialEq, Eq)]
+pub struct SledTtlUsage {
+    pub size: u64,
+    pub atime: i64,
+}
+
+impl From<sled::IVec> for SledTtlUsage {
+    fn from(vec: sled::IVec) -> Self {
+        Self {
+            size: util::ivec_to_u64(&vec.subslice(0, 8)),
+            atime: i64::from_be_bytes(vec[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl From<SledTtlUsage> for sled::IVec {
+    fn from(usage: SledTtlUsage) -> Self {
+        [
+            &usage.size.to_be_bytes()[..],
+            &usage.atime.to_be_bytes()[..],
+        ]
+        .concat()
+        .into()
+    }
+}
+
+/// Update the atime for the given cache key, and return the updated metadata.
 /// This should be called within a transaction context to ensure atomicity.
 pub fn sled_update_cache_entry_atime(
     metadata_tree: &TransactionalTree,
     atime_tree: &TransactionalTree,
     key: &str,
     atime: i64,
-) {
+) -> SledMetadata {
     let old_entry: SledMetadata = metadata_tree.get(key).unwrap().unwrap().into();
     let old_atime = old_entry.atime;
     atime_tree.remove(&old_atime.to_be_bytes()).unwrap();
     let new_metadata = SledMetadata {
         atime,
         size: old_entry.size,
+        headers: old_entry.headers.clone(),
     };
     metadata_tree.insert(key, new_metadata).unwrap();
     atime_tree.insert(&atime.to_be_bytes(), key).unwrap();
+    SledMetadata { atime, ..old_entry }
 }
 
 pub fn sled_insert_cache_entry(
@@ -162,10 +666,10 @@ pub fn sled_insert_cache_entry(
     metadata_tree: &TransactionalTree,
     atime_tree: &TransactionalTree,
     key: &str,
-    size: u64,
-    atime: i64,
+    metadata: SledMetadata,
 ) {
-    match metadata_tree.insert(key, SledMetadata { atime, size }) {
+    let (atime, size) = (metadata.atime, metadata.size);
+    match metadata_tree.insert(key, metadata) {
         Ok(Some(old_entry)) => {
             // remove old entry in atime_tree
             let old_entry: SledMetadata = old_entry.into();
@@ -286,6 +790,7 @@ mod tests {
         let metadata = SledMetadata {
             atime: 233,
             size: 0xaabbccdddeadbeef,
+            headers: ResponseHeaders::default(),
         };
         let ivec: IVec = metadata.into();
         assert_eq!(
@@ -303,5 +808,75 @@ mod tests {
         let metadata: SledMetadata = ivec.into();
         assert_eq!(metadata.atime, 233);
         assert_eq!(metadata.size, 0xaabbccdddeadbeef);
+        assert_eq!(metadata.headers, ResponseHeaders::default());
+    }
+
+    #[test]
+    fn sled_eviction_state() {
+        let state = SledEvictionState {
+            freq: 3,
+            visited: true,
+            order_key: vec![1, 2, 3],
+        };
+        let ivec: IVec = state.into();
+        assert_eq!(ivec, vec![0, 0, 0, 0, 0, 0, 0, 3, 1, 1, 2, 3]);
+        let state: SledEvictionState = ivec.into();
+        assert_eq!(state.freq, 3);
+        assert!(state.visited);
+        assert_eq!(state.order_key, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn sled_ttl_usage() {
+        let usage = SledTtlUsage {
+            size: 42,
+            atime: 233,
+        };
+        let ivec: IVec = usage.into();
+        assert_eq!(ivec.len(), 16);
+        let usage: SledTtlUsage = ivec.into();
+        assert_eq!(
+            usage,
+            SledTtlUsage {
+                size: 42,
+                atime: 233
+            }
+        );
+    }
+
+    #[test]
+    fn sled_ttl_metadata() {
+        let headers = ResponseHeaders::decode("etag: \"42\"\r\n");
+        let ivec: IVec = SledTtlMetadata {
+            expire_time: 1,
+            remove_time: 2,
+            headers: headers.clone(),
+        }
+        .into();
+        let metadata: SledTtlMetadata = ivec.into();
+        assert_eq!(metadata.expire_time, 1);
+        assert_eq!(metadata.remove_time, 2);
+        assert_eq!(metadata.headers, headers);
+
+        let legacy: IVec = 233i64.to_be_bytes().to_vec().into();
+        let metadata: SledTtlMetadata = legacy.into();
+        assert_eq!(metadata.expire_time, 233);
+        assert_eq!(metadata.remove_time, 233);
+        assert_eq!(metadata.headers, ResponseHeaders::default());
+    }
+
+    #[test]
+    fn sled_metadata_with_headers() {
+        let headers = ResponseHeaders::decode("etag: \"42\"\r\ncontent-type: text/plain\r\n");
+        let metadata = SledMetadata {
+            atime: 233,
+            size: 42,
+            headers: headers.clone(),
+        };
+        let ivec: IVec = metadata.into();
+        let metadata: SledMetadata = ivec.into();
+        assert_eq!(metadata.size, 42);
+        assert_eq!(metadata.headers, headers);
+        assert_eq!(metadata.headers.get("ETag"), Some("\"42\""));
     }
 }
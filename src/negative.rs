@@ -0,0 +1,127 @@
+//! Negative caching of responses telling that an object does not exist.
+//!
+//! Clients often probe URLs that do not exist upstream, e.g. index variants. The
+//! statuses of such responses are remembered in memory for a short time and
+//! replayed, instead of asking upstream again for every probe.
+use crate::error::{Error, Result};
+use crate::settings;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+
+/// Statuses which may be remembered
+const CACHEABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+    StatusCode::FORBIDDEN,
+];
+
+pub struct NegativeCache {
+    ttl: Duration,
+    statuses: Vec<StatusCode>,
+    max_entries: usize,
+    /// Key -> status and the time it expires
+    entries: Mutex<HashMap<String, (StatusCode, Instant)>>,
+}
+
+impl NegativeCache {
+    pub fn new(config: &settings::NegativeCache) -> Result<Self> {
+        let statuses = config
+            .statuses
+            .iter()
+            .map(|status| {
+                StatusCode::from_u16(*status)
+                    .ok()
+                    .filter(|status| CACHEABLE_STATUSES.contains(status))
+                    .ok_or_else(|| {
+                        Error::ConfigInvalid(format!(
+                            "status {} cannot be cached negatively, expecting 404, 410 or 403",
+                            status
+                        ))
+                    })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            ttl: Duration::from_secs(config.ttl),
+            statuses,
+            max_entries: config.max_entries,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The remembered status of the key if it has not expired
+    pub fn get(&self, key: &str) -> Option<StatusCode> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((status, expire)) if *expire > Instant::now() => Some(*status),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remember the status of an upstream response of the key.
+    /// Returns whether it is remembered, i.e. it is one of the configured statuses.
+    pub fn insert(&self, key: &str, status: StatusCode) -> bool {
+        if !self.statuses.contains(&status) || self.ttl.is_zero() {
+            return false;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(key) {
+            entries.retain(|_, (_, expire)| *expire > now);
+            if entries.len() >= self.max_entries {
+                // entries live shortly, so dropping all of them costs little
+                entries.clear();
+            }
+        }
+        entries.insert(key.to_string(), (status, now + self.ttl));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(ttl: u64, statuses: &[u16], max_entries: usize) -> settings::NegativeCache {
+        settings::NegativeCache {
+            ttl,
+            statuses: statuses.to_vec(),
+            max_entries,
+        }
+    }
+
+    #[test]
+    fn remember_statuses() {
+        let cache = NegativeCache::new(&config(60, &[404, 410], 2)).unwrap();
+        assert!(cache.insert("a", StatusCode::NOT_FOUND));
+        assert!(cache.insert("b", StatusCode::GONE));
+        assert!(!cache.insert("c", StatusCode::FORBIDDEN));
+        assert!(!cache.insert("c", StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!(cache.get("a"), Some(StatusCode::NOT_FOUND));
+        assert_eq!(cache.get("b"), Some(StatusCode::GONE));
+        assert_eq!(cache.get("c"), None);
+        // full of unexpired entries
+        assert!(cache.insert("c", StatusCode::NOT_FOUND));
+        assert_eq!(cache.get("c"), Some(StatusCode::NOT_FOUND));
+        assert!(cache.entries.lock().unwrap().len() <= 2);
+
+        let cache = NegativeCache::new(&config(0, &[403], 2)).unwrap();
+        assert!(!cache.insert("a", StatusCode::FORBIDDEN));
+        assert!(NegativeCache::new(&config(60, &[500], 2)).is_err());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = NegativeCache::new(&config(60, &[404], 10)).unwrap();
+        cache.insert("a", StatusCode::NOT_FOUND);
+        cache.entries.lock().unwrap().get_mut("a").unwrap().1 = Instant::now();
+        assert_eq!(cache.get("a"), None);
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,463 @@
+use crate::error::{Error, Result};
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Maximum number of range specs accepted in a `Range` header.
+/// Requests with more ranges are served in full.
+const MAX_RANGES: usize = 64;
+
+/// Maximum number of bytes read from a file at a time.
+const FILE_READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// An inclusive byte range: (first byte position, last byte position)
+pub type ByteRange = (u64, u64);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// The `Range` header is ignored, and the whole body is served
+    Full,
+    /// Sorted and non-overlapping ranges to serve
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlaps the body
+    Unsatisfiable,
+}
+
+/// Parse the value of a `Range` header against a body of `len` bytes.
+///
+/// Only the `bytes` unit is supported. A header with invalid syntax is ignored as
+/// suggested by RFC 7233. Overlapping and adjacent ranges are coalesced, and
+/// ranges are served in ascending order.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Full,
+    };
+    let mut ranges = Vec::new();
+    for (idx, spec) in specs.split(',').map(str::trim).enumerate() {
+        if idx >= MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        if spec.is_empty() {
+            continue;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some(pair) => pair,
+            None => return RangeRequest::Full,
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // suffix-byte-range-spec
+            match last.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some((len.saturating_sub(suffix), len.saturating_sub(1))),
+                Err(_) => return RangeRequest::Full,
+            }
+        } else {
+            let first = match first.parse::<u64>() {
+                Ok(first) => first,
+                Err(_) => return RangeRequest::Full,
+            };
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(last) if last >= first => last,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            Some((first, std::cmp::min(last, len.saturating_sub(1))))
+        };
+        if let Some((first, last)) = range {
+            if first < len {
+                ranges.push((first, last));
+            }
+        }
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    RangeRequest::Partial(merged)
+}
+
+pub fn content_range(range: ByteRange, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.0, range.1, len)
+}
+
+/// Describes a `multipart/byteranges` body
+pub struct Multipart {
+    pub boundary: String,
+    /// Content type of each part
+    pub content_type: String,
+    /// Length of the whole representation
+    pub len: u64,
+}
+
+impl Multipart {
+    fn part_header(&self, idx: usize, range: ByteRange) -> String {
+        format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            if idx == 0 { "" } else { "\r\n" },
+            self.boundary,
+            self.content_type,
+            content_range(range, self.len)
+        )
+    }
+
+    fn closing(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// The `Content-Type` header of the whole response
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Length of the multipart body for the given ranges
+    pub fn body_len(&self, ranges: &[ByteRange]) -> u64 {
+        ranges
+            .iter()
+            .enumerate()
+            .map(|(idx, range)| self.part_header(idx, *range).len() as u64 + range.1 - range.0 + 1)
+            .sum::<u64>()
+            + self.closing().len() as u64
+    }
+}
+
+struct Slicer<S> {
+    stream: S,
+    ranges: Vec<ByteRange>,
+    multipart: Option<Multipart>,
+    /// Index of the range being served
+    idx: usize,
+    part_started: bool,
+    /// Unconsumed bytes of the last chunk
+    leftover: Option<Bytes>,
+    /// Offset of the first byte of `leftover` or the next chunk
+    pos: u64,
+    finished: bool,
+}
+
+impl<S> Slicer<S>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            let (first, last) = match self.ranges.get(self.idx) {
+                Some(range) => *range,
+                None => {
+                    self.finished = true;
+                    return self
+                        .multipart
+                        .as_ref()
+                        .map(|multipart| Ok(multipart.closing().into()));
+                }
+            };
+            if let Some(multipart) = &self.multipart {
+                if !self.part_started {
+                    self.part_started = true;
+                    return Some(Ok(multipart.part_header(self.idx, (first, last)).into()));
+                }
+            }
+            if self.pos > last {
+                self.idx += 1;
+                self.part_started = false;
+                continue;
+            }
+            let chunk = match self.leftover.take() {
+                Some(chunk) => chunk,
+                None => match self.stream.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        self.finished = true;
+                        return Some(Err(e));
+                    }
+                    None => {
+                        self.finished = true;
+                        return Some(Err(Error::OtherError(format!(
+                            "body ended at byte {} before range {}-{}",
+                            self.pos, first, last
+                        ))));
+                    }
+                },
+            };
+            let chunk_start = self.pos;
+            let chunk_end = chunk_start + chunk.len() as u64;
+            if chunk_end <= first {
+                self.pos = chunk_end;
+                continue;
+            }
+            let from = (first.max(chunk_start) - chunk_start) as usize;
+            let to = ((last + 1).min(chunk_end) - chunk_start) as usize;
+            if to < chunk.len() {
+                self.leftover = Some(chunk.slice(to..));
+            }
+            self.pos = chunk_start + to as u64;
+            return Some(Ok(chunk.slice(from..to)));
+        }
+    }
+}
+
+/// Extract `ranges` from a body stream in a single pass.
+/// `ranges` must be sorted and non-overlapping, as returned by `parse_range`.
+/// If `multipart` is given, the ranges are wrapped in a `multipart/byteranges` body.
+pub fn slice_stream<S>(
+    stream: S,
+    ranges: Vec<ByteRange>,
+    multipart: Option<Multipart>,
+) -> impl Stream<Item = Result<Bytes>> + Send
+where
+    S: Stream<Item = Result<Bytes>> + Send + Unpin,
+{
+    let slicer = Slicer {
+        stream,
+        ranges,
+        multipart,
+        idx: 0,
+        part_started: false,
+        leftover: None,
+        pos: 0,
+        finished: false,
+    };
+    stream::unfold(slicer, |mut slicer| async move {
+        slicer.next_chunk().await.map(|item| (item, slicer))
+    })
+}
+
+struct FileSlicer {
+    file: File,
+    ranges: Vec<ByteRange>,
+    multipart: Option<Multipart>,
+    /// Index of the range being served
+    idx: usize,
+    /// Bytes left of the range being served, `None` before seeking to it
+    remaining: Option<u64>,
+    finished: bool,
+}
+
+impl FileSlicer {
+    async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            let (first, last) = match self.ranges.get(self.idx) {
+                Some(range) => *range,
+                None => {
+                    self.finished = true;
+                    return self
+                        .multipart
+                        .as_ref()
+                        .map(|multipart| Ok(multipart.closing().into()));
+                }
+            };
+            match self.remaining {
+                None => {
+                    if let Err(e) = self.file.seek(SeekFrom::Start(first)).await {
+                        self.finished = true;
+                        return Some(Err(e.into()));
+                    }
+                    self.remaining = Some(last - first + 1);
+                    if let Some(multipart) = &self.multipart {
+                        return Some(Ok(multipart.part_header(self.idx, (first, last)).into()));
+                    }
+                }
+                Some(0) => {
+                    self.idx += 1;
+                    self.remaining = None;
+                }
+                Some(remaining) => {
+                    let mut buf = vec![0; remaining.min(FILE_READ_CHUNK_SIZE) as usize];
+                    let n = match self.file.read(&mut buf).await {
+                        Ok(0) => {
+                            self.finished = true;
+                            return Some(Err(Error::OtherError(format!(
+                                "file ended at byte {} before range {}-{}",
+                                last + 1 - remaining,
+                                first,
+                                last
+                            ))));
+                        }
+                        Ok(n) => n,
+                        Err(e) => {
+                            self.finished = true;
+                            return Some(Err(e.into()));
+                        }
+                    };
+                    buf.truncate(n);
+                    self.remaining = Some(remaining - n as u64);
+                    return Some(Ok(buf.into()));
+                }
+            }
+        }
+    }
+}
+
+/// Extract `ranges` from a file, seeking to the start of each range instead of
+/// reading the bytes before it.
+/// `ranges` must be sorted and non-overlapping, as returned by `parse_range`.
+/// If `multipart` is given, the ranges are wrapped in a `multipart/byteranges` body.
+pub fn slice_file(
+    file: File,
+    ranges: Vec<ByteRange>,
+    multipart: Option<Multipart>,
+) -> impl Stream<Item = Result<Bytes>> + Send {
+    let slicer = FileSlicer {
+        file,
+        ranges,
+        multipart,
+        idx: 0,
+        remaining: None,
+        finished: false,
+    };
+    stream::unfold(slicer, |mut slicer| async move {
+        slicer.next_chunk().await.map(|item| (item, slicer))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunked(data: &'static [u8], size: usize) -> impl Stream<Item = Result<Bytes>> + Unpin {
+        stream::iter(
+            data.chunks(size)
+                .map(|c| Ok(Bytes::from_static(c)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    async fn collect(stream: impl Stream<Item = Result<Bytes>>) -> Vec<u8> {
+        stream.map(|x| x.unwrap().to_vec()).concat().await
+    }
+
+    #[test]
+    fn parse_single_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-4", 10),
+            RangeRequest::Partial(vec![(0, 4)])
+        );
+        assert_eq!(
+            parse_range("bytes=5-", 10),
+            RangeRequest::Partial(vec![(5, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=-3", 10),
+            RangeRequest::Partial(vec![(7, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=-30", 10),
+            RangeRequest::Partial(vec![(0, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=8-100", 10),
+            RangeRequest::Partial(vec![(8, 9)])
+        );
+    }
+
+    #[test]
+    fn parse_multiple_ranges() {
+        assert_eq!(
+            parse_range("bytes=6-7, 0-1,,1-2", 10),
+            RangeRequest::Partial(vec![(0, 2), (6, 7)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-1,2-3", 10),
+            RangeRequest::Partial(vec![(0, 3)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-1,20-30", 10),
+            RangeRequest::Partial(vec![(0, 1)])
+        );
+    }
+
+    #[test]
+    fn parse_unsatisfiable_and_invalid_ranges() {
+        assert_eq!(parse_range("bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 10), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=a-b", 10), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 10), RangeRequest::Full);
+        assert_eq!(parse_range("bytes 0-1", 10), RangeRequest::Full);
+    }
+
+    #[tokio::test]
+    async fn slice_single_range_across_chunks() {
+        let stream = slice_stream(chunked(b"0123456789", 3), vec![(2, 7)], None);
+        assert_eq!(collect(stream).await, b"234567");
+    }
+
+    #[tokio::test]
+    async fn slice_multipart_ranges() {
+        let multipart = Multipart {
+            boundary: "BOUNDARY".to_string(),
+            content_type: "text/plain".to_string(),
+            len: 10,
+        };
+        let ranges = vec![(0, 1), (4, 5), (9, 9)];
+        let body_len = multipart.body_len(&ranges);
+        let stream = slice_stream(chunked(b"0123456789", 4), ranges, Some(multipart));
+        let body = collect(stream).await;
+        let expected =
+            "--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+            \r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 4-5/10\r\n\r\n45\
+            \r\n--BOUNDARY\r\nContent-Type: text/plain\r\nContent-Range: bytes 9-9/10\r\n\r\n9\
+            \r\n--BOUNDARY--\r\n";
+        assert_eq!(std::str::from_utf8(&body).unwrap(), expected);
+        assert_eq!(body_len, expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn slice_truncated_body() {
+        let mut stream = Box::pin(slice_stream(chunked(b"0123", 2), vec![(2, 7)], None));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from("23"));
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn slice_file_ranges() {
+        let dir = std::path::Path::new("cache/test/range");
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join("slice_file_ranges");
+        std::fs::write(&path, "0123456789").unwrap();
+        let file = || async { File::open(&path).await.unwrap() };
+        let stream = slice_file(file().await, vec![(2, 7)], None);
+        assert_eq!(collect(stream).await, b"234567");
+        let multipart = || Multipart {
+            boundary: "BOUNDARY".to_string(),
+            content_type: "text/plain".to_string(),
+            len: 10,
+        };
+        let ranges = vec![(0, 1), (4, 5), (9, 9)];
+        let sliced = collect(slice_file(file().await, ranges.clone(), Some(multipart()))).await;
+        let streamed = collect(slice_stream(
+            chunked(b"0123456789", 4),
+            ranges,
+            Some(multipart()),
+        ))
+        .await;
+        assert_eq!(sliced, streamed);
+        // the file is shorter than the range
+        let mut stream = Box::pin(slice_file(file().await, vec![(8, 11)], None));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from("89"));
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,265 @@
+//! Resumption of interrupted upstream downloads.
+//!
+//! When the body of an upstream response breaks off, the rest of it is requested
+//! with `Range` from the last received byte. The request carries `If-Range` with
+//! the strong `ETag` of the original response, so that the upstream sends the
+//! whole new object instead if it has changed meanwhile, which is then given up.
+//! Without a strong `ETag`, the body is requested again from its first byte.
+//! Attempts are retried with exponential backoff.
+use crate::error::{Error, Result};
+use crate::inflight::Chunk;
+use crate::metric;
+use crate::settings::Retry;
+use crate::util;
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use metrics::increment_counter;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use reqwest::StatusCode;
+use std::pin::Pin;
+
+type Body = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+struct Resumable {
+    client: util::Client,
+    url: String,
+    /// Headers of the original request, except its validators
+    headers: HeaderMap,
+    config: Retry,
+    /// Strong validator of the original response, without which it is restarted
+    etag: Option<HeaderValue>,
+    /// Number of bytes received so far
+    received: u64,
+    /// Largest number of bytes received before any restart
+    reached: u64,
+    /// Number of attempts since bytes beyond `reached` were received
+    attempts: u32,
+    /// `None` once the body is over
+    body: Option<Body>,
+}
+
+/// The body of `res` from `url`, which is resumed if it breaks off. Requests
+/// to resume it carry `headers` too. The body yields `Chunk::Restart` if it
+/// starts over.
+pub fn resumable_body(
+    client: util::Client,
+    url: String,
+    res: reqwest::Response,
+    headers: HeaderMap,
+    config: Retry,
+) -> impl Stream<Item = Result<Chunk>> + Send {
+    let etag = res
+        .headers()
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .cloned();
+    let body = client.body_stream(res);
+    let state = Resumable {
+        client,
+        url,
+        headers,
+        config,
+        etag,
+        received: 0,
+        reached: 0,
+        attempts: 0,
+        body: Some(body),
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            match state.body.as_mut()?.next().await {
+                Some(Ok(bytes)) => {
+                    state.received += bytes.len() as u64;
+                    if state.received > state.reached {
+                        state.reached = state.received;
+                        state.attempts = 0;
+                    }
+                    return Some((Ok(Chunk::Data(bytes)), state));
+                }
+                Some(Err(e)) => match state.resume(e).await {
+                    Ok((body, false)) => state.body = Some(body),
+                    Ok((body, true)) => {
+                        state.body = Some(body);
+                        state.received = 0;
+                        return Some((Ok(Chunk::Restart), state));
+                    }
+                    Err(e) => {
+                        state.body = None;
+                        return Some((Err(e), state));
+                    }
+                },
+                None => return None,
+            }
+        }
+    })
+}
+
+impl Resumable {
+    /// Request the rest of the body after it failed with `e`, or the whole body
+    /// without a validator. Returns the body and whether it starts over.
+    async fn resume(&mut self, mut e: Error) -> Result<(Body, bool)> {
+        while self.attempts < self.config.max_retries {
+            let backoff = self.config.backoff(self.attempts);
+            self.attempts += 1;
+            warn!(
+                "download of {} broke off at {} bytes: {}, resuming in {} ms (attempt {})",
+                self.url,
+                self.received,
+                e,
+                backoff.as_millis(),
+                self.attempts
+            );
+            tokio::time::sleep(backoff).await;
+            increment_counter!(metric::CNT_DOWNLOAD_RETRIES);
+            let mut headers = self.headers.clone();
+            if let Some(etag) = &self.etag {
+                headers.insert(
+                    RANGE,
+                    HeaderValue::from_str(&format!("bytes={}-", self.received)).unwrap(),
+                );
+                headers.insert(IF_RANGE, etag.clone());
+            }
+            match self.client.request(&self.url, false, headers).await {
+                Ok(res)
+                    if self.etag.is_some()
+                        && res.status() == StatusCode::PARTIAL_CONTENT
+                        && content_range_start(res.headers()) == Some(self.received) =>
+                {
+                    info!(
+                        "resumed download of {} at {} bytes",
+                        self.url, self.received
+                    );
+                    return Ok((self.client.body_stream(res), false));
+                }
+                Ok(res) if self.etag.is_none() && res.status().is_success() => {
+                    info!("restarted download of {} without a validator", self.url);
+                    return Ok((self.client.body_stream(res), true));
+                }
+                Ok(res) if res.status().is_server_error() => {
+                    e = Error::UpstreamRequestError(Box::new(res));
+                }
+                Ok(res) => {
+                    // e.g. the object has changed, and is sent in full
+                    return Err(Error::OtherError(format!(
+                        "failed to resume download of {}: {}",
+                        self.url,
+                        res.status()
+                    )));
+                }
+                Err(err) => e = err,
+            }
+        }
+        Err(e)
+    }
+}
+
+/// The first byte position of the `Content-Range` of a partial response
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (unit, range) = value.trim().split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use warp::hyper::Body as HyperBody;
+    use warp::Filter;
+
+    const BODY: &str = "hello world";
+
+    /// Serve `BODY`, whose first `breaks` full responses break off after 6 bytes
+    fn serve(etag: &'static str, breaks: usize) -> String {
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let route = warp::header::optional::<String>("range").map(move |range: Option<String>| {
+            let builder = warp::http::Response::builder().header("ETag", etag);
+            match range {
+                Some(range) => {
+                    let start: usize = range["bytes=".len()..]
+                        .trim_end_matches('-')
+                        .parse()
+                        .unwrap();
+                    builder
+                        .status(206)
+                        .header(
+                            "Content-Range",
+                            format!("bytes {}-{}/{}", start, BODY.len() - 1, BODY.len()),
+                        )
+                        .body(HyperBody::from(&BODY[start..]))
+                        .unwrap()
+                }
+                None if full_responses.fetch_add(1, Ordering::SeqCst) >= breaks => builder
+                    .header("Content-Length", BODY.len())
+                    .body(HyperBody::from(BODY))
+                    .unwrap(),
+                None => {
+                    // break off after the head and the first chunk are sent
+                    let chunks = stream::iter(vec![Ok(&BODY[..6])]).chain(stream::once(async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Err(std::io::Error::other("reset"))
+                    }));
+                    builder
+                        .header("Content-Length", BODY.len())
+                        .body(HyperBody::wrap_stream(chunks))
+                        .unwrap()
+                }
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/file", addr)
+    }
+
+    async fn download(url: &str) -> Result<Vec<u8>> {
+        let client = util::Client::default();
+        let res = client.request(url, false, HeaderMap::new()).await?;
+        let config = Retry {
+            max_retries: 2,
+            initial_backoff: 1,
+            max_backoff: 10,
+        };
+        let mut body = Box::pin(resumable_body(
+            client,
+            url.to_string(),
+            res,
+            HeaderMap::new(),
+            config,
+        ));
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next().await {
+            match chunk? {
+                Chunk::Data(bytes) => data.extend_from_slice(&bytes),
+                Chunk::Restart => data.clear(),
+            }
+        }
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn resume_broken_download() {
+        let url = serve("\"v1\"", usize::MAX);
+        assert_eq!(download(&url).await.unwrap(), BODY.as_bytes());
+        // a weak validator may not guard a range request, the body starts over
+        let url = serve("W/\"v1\"", 1);
+        assert_eq!(download(&url).await.unwrap(), BODY.as_bytes());
+        // bytes received again do not renew the attempts
+        let url = serve("W/\"v1\"", usize::MAX);
+        assert!(download(&url).await.is_err());
+    }
+
+    #[test]
+    fn parse_content_range() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 6-10/11"));
+        assert_eq!(content_range_start(&headers), Some(6));
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */11"));
+        assert_eq!(content_range_start(&headers), None);
+    }
+}
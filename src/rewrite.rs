@@ -0,0 +1,394 @@
+//! Rewriting of upstream responses, applied incrementally over the byte stream.
+//!
+//! Rewrites of a rule are applied in order, each one to the output of the
+//! previous one. A rewrite replaces every match of its pattern. As the body is
+//! not buffered, a match is at most `MAX_MATCH_LEN` bytes long: longer matches
+//! that span chunks may be missed.
+//!
+//! Replacements may contain placeholders of the request origin, i.e. `{scheme}`,
+//! `{host}` and `{base_url}`. Rewritten content is cached with markers in place
+//! of the placeholders, and materialized for each request when it is served.
+use crate::error::{Error, Result};
+use crate::settings::Rewrite;
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use regex::bytes::Regex;
+use std::sync::Arc;
+use warp::http::HeaderMap;
+
+/// Maximum length of a match in bytes
+pub const MAX_MATCH_LEN: usize = 8 * 1024;
+
+/// Number of already rewritten bytes kept before the pending bytes, so that
+/// assertions like `\b` and `(?m)^` see the previous (UTF-8) character.
+const CONTEXT_LEN: usize = 4;
+
+/// Placeholders of the request origin, and the markers of them in cached content
+const PLACEHOLDERS: [(&str, &str); 3] = [
+    ("scheme", "\u{1f}mirror-cache:scheme\u{1f}"),
+    ("host", "\u{1f}mirror-cache:host\u{1f}"),
+    ("base_url", "\u{1f}mirror-cache:base_url\u{1f}"),
+];
+
+lazy_static::lazy_static! {
+    /// `{name}` but not `${name}`, which is a capture group of regex rewrites
+    static ref PLACEHOLDER: regex::Regex = regex::Regex::new(r"\$?\{(scheme|host|base_url)\}").unwrap();
+    static ref MARKERS: Vec<Regex> = PLACEHOLDERS
+        .iter()
+        .map(|(_, marker)| Regex::new(&regex::escape(marker)).unwrap())
+        .collect();
+}
+
+/// Origin of a request, as seen by the client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub scheme: String,
+    pub host: String,
+}
+
+impl Origin {
+    /// Resolve the origin from `X-Forwarded-Proto` and `X-Forwarded-Host` set by
+    /// a reverse proxy, or the `Host` header. The forwarded headers are only
+    /// honoured if the peer is a trusted proxy, i.e. `forwarded` is set. Without
+    /// `X-Forwarded-Proto`, the scheme is `https` if the request is received
+    /// over TLS.
+    pub fn from_headers(headers: &HeaderMap, tls: bool, forwarded: bool) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                // the first proxy is the closest to the client
+                .and_then(|value| value.split(',').next())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let forwarded_header = |name: &str| header(name).filter(|_| forwarded);
+        Self {
+            scheme: forwarded_header("x-forwarded-proto")
+                .unwrap_or_else(|| if tls { "https" } else { "http" }.to_string()),
+            host: forwarded_header("x-forwarded-host")
+                .or_else(|| header("host"))
+                .unwrap_or_else(|| "localhost".to_string()),
+        }
+    }
+
+    /// Create a rewriter that replaces the markers of placeholders with their
+    /// values for this origin. `{base_url}` is `public_base_url` if configured.
+    pub fn materializer(&self, public_base_url: Option<&str>) -> Rewriter {
+        let base_url = match public_base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!("{}://{}", self.scheme, self.host),
+        };
+        let values = [self.scheme.as_str(), self.host.as_str(), base_url.as_str()];
+        Rewriter {
+            rules: MARKERS
+                .iter()
+                .zip(values)
+                .map(|(marker, value)| (marker.clone(), value.replace('$', "$$")))
+                .collect(),
+            templated: false,
+        }
+    }
+}
+
+/// Compiled rewrites of a rule
+#[derive(Debug)]
+pub struct Rewriter {
+    rules: Vec<(Regex, String)>,
+    /// Whether any replacement contains placeholders
+    templated: bool,
+}
+
+impl Rewriter {
+    pub fn new(rewrites: &[Rewrite]) -> Result<Self> {
+        let rules = rewrites
+            .iter()
+            .map(|rewrite| {
+                let (pattern, replacement) = if rewrite.regex {
+                    (rewrite.from.clone(), rewrite.to.clone())
+                } else {
+                    (regex::escape(&rewrite.from), rewrite.to.replace('$', "$$"))
+                };
+                let regex = Regex::new(&pattern).map_err(|e| {
+                    Error::ConfigInvalid(format!("invalid rewrite {}: {}", rewrite.from, e))
+                })?;
+                if regex.is_match(b"") {
+                    return Err(Error::ConfigInvalid(format!(
+                        "rewrite {} matches the empty string",
+                        rewrite.from
+                    )));
+                }
+                Ok((regex, replace_placeholders(&replacement)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let templated = rewrites
+            .iter()
+            .any(|rewrite| replace_placeholders(&rewrite.to) != rewrite.to);
+        Ok(Self { rules, templated })
+    }
+
+    /// Whether the rewritten content must be materialized for each request
+    pub fn is_templated(&self) -> bool {
+        self.templated
+    }
+
+    /// Rewrite a whole document.
+    pub fn rewrite(&self, input: &[u8]) -> Vec<u8> {
+        self.start().feed(self, input, true)
+    }
+
+    /// Rewrite a stream chunk by chunk. The output stream ends after the first
+    /// error of the input stream.
+    pub fn rewrite_stream<S>(
+        self: Arc<Self>,
+        input: S,
+    ) -> impl Stream<Item = Result<Bytes>> + Send + Unpin
+    where
+        S: Stream<Item = Result<Bytes>> + Send + Unpin,
+    {
+        let state = self.start();
+        Box::pin(stream::unfold(
+            Some((input, state, self)),
+            |context| async move {
+                let (mut input, mut state, rewriter) = context?;
+                loop {
+                    match input.next().await {
+                        Some(Ok(chunk)) => {
+                            let output = state.feed(&rewriter, &chunk, false);
+                            if !output.is_empty() {
+                                return Some((Ok(output.into()), Some((input, state, rewriter))));
+                            }
+                        }
+                        Some(Err(e)) => return Some((Err(e), None)),
+                        None => return Some((Ok(state.feed(&rewriter, &[], true).into()), None)),
+                    }
+                }
+            },
+        ))
+    }
+
+    fn start(&self) -> RewriteState {
+        RewriteState {
+            stages: self.rules.iter().map(|_| Stage::default()).collect(),
+        }
+    }
+}
+
+/// Replace placeholders in `to` with their markers
+fn replace_placeholders(to: &str) -> String {
+    PLACEHOLDER
+        .replace_all(to, |caps: &regex::Captures| {
+            if caps[0].starts_with('$') {
+                return caps[0].to_string();
+            }
+            PLACEHOLDERS
+                .iter()
+                .find(|(name, _)| *name == &caps[1])
+                .map(|(_, marker)| marker.to_string())
+                .unwrap()
+        })
+        .into_owned()
+}
+
+struct RewriteState {
+    stages: Vec<Stage>,
+}
+
+impl RewriteState {
+    /// Feed `input` through all stages. Returns the bytes that are final.
+    fn feed(&mut self, rewriter: &Rewriter, input: &[u8], eof: bool) -> Vec<u8> {
+        let mut data = input.to_vec();
+        for (stage, (regex, replacement)) in self.stages.iter_mut().zip(&rewriter.rules) {
+            data = stage.feed(regex, replacement, &data, eof);
+        }
+        data
+    }
+}
+
+#[derive(Default)]
+struct Stage {
+    /// Context bytes followed by pending bytes
+    buf: Vec<u8>,
+    /// Length of context bytes at the start of `buf`
+    context: usize,
+}
+
+impl Stage {
+    fn feed(&mut self, regex: &Regex, replacement: &str, input: &[u8], eof: bool) -> Vec<u8> {
+        self.buf.extend_from_slice(input);
+        // a match ending after `hold` may change with more input
+        let hold = if eof {
+            self.buf.len()
+        } else {
+            self.buf.len().saturating_sub(MAX_MATCH_LEN)
+        };
+        let mut output = Vec::new();
+        let mut pos = self.context;
+        let commit = loop {
+            match regex.captures_at(&self.buf, pos) {
+                Some(caps) => {
+                    let m = caps.get(0).unwrap();
+                    if m.end() > hold {
+                        break m.start().min(hold).max(pos);
+                    }
+                    output.extend_from_slice(&self.buf[pos..m.start()]);
+                    caps.expand(replacement.as_bytes(), &mut output);
+                    pos = m.end();
+                }
+                None => break hold.max(pos),
+            }
+        };
+        output.extend_from_slice(&self.buf[pos..commit]);
+        let context = commit.min(CONTEXT_LEN);
+        self.buf.drain(..commit - context);
+        self.context = context;
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewrite(from: &str, to: &str, regex: bool) -> Rewrite {
+        Rewrite {
+            from: from.to_string(),
+            to: to.to_string(),
+            regex,
+        }
+    }
+
+    async fn rewrite_chunks(rewriter: Rewriter, chunks: Vec<&'static str>) -> String {
+        let input = stream::iter(chunks.into_iter().map(|x| Ok(Bytes::from(x))));
+        let output: Vec<Bytes> = Arc::new(rewriter)
+            .rewrite_stream(input)
+            .map(|x| x.unwrap())
+            .collect()
+            .await;
+        String::from_utf8(output.concat()).unwrap()
+    }
+
+    #[test]
+    fn plain_rewrites_apply_in_order() {
+        let rewriter = Rewriter::new(&[
+            rewrite("flower", "vegetable", false),
+            rewrite("vegetable", "$1 fruit", false),
+        ])
+        .unwrap();
+        assert_eq!(rewriter.rewrite(b"flower cat"), b"$1 fruit cat");
+    }
+
+    #[test]
+    fn regex_rewrites() {
+        let rewriter = Rewriter::new(&[rewrite(
+            r#"https://files\.pythonhosted\.org/packages/([0-9a-f]{2})/"#,
+            "/pypi/packages/$1/",
+            true,
+        )])
+        .unwrap();
+        assert_eq!(
+            rewriter.rewrite(b"<a href=\"https://files.pythonhosted.org/packages/ab/cd\">"),
+            b"<a href=\"/pypi/packages/ab/cd\">"
+        );
+        assert!(Rewriter::new(&[rewrite("(", "", true)]).is_err());
+        assert!(Rewriter::new(&[rewrite("a*", "", true)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn matches_across_chunks() {
+        let rewriter = Rewriter::new(&[
+            rewrite("https://upstream.example/", "http://localhost/", false),
+            rewrite(r"\bcat\b", "dog", true),
+        ])
+        .unwrap();
+        let output = rewrite_chunks(
+            rewriter,
+            vec![
+                "a https://ups",
+                "tream.ex",
+                "ample/b c",
+                "at concat ca",
+                "t",
+            ],
+        )
+        .await;
+        assert_eq!(output, "a http://localhost/b dog concat dog");
+    }
+
+    #[tokio::test]
+    async fn materialize_placeholders() {
+        let rewriter = Rewriter::new(&[
+            rewrite("https://upstream.example/", "{base_url}/mirror/", false),
+            rewrite(r"//(?P<host>[a-z.]+)/", "//{host}/${host}/", true),
+        ])
+        .unwrap();
+        assert!(rewriter.is_templated());
+        // the rewritten content is independent of requests
+        let cached = rewriter.rewrite(b"https://upstream.example/a //b.example/c");
+        assert!(!String::from_utf8_lossy(&cached).contains("localhost"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "localhost:9000".parse().unwrap());
+        let origin = Origin::from_headers(&headers, false, false);
+        assert_eq!(
+            origin.materializer(None).rewrite(&cached),
+            b"http://localhost:9000/mirror/a //localhost:9000/b.example/c"
+        );
+        let origin = Origin::from_headers(&headers, true, false);
+        assert_eq!(
+            origin.materializer(None).rewrite(&cached),
+            b"https://localhost:9000/mirror/a //localhost:9000/b.example/c"
+        );
+
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert(
+            "x-forwarded-host",
+            "mirrors.example, proxy.internal".parse().unwrap(),
+        );
+        // forwarded headers of untrusted peers are ignored
+        assert_eq!(
+            Origin::from_headers(&headers, false, false),
+            Origin {
+                scheme: "http".to_string(),
+                host: "localhost:9000".to_string()
+            }
+        );
+        let origin = Origin::from_headers(&headers, false, true);
+        assert_eq!(
+            origin,
+            Origin {
+                scheme: "https".to_string(),
+                host: "mirrors.example".to_string()
+            }
+        );
+        let materialized = rewrite_chunks(
+            origin.materializer(None),
+            vec![Box::leak(
+                String::from_utf8(cached.clone()).unwrap().into_boxed_str(),
+            )],
+        )
+        .await;
+        assert_eq!(
+            materialized,
+            "https://mirrors.example/mirror/a //mirrors.example/b.example/c"
+        );
+        assert_eq!(
+            origin
+                .materializer(Some("https://cdn.example/pub/"))
+                .rewrite(&cached),
+            b"https://cdn.example/pub/mirror/a //mirrors.example/b.example/c"
+        );
+        assert!(!Rewriter::new(&[rewrite("a", "b", false)])
+            .unwrap()
+            .is_templated());
+    }
+
+    #[tokio::test]
+    async fn long_stream() {
+        let rewriter = Rewriter::new(&[rewrite("ab+c", "x", true)]).unwrap();
+        let chunk: &'static str = Box::leak("-abbbc-".repeat(3000).into_boxed_str());
+        let output = rewrite_chunks(rewriter, vec![chunk, "ab", "bc", chunk]).await;
+        assert_eq!(output, "-x-".repeat(3000) + "x" + &"-x-".repeat(3000));
+    }
+}
@@ -1,13 +1,44 @@
 use crate::error::Error;
 use crate::error::Result;
 use config::{Config, Environment, File};
+use std::path::PathBuf;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
+    /// Port of the proxy on localhost if `listen` is not set
+    #[serde(default = "default_port")]
     pub port: u16,
+    /// Port of the metrics endpoint on localhost if `metrics_listen` is not set
+    #[serde(default = "default_metrics_port")]
     pub metrics_port: u16,
+    /// Addresses of the proxy, `host:port` or `unix:path`
+    pub listen: Option<Vec<String>>,
+    /// Addresses of the metrics endpoint, in the same format as `listen`
+    pub metrics_listen: Option<Vec<String>>,
+    /// Permissions of Unix domain sockets in octal, e.g. `660`
+    pub unix_socket_mode: Option<String>,
+    /// Certificates of `tls:` listen addresses
+    pub tls: Option<Tls>,
+    /// Port of the admin API, which is disabled if not set
+    pub admin_port: Option<u16>,
+    /// Public base URL of the mirror, used as `{base_url}` in rewrites instead
+    /// of the origin of each request
+    pub public_base_url: Option<String>,
+    /// Health checking of upstreams of rules with mirrors
+    pub upstream_health: Option<UpstreamHealth>,
+    /// Outbound HTTP client, which rules may override
+    pub http_client: Option<HttpClient>,
+    /// Retrying of interrupted upstream downloads
+    pub retry: Option<Retry>,
+    /// Access control of rules without their own
+    pub access: Option<Access>,
+    /// Addresses of reverse proxies whose `X-Forwarded-For` is trusted, in CIDR
+    /// notation or plain addresses
+    pub trusted_proxies: Option<Vec<String>>,
     redis: Redis,
     pub sled: Sled,
+    /// Directory of spool files of in-flight downloads
+    pub spool_dir: Option<String>,
     pub log_level: String,
     /// Whether to enable configuration file hot reloading
     pub hot_reload: Option<bool>,
@@ -16,11 +47,155 @@ pub struct Settings {
     pub storages: Vec<Storage>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tls {
+    /// The certificate is selected by the server name indicated by the client.
+    /// The first certificate without `server_names` is the default one.
+    pub certificates: Vec<TlsCertificate>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsCertificate {
+    /// Path to the PEM file of the certificate chain
+    pub cert: String,
+    /// Path to the PEM file of the private key
+    pub key: String,
+    /// Server names of the certificate, e.g. `mirrors.example.com` or `*.example.com`
+    pub server_names: Option<Vec<String>>,
+}
+
+fn default_port() -> u16 {
+    9000
+}
+
+fn default_metrics_port() -> u16 {
+    9001
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct Redis {
     url: String,
 }
 
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct HttpClient {
+    /// Seconds to wait for a connection
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for the response headers or the next chunk of the body
+    pub read_timeout: Option<u64>,
+    /// Maximum number of idle connections kept for each host
+    pub max_idle_connections: Option<usize>,
+    /// Maximum number of redirects to follow, `0` to disable redirects
+    pub max_redirects: Option<usize>,
+    /// URL of the proxy of all outbound requests
+    pub proxy: Option<String>,
+    /// Path to a PEM file of additional trusted CA certificates
+    pub ca_bundle: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl HttpClient {
+    /// Options set in `self` override those of `base`.
+    pub fn or(&self, base: &HttpClient) -> HttpClient {
+        HttpClient {
+            connect_timeout: self.connect_timeout.or(base.connect_timeout),
+            read_timeout: self.read_timeout.or(base.read_timeout),
+            max_idle_connections: self.max_idle_connections.or(base.max_idle_connections),
+            max_redirects: self.max_redirects.or(base.max_redirects),
+            proxy: self.proxy.clone().or_else(|| base.proxy.clone()),
+            ca_bundle: self.ca_bundle.clone().or_else(|| base.ca_bundle.clone()),
+            user_agent: self.user_agent.clone().or_else(|| base.user_agent.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamHealth {
+    /// Number of consecutive failures to mark an upstream unhealthy
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Seconds between probes of an unhealthy upstream
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval: u64,
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_probe_interval() -> u64 {
+    30
+}
+
+impl Default for UpstreamHealth {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_failure_threshold(),
+            probe_interval: default_probe_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Retry {
+    /// Maximum number of consecutive attempts to request upstream again or to
+    /// resume a download, `0` to disable
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Milliseconds to wait before the first attempt, doubled on each attempt
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// Maximum milliseconds to wait before an attempt
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff() -> u64 {
+    1000
+}
+
+fn default_max_backoff() -> u64 {
+    30000
+}
+
+impl Retry {
+    /// The time to wait before the attempt following `attempts` failed ones
+    pub fn backoff(&self, attempts: u32) -> std::time::Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << attempts.min(16))
+            .min(self.max_backoff);
+        std::time::Duration::from_millis(backoff)
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+/// Access control of requests. Clients must be in `allow` if it is set, and
+/// must present valid credentials if any are configured.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Access {
+    /// Path to an htpasswd file of users with bcrypt hashed passwords
+    pub basic_auth_file: Option<String>,
+    pub bearer_tokens: Option<Vec<String>>,
+    /// Path to a file of bearer tokens, one per line
+    pub bearer_tokens_file: Option<String>,
+    /// Allowed client addresses, in CIDR notation or plain addresses
+    pub allow: Option<Vec<String>>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Sled {
     pub metadata_path: String,
@@ -32,11 +207,47 @@ pub struct Rule {
     pub path: String,
     pub policy: String,
     pub upstream: String,
+    /// Other upstreams of the rule, in the same format as `upstream`
+    pub mirrors: Option<Vec<String>>,
+    /// How to pick one of `upstream` and `mirrors`, defaults to priority
+    pub upstream_order: Option<UpstreamOrder>,
+    /// Overrides options of the global `http_client`
+    pub http_client: Option<HttpClient>,
+    /// Headers of requests to upstream
+    pub headers: Option<Vec<RequestHeader>>,
+    /// Names of client request headers forwarded to upstream
+    pub forward_headers: Option<Vec<String>>,
+    /// Overrides the global `access`
+    pub access: Option<Access>,
     pub size_limit: Option<String>,
     pub rewrite: Option<Vec<Rewrite>>,
     pub options: Option<Options>,
 }
 
+/// A header of requests to upstream. Its value may contain a secret read from an
+/// environment variable or a file, which replaces `{secret}` in `value`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RequestHeader {
+    pub name: String,
+    /// Defaults to `{secret}`
+    pub value: Option<String>,
+    /// Name of the environment variable of the secret
+    pub env: Option<String>,
+    /// Path to the file of the secret, without the trailing newline
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+pub enum UpstreamOrder {
+    /// Try `upstream` first, then `mirrors` in order
+    #[default]
+    #[serde(rename = "priority")]
+    Priority,
+    /// Start from the next upstream for each request
+    #[serde(rename = "round-robin")]
+    RoundRobin,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Policy {
     pub name: String,
@@ -44,15 +255,54 @@ pub struct Policy {
     pub typ: PolicyType,
     pub metadata_db: MetadataDb,
     pub timeout: Option<u64>,
+    /// Seconds to retain expired entries for revalidation, defaults to `timeout`
+    pub revalidate_window: Option<u64>,
+    /// Seconds after expiration to serve entries while they are refreshed
+    pub stale_while_revalidate: Option<u64>,
+    /// Seconds after expiration to serve entries if the upstream fetch fails
+    pub stale_if_error: Option<u64>,
+    /// HTTP: bounds of the TTL derived from upstream headers, in seconds
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
     pub size: Option<String>,
     pub clean_interval: Option<u64>,
     pub storage: String,
+    /// Remembering of upstream responses that the object does not exist
+    pub negative_cache: Option<NegativeCache>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NegativeCache {
+    /// Seconds to replay the status of a response
+    #[serde(default = "default_negative_ttl")]
+    pub ttl: u64,
+    /// Statuses of responses to remember, among `404`, `410` and `403`
+    #[serde(default = "default_negative_statuses")]
+    pub statuses: Vec<u16>,
+    /// Maximum number of remembered responses
+    #[serde(default = "default_negative_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_negative_ttl() -> u64 {
+    60
+}
+
+fn default_negative_statuses() -> Vec<u16> {
+    vec![404, 410]
+}
+
+fn default_negative_max_entries() -> usize {
+    10000
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Rewrite {
     pub from: String,
     pub to: String,
+    /// `from` is a regex, and `to` may refer to its capture groups as `$1` or `${name}`
+    #[serde(default)]
+    pub regex: bool,
 }
 
 /// Options for rules
@@ -60,6 +310,39 @@ pub struct Rewrite {
 pub struct Options {
     /// Override the content-type in the HTTP response header
     pub content_type: Option<String>,
+    /// Learn the digests of artifacts listed in responses of the rule, which are
+    /// verified before the artifacts are cached
+    pub digests: Option<DigestSource>,
+    /// Maximum size of an index to learn digests from, as it is parsed in memory
+    pub max_index_size: Option<String>,
+}
+
+/// Default maximum size of an index to learn digests from
+const DEFAULT_MAX_INDEX_SIZE: u64 = 64 * 1024 * 1024;
+
+impl Options {
+    pub fn get_max_index_size(&self) -> Result<u64> {
+        match &self.max_index_size {
+            Some(size) => bytefmt::parse(size).map_err(|e| {
+                Error::ConfigInvalid(format!("invalid max_index_size {}: {}", size, e))
+            }),
+            None => Ok(DEFAULT_MAX_INDEX_SIZE),
+        }
+    }
+}
+
+/// Format of an index that lists digests of artifacts
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum DigestSource {
+    /// PyPI simple index pages, with `#sha256=` fragments in links
+    #[serde(rename = "pypi")]
+    PyPI,
+    /// conda `repodata.json`
+    #[serde(rename = "conda")]
+    Conda,
+    /// apt `Packages` files
+    #[serde(rename = "apt")]
+    Apt,
 }
 
 #[derive(Debug, Deserialize, Copy, Clone)]
@@ -68,6 +351,14 @@ pub enum PolicyType {
     Lru,
     #[serde(rename = "TTL")]
     Ttl,
+    #[serde(rename = "LFU")]
+    Lfu,
+    #[serde(rename = "SIEVE")]
+    Sieve,
+    #[serde(rename = "TTL_LRU")]
+    TtlLru,
+    #[serde(rename = "HTTP")]
+    Http,
 }
 
 #[derive(Debug, Deserialize, Copy, Clone)]
@@ -86,21 +377,40 @@ pub struct Storage {
 
 #[derive(Debug, Deserialize, Clone)]
 pub enum StorageConfig {
-    Fs { path: String },
+    Fs {
+        path: String,
+    },
     Mem,
+    /// Deduplicating filesystem storage, see `storage::Storage::ContentAddressed`
+    #[serde(rename = "Cas")]
+    ContentAddressed {
+        path: String,
+    },
 }
 
 impl Settings {
     pub fn default() -> Self {
         Settings {
-            port: 9000,
-            metrics_port: 9001,
+            port: default_port(),
+            metrics_port: default_metrics_port(),
+            listen: None,
+            metrics_listen: None,
+            unix_socket_mode: None,
+            tls: None,
+            admin_port: None,
+            public_base_url: None,
+            upstream_health: None,
+            http_client: None,
+            retry: None,
+            access: None,
+            trusted_proxies: None,
             redis: Redis {
                 url: "redis://localhost".to_string(),
             },
             sled: Sled {
                 metadata_path: "sled/metadata".to_string(),
             },
+            spool_dir: None,
             log_level: "info".to_string(),
             hot_reload: Some(false),
             rules: vec![],
@@ -132,6 +442,47 @@ impl Settings {
         }
     }
 
+    /// The spool directory defaults to `mirror-cache` in the system temporary directory.
+    pub fn get_spool_dir(&self) -> PathBuf {
+        self.spool_dir
+            .as_ref()
+            .map_or_else(|| std::env::temp_dir().join("mirror-cache"), PathBuf::from)
+    }
+
+    pub fn get_listen(&self) -> Vec<String> {
+        self.listen
+            .clone()
+            .unwrap_or_else(|| vec![format!("127.0.0.1:{}", self.port)])
+    }
+
+    pub fn get_metrics_listen(&self) -> Vec<String> {
+        self.metrics_listen
+            .clone()
+            .unwrap_or_else(|| vec![format!("127.0.0.1:{}", self.metrics_port)])
+    }
+
+    pub fn get_unix_socket_mode(&self) -> Result<Option<u32>> {
+        self.unix_socket_mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| {
+                        Error::ConfigInvalid(format!("invalid unix_socket_mode: {}", mode))
+                    })
+            })
+            .transpose()
+    }
+
+    pub fn get_upstream_health(&self) -> UpstreamHealth {
+        self.upstream_health.clone().unwrap_or_default()
+    }
+
+    pub fn get_retry(&self) -> Retry {
+        self.retry.clone().unwrap_or_default()
+    }
+
     pub fn get_redis_url(&self) -> String {
         self.redis.url.clone()
     }
@@ -167,6 +518,12 @@ mod test {
                 path: "".into(),
                 policy: "".into(),
                 upstream: "".into(),
+                mirrors: None,
+                upstream_order: None,
+                http_client: None,
+                headers: None,
+                forward_headers: None,
+                access: None,
                 size_limit: None,
                 rewrite: None,
                 options: None,
@@ -174,6 +531,29 @@ mod test {
         };
     }
 
+    #[test]
+    fn http_client_override() {
+        let base = HttpClient {
+            connect_timeout: Some(10),
+            proxy: Some("http://proxy:3128".into()),
+            ..Default::default()
+        };
+        let rule = HttpClient {
+            connect_timeout: Some(3),
+            user_agent: Some("pip".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rule.or(&base),
+            HttpClient {
+                connect_timeout: Some(3),
+                proxy: Some("http://proxy:3128".into()),
+                user_agent: Some("pip".into()),
+                ..Default::default()
+            }
+        );
+    }
+
     #[test]
     fn get_rule_label_test() {
         let rule = new_rule!(Some("awesome".into()));
@@ -1,19 +1,39 @@
 use crate::cache::CacheData;
 use crate::error::{Error, Result};
+use crate::settings::StorageConfig;
+use crate::util;
 
 use bytes::Bytes;
 use futures::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use sled::transaction::{TransactionResult, Transactional};
 use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fs;
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 use std::vec::Vec;
-use tokio::{fs::OpenOptions, io::BufReader, sync::RwLock};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::RwLock,
+};
 use tokio_util::codec;
 
+/// An object in a storage
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredObject {
+    pub name: String,
+    pub size: u64,
+    /// Last modification time in seconds since the epoch
+    pub mtime: i64,
+}
+
 /// Storage is an abstraction over a persistent storage.
 /// - FileSystem: local filesystem
+/// - ContentAddressed: local filesystem, where identical objects are stored once
 #[derive(Clone)]
 pub enum Storage {
     FileSystem {
@@ -22,6 +42,19 @@ pub enum Storage {
     Memory {
         map: Arc<RwLock<HashMap<String, Vec<u8>>>>,
     },
+    /// Objects are stored as blobs named by their SHA-256 digest under `blobs/`.
+    /// The index database under `index/` maps object names to digests, and counts
+    /// the references to each blob. A blob is removed with its last reference.
+    ContentAddressed {
+        root_dir: String,
+        /// name -> digest
+        names: sled::Tree,
+        /// digest -> number of names referring to it, in big endian u64
+        refs: sled::Tree,
+        db: sled::Db,
+        /// Serializes changes to the blobs and their reference counts
+        lock: Arc<tokio::sync::Mutex<()>>,
+    },
 }
 
 impl Storage {
@@ -30,16 +63,7 @@ impl Storage {
             Storage::FileSystem { root_dir, .. } => {
                 let mut path = PathBuf::from(root_dir);
                 path.push(name);
-                match fs::metadata(&path) {
-                    Ok(metadata) => {
-                        let len = metadata.len(); // actually we don't need to know the size here
-                        match get_file_stream(&path).await {
-                            Ok(stream) => Ok(CacheData::ByteStream(Box::new(stream), Some(len))),
-                            Err(e) => Err(e),
-                        }
-                    }
-                    Err(e) => Err(Error::IoError(e)),
-                }
+                open_file(&path).await
             }
             Storage::Memory { map, .. } => map.read().await.get(name).map_or(
                 Err(Error::IoError(std::io::Error::new(
@@ -48,20 +72,63 @@ impl Storage {
                 ))),
                 |x| Ok(x.clone().into()),
             ),
+            Storage::ContentAddressed {
+                root_dir, names, ..
+            } => open_file(&blob_path(root_dir, &cas_digest(names, name)?)).await,
         }
     }
 
-    pub async fn persist(&self, name: &str, mut data: CacheData) {
+    /// Persist an object. Objects in the filesystem are replaced atomically, so
+    /// that readers never see a partially written object.
+    pub async fn persist(&self, name: &str, mut data: CacheData) -> Result<()> {
         match self {
             Storage::FileSystem { root_dir, .. } => fs_persist(root_dir, name, &mut data).await,
+            Storage::ContentAddressed {
+                root_dir,
+                names,
+                refs,
+                db,
+                lock,
+            } => {
+                let (digest, _guard) = cas_write(root_dir, &mut data, lock).await?;
+                let (_, unreferenced) = cas_update(names, refs, name, Some(&digest))?;
+                // make the index durable as the blob
+                db.flush_async().await.map_err(Error::SledError)?;
+                cas_release(root_dir, unreferenced)
+            }
             Storage::Memory { ref map, .. } => {
                 map.write()
                     .await
                     .insert(name.to_string(), data.into_vec_u8().await);
+                Ok(())
             }
         }
     }
 
+    /// Remove temp files left by interrupted writes, and blobs of content-addressed
+    /// storages that are not referred to. Returns the number of removed files.
+    /// This must not run concurrently with writes.
+    pub fn cleanup_temp_files(&self) -> Result<usize> {
+        match self {
+            Storage::FileSystem { ref root_dir, .. } => {
+                fs_cleanup(&PathBuf::from(root_dir), is_temp_file)
+            }
+            Storage::ContentAddressed {
+                ref root_dir,
+                ref refs,
+                ..
+            } => fs_cleanup(&Path::new(root_dir).join(BLOBS_DIR), |path| {
+                is_temp_file(path)
+                    || path.file_name().is_some_and(|digest| {
+                        !refs
+                            .contains_key(digest.to_string_lossy().as_bytes())
+                            .unwrap_or(true)
+                    })
+            }),
+            Storage::Memory { .. } => Ok(0),
+        }
+    }
+
     pub async fn remove(&self, name: &str) -> Result<()> {
         match self {
             Storage::FileSystem { ref root_dir, .. } => {
@@ -73,38 +140,430 @@ impl Storage {
                 map.write().await.remove(name);
                 Ok(())
             }
+            Storage::ContentAddressed {
+                root_dir,
+                names,
+                refs,
+                db,
+                lock,
+            } => {
+                let _guard = lock.lock().await;
+                match cas_update(names, refs, name, None)? {
+                    (Some(_), unreferenced) => {
+                        db.flush_async().await.map_err(Error::SledError)?;
+                        cas_release(root_dir, unreferenced)
+                    }
+                    (None, _) => Err(not_found(name)),
+                }
+            }
+        }
+    }
+    /// Size of the stored data in bytes, without reading it.
+    pub async fn size(&self, name: &str) -> Result<u64> {
+        match self {
+            Storage::FileSystem { ref root_dir, .. } => {
+                let mut path = PathBuf::from(root_dir);
+                path.push(name);
+                Ok(fs::metadata(path)?.len())
+            }
+            Storage::Memory { map, .. } => map.read().await.get(name).map_or(
+                Err(Error::IoError(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "No such key.",
+                ))),
+                |x| Ok(x.len() as u64),
+            ),
+            Storage::ContentAddressed {
+                root_dir, names, ..
+            } => Ok(fs::metadata(blob_path(root_dir, &cas_digest(names, name)?))?.len()),
+        }
+    }
+
+    /// List all objects in the storage. Directories in `skip_dirs` are not walked,
+    /// e.g. the metadata database placed under the root directory.
+    /// Content-addressed storages list the objects in their index, whose blobs
+    /// may be shared.
+    pub async fn list(&self, skip_dirs: &[PathBuf]) -> Result<Vec<StoredObject>> {
+        match self {
+            Storage::FileSystem { ref root_dir, .. } => {
+                let root = PathBuf::from(root_dir);
+                let skip_dirs = skip_dirs.to_vec();
+                // the walk may take long on a large storage
+                spawn_blocking(move || {
+                    let mut objects = Vec::new();
+                    if !root.exists() {
+                        return Ok(objects);
+                    }
+                    let skip_dirs: Vec<PathBuf> = skip_dirs
+                        .iter()
+                        .filter_map(|dir| dir.canonicalize().ok())
+                        .collect();
+                    fs_walk(&root, &skip_dirs, &mut |path, metadata| {
+                        if is_temp_file(&path) {
+                            return Ok(());
+                        }
+                        let name = path
+                            .strip_prefix(&root)
+                            .unwrap()
+                            .components()
+                            .map(|c| c.as_os_str().to_string_lossy())
+                            .collect::<Vec<_>>()
+                            .join("/");
+                        objects.push(StoredObject {
+                            name,
+                            size: metadata.len(),
+                            mtime: mtime(&metadata)?,
+                        });
+                        Ok(())
+                    })?;
+                    Ok(objects)
+                })
+                .await
+            }
+            Storage::Memory { map, .. } => {
+                let mtime = util::now();
+                Ok(map
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(name, data)| StoredObject {
+                        name: name.clone(),
+                        size: data.len() as u64,
+                        mtime,
+                    })
+                    .collect())
+            }
+            Storage::ContentAddressed {
+                root_dir, names, ..
+            } => {
+                let (root_dir, names) = (root_dir.clone(), names.clone());
+                spawn_blocking(move || {
+                    let mut objects = Vec::new();
+                    for entry in names.iter() {
+                        let (name, digest) = entry.map_err(Error::SledError)?;
+                        let digest = String::from_utf8_lossy(&digest);
+                        // the blob of a name is missing if it was removed manually
+                        if let Ok(metadata) = fs::metadata(blob_path(&root_dir, &digest)) {
+                            objects.push(StoredObject {
+                                name: String::from_utf8_lossy(&name).into_owned(),
+                                size: metadata.len(),
+                                mtime: mtime(&metadata)?,
+                            });
+                        }
+                    }
+                    Ok(objects)
+                })
+                .await
+            }
         }
     }
+
     pub fn new_mem() -> Self {
         Storage::Memory {
             map: Arc::new(RwLock::new(HashMap::new())),
         }
     }
+
+    pub fn new_content_addressed(root_dir: &str) -> Result<Self> {
+        let db = sled::open(Path::new(root_dir).join(INDEX_DIR)).map_err(Error::SledError)?;
+        Ok(Storage::ContentAddressed {
+            root_dir: root_dir.to_string(),
+            names: db.open_tree("names").map_err(Error::SledError)?,
+            refs: db.open_tree("refs").map_err(Error::SledError)?,
+            db,
+            lock: Arc::new(tokio::sync::Mutex::new(())),
+        })
+    }
+
+    /// Whether the storage is created from `config`, so that it can be kept on
+    /// configuration reloads.
+    pub fn is_created_from(&self, config: &StorageConfig) -> bool {
+        match (self, config) {
+            (
+                Storage::ContentAddressed { root_dir, .. },
+                StorageConfig::ContentAddressed { path },
+            ) => root_dir == path,
+            _ => false,
+        }
+    }
+}
+
+/// Directory of blobs in content-addressed storages
+const BLOBS_DIR: &str = "blobs";
+/// Directory of the index database in content-addressed storages
+const INDEX_DIR: &str = "index";
+
+fn not_found(name: &str) -> Error {
+    Error::IoError(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No such object: {}", name),
+    ))
+}
+
+fn mtime(metadata: &fs::Metadata) -> Result<i64> {
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64))
+}
+
+/// Path of a blob, which is sharded by the first two hex digits of its digest
+fn blob_path(root_dir: &str, digest: &str) -> PathBuf {
+    let mut path = PathBuf::from(root_dir);
+    path.push(BLOBS_DIR);
+    path.push(digest.get(..2).unwrap_or_default());
+    path.push(digest);
+    path
+}
+
+fn cas_digest(names: &sled::Tree, name: &str) -> Result<String> {
+    match names.get(name).map_err(Error::SledError)? {
+        Some(digest) => Ok(String::from_utf8_lossy(&digest).into_owned()),
+        None => Err(not_found(name)),
+    }
+}
+
+/// Write an object to a temp file while hashing it, then move it to its blob
+/// unless an identical blob exists. Returns the digest, and the lock guard that
+/// must be held until the blob is referred to.
+async fn cas_write<'a>(
+    root_dir: &str,
+    data: &mut CacheData,
+    lock: &'a tokio::sync::Mutex<()>,
+) -> Result<(String, tokio::sync::MutexGuard<'a, ()>)> {
+    let blobs_dir = Path::new(root_dir).join(BLOBS_DIR);
+    fs::create_dir_all(&blobs_dir)?;
+    let temp_path = temp_path(&blobs_dir.join("blob"));
+    let mut hasher = Sha256::new();
+    if let Err(e) = fs_write(&temp_path, data, &mut |bytes| hasher.update(bytes)).await {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    let digest = format!("{:x}", hasher.finalize());
+    let path = blob_path(root_dir, &digest);
+
+    let guard = lock.lock().await;
+    let result = if path.exists() {
+        fs::remove_file(&temp_path).map_err(|e| e.into())
+    } else {
+        let parent_dir = path.parent().unwrap();
+        fs::create_dir_all(parent_dir)
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .and_then(|_| fs::File::open(parent_dir)?.sync_all())
+            .map_err(|e| e.into())
+    };
+    match result {
+        Ok(_) => Ok((digest, guard)),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
 }
 
-async fn fs_persist(root_dir: &str, name: &str, data: &mut CacheData) {
+/// Point `name` to `digest`, or remove it if `digest` is `None`, and update the
+/// reference counts. Returns the previous digest of `name`, and the digest of
+/// the blob that is no longer referred to.
+fn cas_update(
+    names: &sled::Tree,
+    refs: &sled::Tree,
+    name: &str,
+    digest: Option<&str>,
+) -> Result<(Option<String>, Option<String>)> {
+    let result: TransactionResult<_, ()> = (names, refs).transaction(|(names, refs)| {
+        let previous = match digest {
+            Some(digest) => names.insert(name, digest)?,
+            None => names.remove(name)?,
+        }
+        .map(|previous| String::from_utf8_lossy(&previous).into_owned());
+        if previous.as_deref() == digest {
+            return Ok((previous, None));
+        }
+        if let Some(digest) = digest {
+            let count = refs.get(digest)?.map_or(0, |count| decode_count(&count));
+            refs.insert(digest, &(count + 1).to_be_bytes())?;
+        }
+        let mut unreferenced = None;
+        if let Some(previous) = &previous {
+            match refs
+                .get(previous.as_str())?
+                .map_or(0, |count| decode_count(&count))
+            {
+                0 | 1 => {
+                    refs.remove(previous.as_str())?;
+                    unreferenced = Some(previous.clone());
+                }
+                count => {
+                    refs.insert(previous.as_str(), &(count - 1).to_be_bytes())?;
+                }
+            }
+        }
+        Ok((previous, unreferenced))
+    });
+    result.map_err(|e| Error::OtherError(format!("failed to update index: {:?}", e)))
+}
+
+fn decode_count(count: &[u8]) -> u64 {
+    count.try_into().map_or(0, u64::from_be_bytes)
+}
+
+/// Remove the blob of `digest` once it is no longer referred to.
+fn cas_release(root_dir: &str, digest: Option<String>) -> Result<()> {
+    if let Some(digest) = digest {
+        match fs::remove_file(blob_path(root_dir, &digest)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Remove files under `dir` that satisfy `predicate`. Returns the number of
+/// removed files.
+fn fs_cleanup(dir: &Path, predicate: impl Fn(&Path) -> bool) -> Result<usize> {
+    let mut removed = 0;
+    if !dir.exists() {
+        return Ok(removed);
+    }
+    fs_walk(dir, &[], &mut |path, _| {
+        if predicate(&path) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+        Ok(())
+    })?;
+    Ok(removed)
+}
+
+/// Maximum number of bytes read from a stored file at a time when it is copied
+const FILE_READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Suffix of temp files, which are renamed to objects once they are written
+const TEMP_SUFFIX: &str = ".mirror-cache-tmp";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Create a unique temp file path in the same directory as `path`, so that it
+/// can be renamed to `path` atomically.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        file_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_SUFFIX
+    ))
+}
+
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TEMP_SUFFIX))
+}
+
+async fn fs_persist(root_dir: &str, name: &str, data: &mut CacheData) -> Result<()> {
     let mut path = PathBuf::from(root_dir);
     path.push(name);
-    let parent_dirs = path.as_path().parent().unwrap();
-    fs::create_dir_all(parent_dirs).unwrap();
-    let mut f = fs::File::create(path).unwrap();
+    let parent_dir = path
+        .parent()
+        .ok_or_else(|| Error::OtherError(format!("invalid object name: {}", name)))?;
+    tokio::fs::create_dir_all(parent_dir).await?;
+    let temp_path = temp_path(&path);
+    let result = match fs_write(&temp_path, data, &mut |_| {}).await {
+        Ok(_) => tokio::fs::rename(&temp_path, &path)
+            .await
+            .map_err(|e| e.into()),
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    // make the rename durable
+    if let Ok(dir) = tokio::fs::File::open(parent_dir).await {
+        let _ = dir.sync_all().await;
+    }
+    result
+}
+
+/// Write `data` to `path`. `inspect` is called with every written chunk.
+async fn fs_write(
+    path: &Path,
+    data: &mut CacheData,
+    inspect: &mut (dyn FnMut(&[u8]) + Send),
+) -> Result<()> {
+    let mut f = tokio::fs::File::create(path).await?;
     match data {
         CacheData::ByteStream(stream, ..) => {
             while let Some(v) = stream.next().await {
-                f.write_all(v.unwrap().as_ref()).unwrap()
+                let v = v?;
+                inspect(&v);
+                f.write_all(v.as_ref()).await?
+            }
+        }
+        CacheData::File(file, _) => {
+            let mut buf = vec![0; FILE_READ_CHUNK_SIZE];
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                inspect(&buf[..n]);
+                f.write_all(&buf[..n]).await?
+            }
+        }
+        _ => {
+            inspect(data.as_ref());
+            f.write_all(data.as_ref()).await?
+        }
+    }
+    f.sync_all().await?;
+    Ok(())
+}
+
+/// Run blocking filesystem operations on the blocking thread pool.
+async fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Error::OtherError(format!("blocking task failed: {}", e)))?
+}
+
+/// Call `f` with the path and metadata of every file under `dir`.
+/// Directories in `skip_dirs` are not walked.
+fn fs_walk(
+    dir: &Path,
+    skip_dirs: &[PathBuf],
+    f: &mut dyn FnMut(PathBuf, fs::Metadata) -> Result<()>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !skip_dirs.contains(&path.canonicalize()?) {
+                fs_walk(&path, skip_dirs, f)?;
             }
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            f(path, metadata)?;
         }
-        _ => f.write_all(data.as_ref()).unwrap(),
     }
+    Ok(())
 }
 
-pub async fn get_file_stream(path: &Path) -> Result<impl Stream<Item = Result<Bytes>>> {
+/// Open a stored file, which is served from any offset for range requests.
+async fn open_file(path: &Path) -> Result<CacheData> {
     let f = OpenOptions::default().read(true).open(path).await?;
+    let len = f.metadata().await?.len();
+    Ok(CacheData::File(f, len))
+}
+
+pub fn file_stream(f: tokio::fs::File) -> impl Stream<Item = Result<Bytes>> {
     let f = BufReader::new(f);
-    let stream = codec::FramedRead::new(f, codec::BytesCodec::new())
+    codec::FramedRead::new(f, codec::BytesCodec::new())
         .map_ok(|bytes| bytes.freeze())
-        .map_err(|e| e.into());
-    Ok(stream)
+        .map_err(|e| e.into())
 }
 
 #[cfg(test)]
@@ -114,16 +573,24 @@ mod test {
     async fn write_read(storage: &mut Storage) {
         let name = "write_read_test";
         let data = "Metaphysics includes cosmosology and ontology.";
-        storage.persist(name, String::from(data).into()).await;
+        storage
+            .persist(name, String::from(data).into())
+            .await
+            .unwrap();
         let data_read: Vec<u8> = storage.read(name).await.unwrap().into_vec_u8().await;
         assert_eq!(data.as_bytes().to_vec(), data_read);
+        assert_eq!(storage.size(name).await.unwrap(), data.len() as u64);
     }
 
     async fn remove(storage: &mut Storage) {
         let name = "remove_test";
-        storage.persist(name, String::from("wow").into()).await;
+        storage
+            .persist(name, String::from("wow").into())
+            .await
+            .unwrap();
         storage.remove(name).await.unwrap();
         assert!(storage.read(name).await.is_err());
+        assert!(storage.size(name).await.is_err());
     }
 
     #[tokio::test]
@@ -142,6 +609,120 @@ mod test {
         remove(&mut storage).await;
     }
 
+    #[tokio::test]
+    async fn test_fs_list() {
+        let storage = Storage::FileSystem {
+            root_dir: "cache/test_fs_list".to_string(),
+        };
+        storage.persist("a", vec![1].into()).await.unwrap();
+        storage.persist("b/c", vec![2, 3].into()).await.unwrap();
+        storage.persist("skip/d", vec![4].into()).await.unwrap();
+        let mut objects = storage
+            .list(&[PathBuf::from("cache/test_fs_list/skip")])
+            .await
+            .unwrap();
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = objects.iter().map(|o| (o.name.as_str(), o.size)).collect();
+        assert_eq!(names, vec![("a", 1), ("b/c", 2)]);
+        assert!(objects[0].mtime > 0);
+    }
+
+    #[tokio::test]
+    async fn test_fs_persist_failure() {
+        let root_dir = "cache/test_fs_persist_failure";
+        let _ = fs::remove_dir_all(root_dir);
+        let storage = Storage::FileSystem {
+            root_dir: root_dir.to_string(),
+        };
+        storage.persist("a", vec![1].into()).await.unwrap();
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from_static(&[2])),
+            Err(Error::OtherError("broken".to_string())),
+        ]);
+        let data = CacheData::ByteStream(Box::new(stream), Some(2));
+        assert!(storage.persist("a", data).await.is_err());
+        // the old object is intact, and the temp file is removed
+        assert_eq!(
+            storage.read("a").await.unwrap().into_vec_u8().await,
+            vec![1]
+        );
+        assert_eq!(fs::read_dir(root_dir).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fs_cleanup_temp_files() {
+        let root_dir = "cache/test_fs_cleanup_temp_files";
+        let storage = Storage::FileSystem {
+            root_dir: root_dir.to_string(),
+        };
+        storage.persist("a/b", vec![1].into()).await.unwrap();
+        fs::write(temp_path(Path::new(&format!("{}/a/b", root_dir))), [2]).unwrap();
+        let objects = storage.list(&[]).await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(storage.cleanup_temp_files().unwrap(), 1);
+        assert_eq!(storage.cleanup_temp_files().unwrap(), 0);
+        assert_eq!(storage.list(&[]).await.unwrap(), objects);
+    }
+
+    #[tokio::test]
+    async fn test_cas_write_read() {
+        let mut storage = Storage::new_content_addressed("cache/test_cas_write_read").unwrap();
+        write_read(&mut storage).await;
+        remove(&mut storage).await;
+    }
+
+    #[tokio::test]
+    async fn test_cas_dedup() {
+        let root_dir = "cache/test_cas_dedup";
+        let _ = fs::remove_dir_all(root_dir);
+        let storage = Storage::new_content_addressed(root_dir).unwrap();
+        let blobs = || {
+            let mut count = 0;
+            fs_walk(&Path::new(root_dir).join(BLOBS_DIR), &[], &mut |_, _| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+            count
+        };
+        storage.persist("a", vec![1, 2].into()).await.unwrap();
+        storage.persist("b/c", vec![1, 2].into()).await.unwrap();
+        storage.persist("d", vec![3].into()).await.unwrap();
+        assert_eq!(blobs(), 2);
+        let mut objects = storage.list(&[]).await.unwrap();
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = objects.iter().map(|o| (o.name.as_str(), o.size)).collect();
+        assert_eq!(names, vec![("a", 2), ("b/c", 2), ("d", 1)]);
+
+        // a shared blob is kept until its last reference is removed
+        storage.remove("a").await.unwrap();
+        assert!(storage.read("a").await.is_err());
+        assert!(storage.remove("a").await.is_err());
+        assert_eq!(
+            storage.read("b/c").await.unwrap().into_vec_u8().await,
+            vec![1, 2]
+        );
+        storage.remove("b/c").await.unwrap();
+        assert_eq!(blobs(), 1);
+
+        // overwriting releases the previous blob
+        storage.persist("d", vec![4].into()).await.unwrap();
+        storage.persist("d", vec![4].into()).await.unwrap();
+        assert_eq!(blobs(), 1);
+        assert_eq!(
+            storage.read("d").await.unwrap().into_vec_u8().await,
+            vec![4]
+        );
+
+        // unreferenced blobs and temp files are cleaned up
+        let blob = blob_path(root_dir, &"0".repeat(64));
+        fs::create_dir_all(blob.parent().unwrap()).unwrap();
+        fs::write(&blob, [5]).unwrap();
+        fs::write(temp_path(&blob), [5]).unwrap();
+        assert_eq!(storage.cleanup_temp_files().unwrap(), 2);
+        assert_eq!(blobs(), 1);
+    }
+
     #[tokio::test]
     async fn test_mem_write_read() {
         let mut storage = Storage::new_mem();
@@ -1,35 +1,63 @@
+use crate::access::{self, AccessControl};
 use crate::cache::{
-    Cache, CacheData, CacheHitMiss, LruCache, RedisMetadataDb, SledMetadataDb, TtlCache,
+    Cache, CacheData, CacheHitMiss, CacheSizeType, Eviction, LruCache, ReconcileReport,
+    RedisMetadataDb, ResponseHeaders, SledMetadataDb, StaleEntry, StaleWindows, TtlCache,
+    FRESHNESS_HEADERS,
 };
+use crate::conditional;
 use crate::error::Error;
 use crate::error::Result;
+use crate::freshness::HttpFreshness;
+use crate::inflight::{self, DownloadState, InflightDownload};
+use crate::integrity;
 use crate::metric;
+use crate::negative::NegativeCache;
+use crate::range::{self, RangeRequest};
+use crate::resume;
+use crate::rewrite::{Origin, Rewriter};
 use crate::settings::Settings;
-use crate::settings::{MetadataDb, Policy, PolicyType, Rewrite};
-use crate::storage::Storage;
+use crate::settings::{DigestSource, MetadataDb, Policy, PolicyType};
+use crate::storage::{self, Storage};
+use crate::upstream::Upstreams;
 use crate::util;
 
 use bytes::Bytes;
-use futures::Stream;
-use futures::StreamExt;
+use futures::{future, stream, Stream, TryStreamExt};
+use ipnet::IpNet;
 use metrics::{histogram, increment_counter};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use warp::http::Response;
+use warp::http::header::{
+    HeaderName, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+};
+use warp::http::{HeaderMap, Response, StatusCode};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Task {
     pub rule_id: RuleId,
+    /// URL of the primary upstream, which identifies the task
     pub url: String,
+    /// URLs of the other upstreams of the rule
+    pub mirrors: Vec<String>,
+    /// Values of the forwarded client headers, which may select another
+    /// representation of the URL
+    pub variant: Option<String>,
 }
 
 pub enum TaskResponse {
     StringResponse(String),
     BytesResponse(Bytes),
-    StreamResponse(Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>),
+    StreamResponse(
+        Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>,
+        Option<CacheSizeType>,
+    ), // stream and size
+    /// A stored file and its size
+    FileResponse(tokio::fs::File, CacheSizeType),
     Redirect(warp::reply::WithHeader<warp::http::StatusCode>),
 }
 
@@ -44,7 +72,10 @@ impl From<CacheData> for TaskResponse {
         match cache_data {
             CacheData::TextData(text) => text.into(),
             CacheData::BytesData(bytes) => TaskResponse::BytesResponse(bytes),
-            CacheData::ByteStream(stream, ..) => TaskResponse::StreamResponse(Box::pin(stream)),
+            CacheData::ByteStream(stream, size) => {
+                TaskResponse::StreamResponse(Box::pin(stream), size)
+            }
+            CacheData::File(file, size) => TaskResponse::FileResponse(file, size),
         }
     }
 }
@@ -57,36 +88,237 @@ impl warp::Reply for TaskResponse {
                 .body(content.into())
                 .unwrap(),
             TaskResponse::BytesResponse(bytes) => warp::reply::Response::new(bytes.into()),
-            TaskResponse::StreamResponse(stream) => {
+            TaskResponse::StreamResponse(stream, _) => {
                 warp::reply::Response::new(warp::hyper::Body::wrap_stream(stream))
             }
+            TaskResponse::FileResponse(file, _) => warp::reply::Response::new(
+                warp::hyper::Body::wrap_stream(storage::file_stream(file)),
+            ),
             TaskResponse::Redirect(r) => r.into_response(),
         }
     }
 }
 
+impl TaskResponse {
+    /// Rewrite the body with `rewriter`. The length of a rewritten stream is unknown.
+    fn rewrite(self, rewriter: Rewriter) -> Self {
+        match self {
+            TaskResponse::StringResponse(content) => TaskResponse::StringResponse(
+                String::from_utf8_lossy(&rewriter.rewrite(content.as_bytes())).into_owned(),
+            ),
+            TaskResponse::BytesResponse(bytes) => {
+                TaskResponse::BytesResponse(rewriter.rewrite(&bytes).into())
+            }
+            TaskResponse::StreamResponse(stream, _) => TaskResponse::StreamResponse(
+                Box::pin(Arc::new(rewriter).rewrite_stream(stream)),
+                None,
+            ),
+            TaskResponse::FileResponse(file, _) => TaskResponse::StreamResponse(
+                Box::pin(Arc::new(rewriter).rewrite_stream(Box::pin(storage::file_stream(file)))),
+                None,
+            ),
+            TaskResponse::Redirect(_) => self,
+        }
+    }
+
+    /// Length of the whole body, if known
+    fn len(&self) -> Option<CacheSizeType> {
+        match self {
+            TaskResponse::StringResponse(content) => Some(content.len() as CacheSizeType),
+            TaskResponse::BytesResponse(bytes) => Some(bytes.len() as CacheSizeType),
+            TaskResponse::StreamResponse(_, size) => *size,
+            TaskResponse::FileResponse(_, size) => Some(*size),
+            TaskResponse::Redirect(_) => None,
+        }
+    }
+
+    /// Create a response honoring the `Range` header of the request.
+    /// Ranges are only served if the length of the whole body is known, otherwise
+    /// the whole body is served.
+    /// `headers` are the stored upstream response headers to replay.
+    /// `content_type` overrides the content type of the body.
+    pub fn into_ranged_response(
+        self,
+        range: Option<&str>,
+        headers: &ResponseHeaders,
+        content_type: Option<&str>,
+    ) -> warp::reply::Response {
+        let len = self.len();
+        let range_request = match (range, len) {
+            (Some(range), Some(len)) => range::parse_range(range, len),
+            _ => RangeRequest::Full,
+        };
+        let is_redirect = matches!(self, TaskResponse::Redirect(_));
+        let content_type = content_type
+            .or_else(|| headers.get("content-type"))
+            .or(match self {
+                TaskResponse::StringResponse(_) => Some("text/html"),
+                _ => None,
+            });
+        let mut resp = match range_request {
+            RangeRequest::Full => warp::Reply::into_response(self),
+            RangeRequest::Unsatisfiable => Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", len.unwrap()))
+                .body(warp::hyper::Body::empty())
+                .unwrap(),
+            RangeRequest::Partial(ranges) => {
+                let len = len.unwrap();
+                if ranges.len() == 1 {
+                    let range = ranges[0];
+                    let mut resp = Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(CONTENT_RANGE, range::content_range(range, len))
+                        .header(CONTENT_LENGTH, range.1 - range.0 + 1)
+                        .body(self.into_ranges_body(ranges, None))
+                        .unwrap();
+                    Self::set_content_type(&mut resp, content_type);
+                    resp
+                } else {
+                    let multipart = range::Multipart {
+                        boundary: format!("mirror-cache-{:x}", util::now_nanos()),
+                        content_type: content_type
+                            .unwrap_or("application/octet-stream")
+                            .to_string(),
+                        len,
+                    };
+                    let multipart_content_type = multipart.content_type();
+                    let body_len = multipart.body_len(&ranges);
+                    Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(CONTENT_TYPE, multipart_content_type)
+                        .header(CONTENT_LENGTH, body_len)
+                        .body(self.into_ranges_body(ranges, Some(multipart)))
+                        .unwrap()
+                }
+            }
+        };
+        if !is_redirect {
+            Self::set_headers(&mut resp, headers);
+        }
+        if resp.status() == StatusCode::OK {
+            Self::set_content_type(&mut resp, content_type);
+        }
+        if len.is_some() {
+            resp.headers_mut()
+                .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        }
+        resp
+    }
+
+    /// The body of `ranges` of the response. Stored files are sliced by seeking
+    /// to each range, and other bodies by skipping the bytes before it.
+    fn into_ranges_body(
+        self,
+        ranges: Vec<range::ByteRange>,
+        multipart: Option<range::Multipart>,
+    ) -> warp::hyper::Body {
+        let stream = match self {
+            TaskResponse::StringResponse(content) => {
+                Box::pin(stream::iter(vec![Ok(Bytes::from(content))]))
+            }
+            TaskResponse::BytesResponse(bytes) => Box::pin(stream::iter(vec![Ok(bytes)])),
+            TaskResponse::StreamResponse(stream, _) => stream,
+            TaskResponse::FileResponse(file, _) => {
+                return warp::hyper::Body::wrap_stream(range::slice_file(file, ranges, multipart))
+            }
+            TaskResponse::Redirect(_) => unreachable!(),
+        };
+        warp::hyper::Body::wrap_stream(range::slice_stream(stream, ranges, multipart))
+    }
+
+    /// Replay stored upstream headers, except the content type which depends on
+    /// the kind of the response, and headers only stored to derive freshness.
+    fn set_headers(resp: &mut warp::reply::Response, headers: &ResponseHeaders) {
+        for (name, value) in headers.iter() {
+            if name.eq_ignore_ascii_case("content-type")
+                || FRESHNESS_HEADERS
+                    .iter()
+                    .any(|header| name.eq_ignore_ascii_case(header))
+            {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                resp.headers_mut().append(name, value);
+            }
+        }
+    }
+
+    fn set_content_type(resp: &mut warp::reply::Response, content_type: Option<&str>) {
+        if let Some(value) = content_type.and_then(|x| HeaderValue::from_str(x).ok()) {
+            resp.headers_mut().insert(CONTENT_TYPE, value);
+        }
+    }
+}
+
 impl Task {
     /// create a unique key for the current task
     pub fn to_key(&self) -> String {
-        self.url
-            .replace("http://", "http/")
-            .replace("https://", "https/")
-            .trim_end_matches('/')
-            .to_string()
+        let key = url_to_key(&self.url).trim_end_matches('/').to_string();
+        match &self.variant {
+            Some(variant) => {
+                let digest = format!("{:x}", Sha256::digest(variant.as_bytes()));
+                format!("{}@{}", key, &digest[..16])
+            }
+            None => key,
+        }
+    }
+}
+
+fn url_to_key(url: &str) -> String {
+    url.replace("http://", "http/")
+        .replace("https://", "https/")
+}
+
+/// The prefix of keys of tasks matched by a rule of `upstream`, i.e. its literal
+/// part before the first capture group.
+fn upstream_key_prefix(upstream: &str) -> String {
+    match upstream.split_once('$') {
+        Some((literal, _)) => url_to_key(literal),
+        None => url_to_key(upstream).trim_end_matches('/').to_string(),
     }
 }
 
 pub type RuleId = usize;
 
+/// In-flight downloads of tasks
+type TaskMap = HashMap<Task, Arc<InflightDownload>>;
+
 #[derive(Clone)]
 pub struct TaskManager {
     pub config: Settings,
     /// RuleId -> (cache, size_limit of payload)
     pub rule_map: HashMap<RuleId, (Arc<RwLock<dyn Cache>>, usize)>,
     /// Specifies how to do the upstream rewrite for RuleId.
-    /// RuleId -> Vec<Rewrite>
-    pub rewrite_map: HashMap<RuleId, Vec<Rewrite>>,
-    task_set: Arc<RwLock<HashSet<Task>>>,
+    /// RuleId -> compiled rewrites
+    pub rewrite_map: HashMap<RuleId, Arc<Rewriter>>,
+    /// Policy name -> cache
+    pub cache_map: HashMap<String, Arc<RwLock<dyn Cache>>>,
+    /// Storage name -> storage
+    storage_map: HashMap<String, Arc<Storage>>,
+    /// Path -> sled metadata database, kept across configuration reloads
+    sled_dbs: HashMap<String, sled::Db>,
+    /// Health of upstreams, kept across configuration reloads
+    upstreams: Arc<Upstreams>,
+    /// Outbound HTTP client shared by rules without their own
+    client: util::Client,
+    /// RuleId -> outbound HTTP client of rules with `http_client` options
+    client_map: HashMap<RuleId, util::Client>,
+    /// RuleId -> (configured headers, names of forwarded client headers) of
+    /// requests to upstream
+    header_map: HashMap<RuleId, (HeaderMap, Vec<HeaderName>)>,
+    /// Access control of rules without their own
+    access: Option<Arc<AccessControl>>,
+    /// RuleId -> access control of rules with `access` options
+    access_map: HashMap<RuleId, Arc<AccessControl>>,
+    /// Networks of reverse proxies whose `X-Forwarded-For` is trusted
+    trusted_proxies: Vec<IpNet>,
+    /// Policy name -> negative cache of policies with `negative_cache` options
+    negative_map: HashMap<String, Arc<NegativeCache>>,
+    task_map: Arc<RwLock<TaskMap>>,
 }
 
 impl TaskManager {
@@ -94,8 +326,19 @@ impl TaskManager {
         TaskManager {
             config,
             rule_map: HashMap::new(),
-            task_set: Arc::new(RwLock::new(HashSet::new())),
+            task_map: Arc::new(RwLock::new(HashMap::new())),
             rewrite_map: HashMap::new(),
+            cache_map: HashMap::new(),
+            storage_map: HashMap::new(),
+            sled_dbs: HashMap::new(),
+            upstreams: Arc::new(Upstreams::default()),
+            client: util::Client::default(),
+            client_map: HashMap::new(),
+            header_map: HashMap::new(),
+            access: None,
+            access_map: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            negative_map: HashMap::new(),
         }
     }
 
@@ -103,189 +346,772 @@ impl TaskManager {
         Self {
             config: Settings::default(),
             rule_map: HashMap::new(),
-            task_set: Arc::new(RwLock::new(HashSet::new())),
+            task_map: Arc::new(RwLock::new(HashMap::new())),
             rewrite_map: HashMap::new(),
+            cache_map: HashMap::new(),
+            storage_map: HashMap::new(),
+            sled_dbs: HashMap::new(),
+            upstreams: Arc::new(Upstreams::default()),
+            client: util::Client::default(),
+            client_map: HashMap::new(),
+            header_map: HashMap::new(),
+            access: None,
+            access_map: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            negative_map: HashMap::new(),
         }
     }
 
-    pub async fn resolve_task(&self, task: &Task) -> (Result<TaskResponse>, CacheHitMiss) {
+    /// Resolve the task to a response along with the upstream response headers.
+    /// Headers of the client request are forwarded to upstream if the rule
+    /// configures so.
+    pub async fn resolve_task(
+        &self,
+        task: &Task,
+        client_headers: &HeaderMap,
+    ) -> (Result<(TaskResponse, ResponseHeaders)>, CacheHitMiss) {
         // try get from cache
-        let mut cache_result = None;
         let key = task.to_key();
+        let headers = self.upstream_headers(task.rule_id, client_headers);
 
-        if let Some(bytes) = self.get(task, &key).await {
-            cache_result = Some(bytes);
-        }
-        if let Some(data) = cache_result {
+        if let Some((data, headers)) = self.get(task, &key).await {
             info!("[Request] [HIT] {:?}", &task);
-            return (Ok(data.into()), CacheHitMiss::Hit);
+            return (Ok((data.into(), headers)), CacheHitMiss::Hit);
+        }
+        if let Some((policy, negative)) = self.negative_cache(task.rule_id) {
+            if let Some(status) = negative.get(&key) {
+                info!("[Request] [NEGATIVE HIT] {:?}: {}", &task, status);
+                increment_counter!(metric::CNT_NEGATIVE_HIT, "policy" => policy.to_string());
+                return (Err(Error::NegativeCacheHit(status)), CacheHitMiss::Hit);
+            }
+            increment_counter!(metric::CNT_NEGATIVE_MISS, "policy" => policy.to_string());
+        }
+        // serve an expired entry if the policy allows
+        let stale_if_error = match self.get_stale(task, &key).await {
+            Some(stale) if stale.while_revalidate => {
+                info!("[Request] [STALE] {:?}, refreshing in background", &task);
+                increment_counter!(metric::CNT_STALE_SERVED);
+                self.refresh_in_background(task, &headers).await;
+                return (Ok((stale.data.into(), stale.headers)), CacheHitMiss::Hit);
+            }
+            Some(stale) if stale.if_error => Some(stale),
+            _ => None,
+        };
+        match (
+            self.resolve_miss(task, &key, &headers).await,
+            stale_if_error,
+        ) {
+            ((Err(e), _), Some(stale)) if Self::is_fetch_failure(&e) => {
+                warn!(
+                    "[Request] [STALE] {:?}, serving expired entry as upstream failed: {}",
+                    &task, e
+                );
+                increment_counter!(metric::CNT_STALE_SERVED);
+                (Ok((stale.data.into(), stale.headers)), CacheHitMiss::Hit)
+            }
+            (resp, _) => resp,
+        }
+    }
+
+    /// Request the upstreams of the task in order, until one responds without a
+    /// server error. All of them are requested again with backoff as configured
+    /// by `retry` if none does. Returns the response along with the URL of the
+    /// upstream. `head` makes HEAD requests instead of GET.
+    pub async fn request_upstream(
+        &self,
+        task: &Task,
+        head: bool,
+        headers: HeaderMap,
+    ) -> Result<(reqwest::Response, String)> {
+        let order = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.upstream_order)
+            .unwrap_or_default();
+        let urls: Vec<String> = std::iter::once(task.url.clone())
+            .chain(task.mirrors.iter().cloned())
+            .collect();
+        let client = self.get_client(task.rule_id);
+        let retry = self.config.get_retry();
+        let mut attempts = 0;
+        loop {
+            let mut last_result = None;
+            for url in self.upstreams.order(task.rule_id, &urls, order) {
+                if last_result.is_some() {
+                    increment_counter!(metric::CNT_UPSTREAM_FAILOVERS);
+                    info!("[Request] {:?} failing over to {}", task, url);
+                }
+                match client.request(&url, head, headers.clone()).await {
+                    Ok(res) if !res.status().is_server_error() => {
+                        self.upstreams.report_success(&url);
+                        return Ok((res, url));
+                    }
+                    result => {
+                        self.upstreams.report_failure(&url, client);
+                        last_result = Some(result.map(|res| (res, url)));
+                    }
+                }
+            }
+            if attempts >= retry.max_retries {
+                return last_result.unwrap();
+            }
+            // every upstream failed, try them again after a backoff
+            let backoff = retry.backoff(attempts);
+            attempts += 1;
+            warn!(
+                "[Request] {:?} failed on all upstreams, retrying in {} ms (attempt {})",
+                task,
+                backoff.as_millis(),
+                attempts
+            );
+            tokio::time::sleep(backoff).await;
+            increment_counter!(metric::CNT_UPSTREAM_RETRIES);
+        }
+    }
+
+    /// Whether the error is caused by upstream being unavailable.
+    fn is_fetch_failure(e: &Error) -> bool {
+        match e {
+            Error::UpstreamRequestError(res) => res.status().is_server_error(),
+            _ => true,
+        }
+    }
+
+    /// Refresh the task in the background, unless it is being downloaded.
+    async fn refresh_in_background(&self, task: &Task, headers: &HeaderMap) {
+        let (download, is_new) = self.task_map_join(task).await;
+        if !is_new {
+            return;
         }
+        let tm = self.clone();
+        let task = task.clone();
+        let headers = headers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tm.fetch_task(&task, &download, &headers).await {
+                warn!(
+                    "[Request] {:?} failed to refresh in background: {}",
+                    &task, e
+                );
+            }
+        });
+    }
+
+    /// Resolve the task which is not in the cache.
+    async fn resolve_miss(
+        &self,
+        task: &Task,
+        key: &str,
+        headers: &HeaderMap,
+    ) -> (Result<(TaskResponse, ResponseHeaders)>, CacheHitMiss) {
         increment_counter!(metric::COUNTER_CACHE_MISS);
         // cache miss
-        // fetch from upstream
+        // attach to the in-flight download of the task if there is one
+        let download = loop {
+            let (download, is_new) = self.task_map_join(task).await;
+            if is_new {
+                break download;
+            }
+            info!("[Request] [MISS] {:?}, following in-flight download", &task);
+            if let Some(resp) = self.subscribe_download(task, &download).await {
+                return (resp, CacheHitMiss::Miss);
+            }
+            // the download is not used, e.g. the entry is revalidated meanwhile
+            if let Some((data, headers)) = self.get(task, key).await {
+                return (Ok((data.into(), headers)), CacheHitMiss::Hit);
+            }
+        };
+        // fetch in a separate task, so that the download is settled even if the
+        // requester goes away
+        let tm = self.clone();
+        let task_clone = task.clone();
+        let download_clone = download.clone();
+        let headers = headers.clone();
+        let fetch =
+            tokio::spawn(
+                async move { tm.fetch_task(&task_clone, &download_clone, &headers).await },
+            );
+        match fetch.await {
+            Ok(Ok(None)) => match self.subscribe_download(task, &download).await {
+                Some(resp) => (resp, CacheHitMiss::Miss),
+                None => (
+                    Err(Error::OtherError(format!(
+                        "failed to start download of {:?}",
+                        task
+                    ))),
+                    CacheHitMiss::Miss,
+                ),
+            },
+            Ok(Ok(Some(resp))) => (Ok(resp), CacheHitMiss::Miss),
+            Ok(Err(e)) => (Err(e), CacheHitMiss::Miss),
+            Err(e) => (Err(Error::OtherError(e.to_string())), CacheHitMiss::Miss),
+        }
+    }
+
+    /// Fetch the task from upstream with `upstream_headers`, and start the newly registered
+    /// download with the response. An expired cache entry is revalidated with a
+    /// conditional request instead if possible.
+    /// Returns a response to use instead if the download is not started.
+    async fn fetch_task(
+        &self,
+        task: &Task,
+        download: &Arc<InflightDownload>,
+        upstream_headers: &HeaderMap,
+    ) -> Result<Option<(TaskResponse, ResponseHeaders)>> {
         let remote_url = self.resolve_task_upstream(task);
-        info!(
-            "[Request] [MISS] {:?}, fetching from upstream: {}",
-            &task, &remote_url
-        );
-        let resp = util::make_request(&remote_url, false).await;
+        let key = task.to_key();
+        let stale = self
+            .get_stale(task, &key)
+            .await
+            .filter(|stale| conditional::has_validator(&stale.headers));
+        let mut request_headers = upstream_headers.clone();
+        request_headers.extend(match &stale {
+            Some(stale) => {
+                info!(
+                    "[Request] [STALE] {:?}, revalidating with upstream: {}",
+                    &task, &remote_url
+                );
+                conditional::revalidation_headers(&stale.headers)
+            }
+            None => {
+                info!(
+                    "[Request] [MISS] {:?}, fetching from upstream: {}",
+                    &task, &remote_url
+                );
+                HeaderMap::new()
+            }
+        });
+        let resp = self.request_upstream(task, false, request_headers).await;
         match resp {
-            Ok(res) => {
+            Ok((res, remote_url)) => {
+                if res.status() == StatusCode::NOT_MODIFIED {
+                    if let Some(StaleEntry {
+                        data, mut headers, ..
+                    }) = stale
+                    {
+                        headers.update(ResponseHeaders::from_header_map(res.headers()));
+                        if let Some(cache) = self.get_cache_for_cache_rule(task.rule_id) {
+                            cache.write().await.refresh(&key, headers.clone()).await;
+                        }
+                        self.task_map_abort(task, download).await;
+                        increment_counter!(metric::CNT_REVALIDATED);
+                        info!("[Request] [REVALIDATED] {:?}", &task);
+                        return Ok(Some((data.into(), headers)));
+                    }
+                }
                 if !res.status().is_success() {
-                    return (Err(Error::UpstreamRequestError(res)), CacheHitMiss::Miss);
+                    if let Some((_, negative)) = self.negative_cache(task.rule_id) {
+                        negative.insert(&key, res.status());
+                    }
+                    self.task_map_abort(task, download).await;
+                    return Err(Error::UpstreamRequestError(Box::new(res)));
                 }
                 // if the response is too large, respond users with a redirect to upstream
                 if let Some(content_length) = res.content_length() {
                     let size_limit = self.get_task_size_limit(task);
                     if size_limit != 0 && size_limit < content_length as usize {
-                        return (
-                            Ok(TaskResponse::Redirect(warp::reply::with_header(
+                        self.task_map_abort(task, download).await;
+                        return Ok(Some((
+                            TaskResponse::Redirect(warp::reply::with_header(
                                 warp::http::StatusCode::FOUND,
                                 "Location",
                                 remote_url,
-                            ))),
-                            CacheHitMiss::Miss,
-                        );
+                            )),
+                            ResponseHeaders::default(),
+                        )));
                     }
                 }
-                // dispatch async cache task
-                let _ = self.spawn_task(task.clone()).await;
-                let rule_id = task.rule_id;
-                if let Some(rewrite_rules) = self.rewrite_map.get(&rule_id) {
-                    let text = res.text().await.unwrap();
-                    let content = Self::rewrite_upstream(text, rewrite_rules);
-                    (Ok(content.into()), CacheHitMiss::Miss)
-                } else {
-                    (
-                        Ok(TaskResponse::StreamResponse(Box::pin(
-                            res.bytes_stream()
-                                .map(move |x| x.map_err(Error::RequestError)),
-                        ))),
-                        CacheHitMiss::Miss,
-                    )
-                }
+                // feed the cache and all requesters with the same upstream response
+                let len = res.content_length();
+                let headers = ResponseHeaders::from_header_map(res.headers());
+                let body = resume::resumable_body(
+                    self.get_client(task.rule_id).clone(),
+                    remote_url,
+                    res,
+                    upstream_headers.clone(),
+                    self.config.get_retry(),
+                );
+                download.start(body, len, headers);
+                self.spawn_task(task.clone(), download.clone()).await;
+                Ok(None)
             }
             Err(e) => {
+                self.task_map_abort(task, download).await;
                 error!("[Request] {:?} failed to fetch upstream: {}", &task, e);
-                (Err(e), CacheHitMiss::Miss)
+                Err(e)
             }
         }
     }
 
-    /// for each rule, create associated cache if the policy has not been created
-    pub fn refresh_config(&mut self, settings: &Settings) {
-        let app_settings = settings;
-        let redis_url = app_settings.get_redis_url();
-        let policies = app_settings.policies.clone();
+    /// Create a response that streams the in-flight download.
+    /// Returns `None` if the download is not usable.
+    async fn subscribe_download(
+        &self,
+        task: &Task,
+        download: &Arc<InflightDownload>,
+    ) -> Option<Result<(TaskResponse, ResponseHeaders)>> {
+        let (stream, len, headers) = download.subscribe().await?;
+        let resp = match self.rewrite_map.get(&task.rule_id) {
+            // the length is unknown until the whole body is rewritten
+            Some(rewriter) => TaskResponse::StreamResponse(
+                Box::pin(rewriter.clone().rewrite_stream(stream)),
+                None,
+            ),
+            None => TaskResponse::StreamResponse(Box::pin(stream), len),
+        };
+        Some(Ok((resp, headers)))
+    }
 
-        let tm = self;
-        tm.config = app_settings.clone();
+    async fn collect_bytes(stream: impl Stream<Item = Result<Bytes>>) -> Result<Vec<u8>> {
+        stream
+            .try_fold(Vec::new(), |mut acc, bytes| {
+                acc.extend_from_slice(&bytes);
+                future::ready(Ok(acc))
+            })
+            .await
+    }
 
-        let mut policy_map: HashSet<String> = HashSet::new(); // used to avoid create duplicated cache if some rules share the same policy
-                                                              // get active policy set
-        for rule in &app_settings.rules {
-            policy_map.insert(rule.policy.clone());
+    /// Learn the digests listed in a downloaded index of at most the given size,
+    /// and verify a downloaded artifact against its digest if known.
+    /// Returns an error if the download does not match.
+    async fn check_integrity(
+        url: &str,
+        digests: Option<(DigestSource, u64)>,
+        download: &Arc<InflightDownload>,
+    ) -> Result<()> {
+        let subscribe = || async {
+            download
+                .subscribe()
+                .await
+                .map(|(stream, _, _)| stream)
+                .ok_or_else(|| Error::OtherError("spool is not readable".to_string()))
+        };
+        if let Some((source, max_index_size)) =
+            digests.filter(|(source, _)| integrity::is_index(*source, url))
+        {
+            let size = download.progress().received;
+            if size > max_index_size {
+                warn!(
+                    "[TASK] skip learning digests from {}: {} bytes exceed max_index_size",
+                    url, size
+                );
+            } else {
+                let body = Self::collect_bytes(subscribe().await?).await?;
+                let learned = integrity::learn(source, url, &body);
+                debug!("[TASK] learned {} digests from {}", learned, url);
+            }
         }
+        if let Some(expected) = integrity::expected_digest(url) {
+            let actual = integrity::digest_stream(&expected, subscribe().await?).await?;
+            if actual != expected {
+                increment_counter!(metric::CNT_INTEGRITY_MISMATCH);
+                return Err(Error::OtherError(format!(
+                    "digest mismatch: expected {}, got {}",
+                    expected, actual
+                )));
+            }
+            increment_counter!(metric::CNT_INTEGRITY_VERIFIED);
+        }
+        Ok(())
+    }
+
+    /// Apply `settings`, creating the cache of each policy used by rules. The
+    /// whole new state is built before it replaces the current one, which is
+    /// kept if the settings are invalid.
+    pub fn refresh_config(&mut self, settings: &Settings) -> Result<()> {
+        let policies = &settings.policies;
+        let client_config = settings.http_client.clone().unwrap_or_default();
+        let client = util::Client::new(&client_config)
+            .map_err(|e| Error::ConfigInvalid(format!("failed to create HTTP client: {}", e)))?;
+        let access = settings
+            .access
+            .as_ref()
+            .map(|config| {
+                AccessControl::new(config)
+                    .map(Arc::new)
+                    .map_err(|e| Error::ConfigInvalid(format!("invalid access control: {}", e)))
+            })
+            .transpose()?;
+        let trusted_proxies =
+            access::parse_networks(settings.trusted_proxies.as_deref().unwrap_or_default())
+                .map_err(|e| Error::ConfigInvalid(format!("invalid trusted_proxies: {}", e)))?;
 
-        // Create storages
+        // policies used by rules, whose caches are shared by the rules
+        let active_policies: HashSet<&String> =
+            settings.rules.iter().map(|rule| &rule.policy).collect();
+
+        // Create storages. Unchanged content-addressed storages are kept, as their
+        // index database cannot be opened twice.
         let mut storage_map = HashMap::new();
-        for storage_config in &app_settings.storages {
-            let storage = Self::create_storage(storage_config);
-            storage_map.insert(storage_config.name.clone(), Arc::new(storage));
-        }
-
-        // Clear cache here, so that previous cache objects can be dropped
-        tm.rule_map.clear();
-        tm.rewrite_map.clear();
-        let mut cache_map: HashMap<String, _> = HashMap::new();
-        let redis_client = redis::Client::open(redis_url).expect("failed to connect to redis");
-        // create cache for each policy
-        for policy in &policy_map {
-            let cache = Self::create_cache_from_rule(
+        for storage_config in &settings.storages {
+            let storage = match self.storage_map.get(&storage_config.name) {
+                Some(storage) if storage.is_created_from(&storage_config.config) => storage.clone(),
+                _ => Arc::new(Self::create_storage(storage_config)?),
+            };
+            storage_map.insert(storage_config.name.clone(), storage);
+        }
+
+        let redis_client = redis::Client::open(settings.get_redis_url())
+            .map_err(|e| Error::ConfigInvalid(format!("invalid redis url: {}", e)))?;
+        let mut sled_dbs = HashMap::new();
+        let mut cache_map = HashMap::new();
+        let mut negative_map = HashMap::new();
+        for policy in active_policies {
+            let cache = self.create_cache_from_rule(
                 policy,
-                &policies,
+                policies,
                 Some(redis_client.clone()),
-                &app_settings.sled.metadata_path,
+                &settings.sled.metadata_path,
                 &storage_map,
-            );
-            cache_map.insert(policy.to_string(), cache.unwrap());
+                &mut sled_dbs,
+            )?;
+            cache_map.insert(policy.clone(), cache);
+            if let Some(config) = policies
+                .iter()
+                .find(|p| &p.name == policy)
+                .and_then(|p| p.negative_cache.as_ref())
+            {
+                let negative = NegativeCache::new(config).map_err(|e| {
+                    Error::ConfigInvalid(format!(
+                        "invalid negative_cache of policy {}: {}",
+                        policy, e
+                    ))
+                })?;
+                negative_map.insert(policy.clone(), Arc::new(negative));
+            }
         }
 
-        for (idx, rule) in app_settings.rules.iter().enumerate() {
+        let mut rule_map = HashMap::new();
+        let mut rewrite_map = HashMap::new();
+        let mut client_map = HashMap::new();
+        let mut header_map = HashMap::new();
+        let mut access_map = HashMap::new();
+        for (idx, rule) in settings.rules.iter().enumerate() {
             debug!("creating rule #{}: {:?}", idx, rule);
-            let cache = cache_map.get(&rule.policy).unwrap().clone();
-            tm.rule_map.insert(
-                idx,
-                (
-                    cache,
-                    rule.size_limit
-                        .clone()
-                        .map_or(0, |x| bytefmt::parse(x).unwrap() as usize),
-                ),
+            let invalid = |what: &str, e: Error| {
+                Error::ConfigInvalid(format!("invalid {} of rule #{}: {}", what, idx, e))
+            };
+            let size_limit = match &rule.size_limit {
+                Some(size) => bytefmt::parse(size).map_err(|e| {
+                    Error::ConfigInvalid(format!("invalid size_limit of rule #{}: {}", idx, e))
+                })? as usize,
+                None => 0,
+            };
+            rule_map.insert(idx, (cache_map[&rule.policy].clone(), size_limit));
+            if let Some(config) = &rule.http_client {
+                let client = util::Client::new(&config.or(&client_config))
+                    .map_err(|e| invalid("http_client", e))?;
+                client_map.insert(idx, client);
+            }
+            if rule.headers.is_some() || rule.forward_headers.is_some() {
+                let headers = util::request_headers(rule.headers.as_deref().unwrap_or_default())
+                    .map_err(|e| invalid("headers", e))?;
+                let forward = util::forwarded_header_names(
+                    rule.forward_headers.as_deref().unwrap_or_default(),
+                )
+                .map_err(|e| invalid("forward_headers", e))?;
+                header_map.insert(idx, (headers, forward));
+            }
+            if let Some(config) = &rule.access {
+                let access = AccessControl::new(config).map_err(|e| invalid("access", e))?;
+                access_map.insert(idx, Arc::new(access));
+            }
+            if let Some(options) = &rule.options {
+                options
+                    .get_max_index_size()
+                    .map_err(|e| invalid("options", e))?;
+            }
+            if let Some(rewrites) = &rule.rewrite {
+                let rewriter = Rewriter::new(rewrites).map_err(|e| invalid("rewrite", e))?;
+                rewrite_map.insert(idx, Arc::new(rewriter));
+            }
+        }
+
+        let spool_dir = settings.get_spool_dir();
+        if let Err(e) = std::fs::create_dir_all(&spool_dir) {
+            error!(
+                "failed to create spool directory {}: {}",
+                spool_dir.display(),
+                e
             );
-            if let Some(rewrite) = rule.rewrite.clone() {
-                tm.rewrite_map.insert(idx, rewrite);
+        }
+        self.upstreams.set_config(settings.get_upstream_health());
+        *self = TaskManager {
+            config: settings.clone(),
+            rule_map,
+            rewrite_map,
+            cache_map,
+            storage_map,
+            sled_dbs,
+            upstreams: self.upstreams.clone(),
+            client,
+            client_map,
+            header_map,
+            access,
+            access_map,
+            trusted_proxies,
+            negative_map,
+            task_map: self.task_map.clone(),
+        };
+        Ok(())
+    }
+
+    fn policy_storage(&self, policy: &str) -> Option<&str> {
+        self.config
+            .policies
+            .iter()
+            .find(|p| p.name == policy)
+            .map(|p| p.storage.as_str())
+    }
+
+    /// Reconcile the metadata of a policy with its storage.
+    ///
+    /// Policies sharing the same storage are locked during reconciliation, and
+    /// their objects are not considered orphans. Only orphans in the key space
+    /// of the rules of the policy are adopted, as other storages may share the
+    /// directory. The metadata database and the spool directory are skipped if
+    /// they are placed under the storage root.
+    pub async fn reconcile(&self, policy: &str) -> Result<ReconcileReport> {
+        let storage_name = self
+            .policy_storage(policy)
+            .filter(|_| self.cache_map.contains_key(policy))
+            .ok_or_else(|| Error::ConfigInvalid(format!("No such policy: {}", policy)))?;
+        let storage = self
+            .storage_map
+            .get(storage_name)
+            .ok_or_else(|| Error::ConfigInvalid(format!("No such storage: {}", storage_name)))?;
+        // lock in name order, so that concurrent reconciliations do not deadlock
+        let mut sharing: Vec<_> = self
+            .cache_map
+            .iter()
+            .filter(|(name, _)| self.policy_storage(name) == Some(storage_name))
+            .collect();
+        sharing.sort_by_key(|(name, _)| name.as_str());
+        let mut guards = Vec::new();
+        for (name, cache) in sharing {
+            guards.push((name, cache.write().await));
+        }
+        let (mut own, mut foreign) = (HashSet::new(), HashSet::new());
+        for (name, cache) in &guards {
+            if name.as_str() == policy {
+                own.extend(cache.all_keys().await);
+            } else {
+                foreign.extend(cache.all_keys().await);
+            }
+        }
+        let prefixes: Vec<String> = self
+            .config
+            .rules
+            .iter()
+            .filter(|rule| rule.policy == policy)
+            .map(|rule| upstream_key_prefix(&rule.upstream))
+            .collect();
+        let skip_dirs = [
+            PathBuf::from(&self.config.sled.metadata_path),
+            self.config.get_spool_dir(),
+        ];
+        let objects = storage
+            .list(&skip_dirs)
+            .await?
+            .into_iter()
+            .filter(|object| {
+                !foreign.contains(&object.name)
+                    && (own.contains(&object.name)
+                        || prefixes
+                            .iter()
+                            .any(|prefix| object.name.starts_with(prefix.as_str())))
+            })
+            .collect();
+        let (_, cache) = guards
+            .iter_mut()
+            .find(|(name, _)| name.as_str() == policy)
+            .unwrap();
+        Ok(cache.reconcile(objects).await)
+    }
+
+    /// Remove temp files left by interrupted writes in all storages, and spool
+    /// files of interrupted downloads.
+    /// This must run before any write, i.e. at startup.
+    pub fn cleanup_temp_files(&self) {
+        for (name, storage) in &self.storage_map {
+            match storage.cleanup_temp_files() {
+                Ok(0) => {}
+                Ok(removed) => info!("removed {} temp files in storage {}", removed, name),
+                Err(e) => error!("failed to clean up temp files in storage {}: {}", name, e),
+            }
+        }
+        let spool_dir = self.config.get_spool_dir();
+        match inflight::cleanup_spool_dir(&spool_dir) {
+            Ok(0) => {}
+            Ok(removed) => info!("removed {} spool files in {}", removed, spool_dir.display()),
+            Err(e) => error!(
+                "failed to clean up spool files in {}: {}",
+                spool_dir.display(),
+                e
+            ),
+        }
+    }
+
+    /// Reconcile all active policies.
+    pub async fn reconcile_all(&self) {
+        let mut policies: Vec<&String> = self.cache_map.keys().collect();
+        policies.sort();
+        for policy in policies {
+            match self.reconcile(policy).await {
+                Ok(report) => info!("reconciled {}: {:?}", policy, report),
+                Err(e) => error!("failed to reconcile {}: {}", policy, e),
             }
         }
     }
 
-    fn create_storage(storage: &crate::settings::Storage) -> crate::storage::Storage {
+    fn create_storage(storage: &crate::settings::Storage) -> Result<crate::storage::Storage> {
         match &storage.config {
-            crate::settings::StorageConfig::Fs { path } => Storage::FileSystem {
+            crate::settings::StorageConfig::Fs { path } => Ok(Storage::FileSystem {
                 root_dir: path.clone(),
-            },
-            crate::settings::StorageConfig::Mem => Storage::new_mem(),
+            }),
+            crate::settings::StorageConfig::Mem => Ok(Storage::new_mem()),
+            crate::settings::StorageConfig::ContentAddressed { path } => {
+                Storage::new_content_addressed(path).map_err(|e| {
+                    Error::ConfigInvalid(format!(
+                        "failed to open content-addressed storage {}: {}",
+                        path, e
+                    ))
+                })
+            }
+        }
+    }
+
+    /// Open the sled database at `path` into `sled_dbs`. The database of the
+    /// current configuration is shared instead, as it cannot be opened twice.
+    fn sled_db(&self, sled_dbs: &mut HashMap<String, sled::Db>, path: &str) -> Result<sled::Db> {
+        let db = match self.sled_dbs.get(path) {
+            Some(db) => db.clone(),
+            None => SledMetadataDb::open_db(path)?,
+        };
+        sled_dbs.insert(path.to_string(), db.clone());
+        Ok(db)
+    }
+
+    fn stale_windows(policy: &Policy) -> StaleWindows {
+        StaleWindows {
+            revalidate: policy.revalidate_window.or(policy.timeout).unwrap_or(0),
+            while_revalidate: policy.stale_while_revalidate.unwrap_or(0),
+            if_error: policy.stale_if_error.unwrap_or(0),
+        }
+    }
+
+    /// The size limit of a size-bounded TTL policy, which requires `size`. It is
+    /// optional for HTTP policies.
+    fn ttl_size_limit(policy: &Policy) -> Result<Option<CacheSizeType>> {
+        match (policy.typ, &policy.size) {
+            (PolicyType::TtlLru | PolicyType::Http, Some(size)) => {
+                bytefmt::parse(size).map(Some).map_err(|e| {
+                    Error::ConfigInvalid(format!("invalid size of policy {}: {}", policy.name, e))
+                })
+            }
+            (PolicyType::TtlLru, None) => Err(Error::ConfigInvalid(format!(
+                "policy {} of type TTL_LRU requires size",
+                policy.name
+            ))),
+            _ => Ok(None),
         }
     }
 
     fn create_cache_from_rule(
+        &self,
         policy_name: &str,
         policies: &[Policy],
         redis_client: Option<redis::Client>,
         sled_metadata_path: &str,
         storage_map: &HashMap<String, Arc<Storage>>,
+        sled_dbs: &mut HashMap<String, sled::Db>,
     ) -> Result<Arc<RwLock<dyn Cache>>> {
         let policy_ident = policy_name;
         for p in policies {
             if p.name == policy_ident {
+                let size = match &p.size {
+                    Some(size) => bytefmt::parse(size).map_err(|e| {
+                        Error::ConfigInvalid(format!(
+                            "invalid size of policy {}: {}",
+                            policy_ident, e
+                        ))
+                    })?,
+                    None => 0,
+                };
+                let storage = storage_map
+                    .get(&p.storage)
+                    .ok_or_else(|| {
+                        Error::ConfigInvalid(format!(
+                            "no such storage of policy {}: {}",
+                            policy_ident, p.storage
+                        ))
+                    })?
+                    .clone();
+                let redis_client = || {
+                    redis_client.clone().ok_or_else(|| {
+                        Error::ConfigInvalid(format!("policy {} requires redis", policy_ident))
+                    })
+                };
+                let sled_path = format!("{}/{}", sled_metadata_path, policy_ident);
                 let policy_type = p.typ;
                 let metadata_db = p.metadata_db;
+                let eviction = match policy_type {
+                    PolicyType::Lfu => Eviction::Lfu,
+                    PolicyType::Sieve => Eviction::Sieve,
+                    _ => Eviction::Lru,
+                };
                 match (policy_type, metadata_db) {
-                    (PolicyType::Lru, MetadataDb::Redis) => {
+                    (PolicyType::Lru | PolicyType::Lfu | PolicyType::Sieve, MetadataDb::Redis) => {
                         return Ok(Arc::new(RwLock::new(LruCache::new(
-                            p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
-                            Arc::new(RedisMetadataDb::new(redis_client.unwrap(), policy_ident)),
-                            storage_map.get(&p.storage).unwrap().clone(),
+                            size,
+                            Arc::new(RedisMetadataDb::new_bounded(
+                                redis_client()?,
+                                policy_ident,
+                                eviction,
+                            )?),
+                            storage.clone(),
                             policy_ident,
                         ))));
                     }
-                    (PolicyType::Lru, MetadataDb::Sled) => {
+                    (PolicyType::Lru | PolicyType::Lfu | PolicyType::Sieve, MetadataDb::Sled) => {
                         return Ok(Arc::new(RwLock::new(LruCache::new(
-                            p.size.as_ref().map_or(0, |x| bytefmt::parse(x).unwrap()),
-                            Arc::new(SledMetadataDb::new_lru(
-                                &format!("{}/{}", sled_metadata_path, policy_ident),
+                            size,
+                            Arc::new(SledMetadataDb::bounded_from_db(
+                                self.sled_db(sled_dbs, &sled_path)?,
+                                &sled_path,
                                 policy_ident,
-                            )),
-                            storage_map.get(&p.storage).unwrap().clone(),
+                                eviction,
+                            )?),
+                            storage.clone(),
                             policy_ident,
                         ))));
                     }
-                    (PolicyType::Ttl, MetadataDb::Redis) => {
+                    (
+                        PolicyType::Ttl | PolicyType::TtlLru | PolicyType::Http,
+                        MetadataDb::Redis,
+                    ) => {
                         return Ok(Arc::new(RwLock::new(TtlCache::new(
                             p.timeout.unwrap_or(0),
-                            Arc::new(RedisMetadataDb::new(redis_client.unwrap(), policy_ident)),
-                            storage_map.get(&p.storage).unwrap().clone(),
+                            Self::stale_windows(p),
+                            Self::ttl_size_limit(p)?,
+                            matches!(policy_type, PolicyType::Http).then(|| HttpFreshness::new(p)),
+                            Arc::new(RedisMetadataDb::new(redis_client()?, policy_ident)),
+                            storage.clone(),
                         ))));
                     }
-                    (PolicyType::Ttl, MetadataDb::Sled) => {
+                    (PolicyType::Ttl | PolicyType::TtlLru | PolicyType::Http, MetadataDb::Sled) => {
                         return Ok(Arc::new(RwLock::new(TtlCache::new(
                             p.timeout.unwrap_or(0),
-                            Arc::new(SledMetadataDb::new_ttl(
-                                &format!("{}/{}", sled_metadata_path, &policy_ident),
+                            Self::stale_windows(p),
+                            Self::ttl_size_limit(p)?,
+                            matches!(policy_type, PolicyType::Http).then(|| HttpFreshness::new(p)),
+                            Arc::new(SledMetadataDb::ttl_from_db(
+                                self.sled_db(sled_dbs, &sled_path)?,
+                                &sled_path,
                                 policy_ident,
                                 p.clean_interval.unwrap_or(3),
-                            )),
-                            storage_map.get(&p.storage).unwrap().clone(),
+                            )?),
+                            storage.clone(),
                         ))));
                     }
                 };
@@ -297,98 +1123,135 @@ impl TaskManager {
         )))
     }
 
-    async fn taskset_contains(&self, t: &Task) -> bool {
-        self.task_set.read().await.contains(t)
+    /// Join the in-flight download of the task, or register a new one if there is
+    /// no usable download.
+    /// Returns the download and whether it is newly registered. The caller is
+    /// responsible for starting or aborting a new download.
+    async fn task_map_join(&self, t: &Task) -> (Arc<InflightDownload>, bool) {
+        let mut task_map = self.task_map.write().await;
+        if let Some(download) = task_map.get(t) {
+            match download.progress().state {
+                DownloadState::Failed(_) | DownloadState::Aborted => {}
+                _ => return (download.clone(), false),
+            }
+        }
+        let download =
+            InflightDownload::new(inflight::new_spool_path(&self.config.get_spool_dir()));
+        task_map.insert(t.clone(), download.clone());
+        (download, true)
     }
 
-    async fn taskset_add(&self, t: Task) {
-        self.task_set.write().await.insert(t);
+    async fn task_map_abort(&self, t: &Task, download: &Arc<InflightDownload>) {
+        Self::task_map_remove(self.task_map.clone(), t, download).await;
+        download.abort();
     }
 
-    async fn taskset_remove(task_set: Arc<RwLock<HashSet<Task>>>, t: &Task) {
-        task_set.write().await.remove(t);
+    /// Remove the task if it is still associated with the given download.
+    async fn task_map_remove(
+        task_map: Arc<RwLock<TaskMap>>,
+        t: &Task,
+        download: &Arc<InflightDownload>,
+    ) {
+        let mut task_map = task_map.write().await;
+        if task_map
+            .get(t)
+            .is_some_and(|current| Arc::ptr_eq(current, download))
+        {
+            task_map.remove(t);
+        }
     }
 
-    async fn taskset_len(task_set: Arc<RwLock<HashSet<Task>>>) -> usize {
-        let len = task_set.read().await.len();
+    async fn task_map_len(task_map: Arc<RwLock<TaskMap>>) -> usize {
+        let len = task_map.read().await.len();
         histogram!(metric::HG_TASKS_LEN, len as f64);
         len
     }
 
-    /// Spawn an async task
-    async fn spawn_task(&self, task: Task) {
+    /// Spawn an async task to fill the cache of the task from its in-flight download.
+    /// The task is removed from the task map once the cache is filled.
+    async fn spawn_task(&self, task: Task, download: Arc<InflightDownload>) {
         increment_counter!(metric::COUNTER_TASKS_BG);
-        if self.taskset_contains(&task).await {
-            info!("[TASK] ignored existing task: {:?}", task);
-            return;
-        }
-        self.taskset_add(task.clone()).await;
-        let task_set_len = Self::taskset_len(self.task_set.clone()).await;
-        info!("[TASK] [len={}] + {:?}", task_set_len, task);
+        let task_map_len = Self::task_map_len(self.task_map.clone()).await;
+        info!("[TASK] [len={}] + {:?}", task_map_len, task);
         let c = self.get_cache_for_cache_rule(task.rule_id).unwrap();
-        let rewrites = self.rewrite_map.get(&task.rule_id).cloned();
-        let task_clone = task.clone();
-        let upstream_url = self.resolve_task_upstream(&task_clone);
-        let task_list_ptr = self.task_set.clone();
-        // spawn an async download task
+        let rewriter = self.rewrite_map.get(&task.rule_id).cloned();
+        let spool_dir = self.config.get_spool_dir();
+        let digests = self
+            .config
+            .rules
+            .get(task.rule_id)
+            .and_then(|rule| rule.options.as_ref())
+            .and_then(|options| {
+                let max_index_size = options.get_max_index_size().ok()?;
+                options.digests.map(|source| (source, max_index_size))
+            });
+        let task_map_ptr = self.task_map.clone();
         tokio::spawn(async move {
-            let resp = util::make_request(&upstream_url, false).await;
-            match resp {
-                Ok(res) => {
-                    if res.status().is_success() {
-                        if let Some(rewrites) = rewrites {
-                            let content = res.text().await.ok();
-                            if content.is_none() {
-                                increment_counter!(metric::CNT_TASKS_BG_FAILURE);
-                                return;
-                            }
-                            let mut content = content.unwrap();
-                            content = Self::rewrite_upstream(content, &rewrites);
-                            c.write()
-                                .await
-                                .put(&task_clone.to_key(), content.into())
-                                .await;
-                        } else {
-                            let len = res.content_length();
-                            let bytestream = res.bytes_stream();
-                            c.write()
-                                .await
-                                .put(
-                                    &task_clone.to_key(),
-                                    CacheData::ByteStream(
-                                        Box::new(
-                                            bytestream.map(move |x| x.map_err(Error::RequestError)),
-                                        ),
-                                        len,
-                                    ),
-                                )
-                                .await;
+            let key = task.to_key();
+            let url = task.url.clone();
+            let download_clone = download.clone();
+            // run in a separate task so that the task map is cleaned up even if it panics
+            let fill = tokio::spawn(async move {
+                // wait for the download so that the cache is not locked meanwhile
+                let progress = download_clone.finished().await;
+                if progress.state != DownloadState::Done {
+                    return Err(Error::OtherError(format!("{:?}", progress.state)));
+                }
+                Self::check_integrity(&url, digests, &download_clone).await?;
+                let (stream, len, headers) = download_clone
+                    .subscribe()
+                    .await
+                    .ok_or_else(|| Error::OtherError("spool is not readable".to_string()))?;
+                let data = match rewriter {
+                    // spool the rewritten body, as its length is unknown until it is done
+                    Some(rewriter) => {
+                        let rewritten = InflightDownload::new(inflight::new_spool_path(&spool_dir));
+                        rewritten.start(rewriter.rewrite_stream(stream), None, headers.clone());
+                        let progress = rewritten.finished().await;
+                        if progress.state != DownloadState::Done {
+                            return Err(Error::OtherError(format!(
+                                "rewrite: {:?}",
+                                progress.state
+                            )));
                         }
-                        increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
-                    } else {
-                        warn!(
-                            "[TASK] ❌ failed to fetch upstream: {}, Task {:?}",
-                            res.status().canonical_reason().unwrap_or("unknown"),
-                            &task_clone
-                        );
-                        increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                        let (stream, _, _) = rewritten.subscribe().await.ok_or_else(|| {
+                            Error::OtherError("spool is not readable".to_string())
+                        })?;
+                        CacheData::ByteStream(Box::new(stream), Some(progress.received))
                     }
+                    None => {
+                        CacheData::ByteStream(Box::new(stream), len.or(Some(progress.received)))
+                    }
+                };
+                c.write().await.put(&key, data, headers).await
+            });
+            match fill.await {
+                Ok(Ok(())) => {
+                    increment_counter!(metric::CNT_TASKS_BG_SUCCESS);
+                }
+                Ok(Err(e)) => {
+                    increment_counter!(metric::CNT_TASKS_BG_FAILURE);
+                    warn!("[TASK] ❌ failed to fill cache: {}, Task {:?}", e, &task);
                 }
                 Err(e) => {
                     increment_counter!(metric::CNT_TASKS_BG_FAILURE);
-                    error!(
-                        "[TASK] ❌ failed to fetch upstream: {}, Task {:?}",
-                        e, &task_clone
-                    );
+                    error!("[TASK] ❌ failed to fill cache: {}, Task {:?}", e, &task);
                 }
-            };
-            Self::taskset_remove(task_list_ptr.clone(), &task_clone).await;
-            Self::taskset_len(task_list_ptr).await;
+            }
+            Self::task_map_remove(task_map_ptr.clone(), &task, &download).await;
+            Self::task_map_len(task_map_ptr).await;
         });
     }
 
+    /// get an expired entry of the task that is still retained
+    async fn get_stale(&self, task: &Task, key: &str) -> Option<StaleEntry> {
+        let cache = self.get_cache_for_cache_rule(task.rule_id)?;
+        let cache = cache.read().await;
+        cache.get_stale(key).await
+    }
+
     /// get task result from cache
-    pub async fn get(&self, task: &Task, key: &str) -> Option<CacheData> {
+    pub async fn get(&self, task: &Task, key: &str) -> Option<(CacheData, ResponseHeaders)> {
         let rule_id = task.rule_id;
         match self.get_cache_for_cache_rule(rule_id) {
             Some(cache) => cache.read().await.get(key).await,
@@ -399,18 +1262,100 @@ impl TaskManager {
         }
     }
 
-    pub fn rewrite_upstream(content: String, rewrites: &[Rewrite]) -> String {
-        let mut content = content;
-        for rewrite in rewrites {
-            content = content.replace(&rewrite.from, &rewrite.to);
+    /// The headers of a response of the task to clients. Validators of rewritten
+    /// bodies are weakened, as upstream validates other bytes.
+    pub fn served_headers(&self, task: &Task, headers: ResponseHeaders) -> ResponseHeaders {
+        if self.rewrite_map.contains_key(&task.rule_id) {
+            headers.weaken_validators()
+        } else {
+            headers
+        }
+    }
+
+    /// Materialize the placeholders in the rewritten response of the task for the
+    /// origin of the request.
+    pub fn materialize(&self, task: &Task, resp: TaskResponse, origin: &Origin) -> TaskResponse {
+        match self.rewrite_map.get(&task.rule_id) {
+            Some(rewriter) if rewriter.is_templated() => {
+                resp.rewrite(origin.materializer(self.config.public_base_url.as_deref()))
+            }
+            _ => resp,
         }
-        content
     }
 
     pub fn resolve_task_upstream(&self, task_type: &Task) -> String {
         task_type.url.clone()
     }
 
+    /// Headers of requests to the upstream of the rule, made of the forwarded
+    /// headers of the client request and the configured headers.
+    pub fn upstream_headers(&self, rule_id: RuleId, client_headers: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some((configured, forward)) = self.header_map.get(&rule_id) {
+            for name in forward {
+                for value in client_headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+            // configured headers take precedence
+            for name in configured.keys() {
+                headers.remove(name);
+            }
+            for (name, value) in configured {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+
+    /// The variant of a task of the rule requested with `client_headers`, made of
+    /// the forwarded headers which are not overridden by configured ones.
+    /// Variants are cached and downloaded apart, so that a response is only
+    /// served to clients which would get the same one from upstream.
+    pub fn variant(&self, rule_id: RuleId, client_headers: &HeaderMap) -> Option<String> {
+        let (configured, forward) = self.header_map.get(&rule_id)?;
+        let mut variant = String::new();
+        for name in forward
+            .iter()
+            .filter(|name| !configured.contains_key(*name))
+        {
+            for value in client_headers.get_all(name) {
+                variant.push_str(&format!(
+                    "{}: {}\r\n",
+                    name,
+                    String::from_utf8_lossy(value.as_bytes())
+                ));
+            }
+        }
+        (!variant.is_empty()).then_some(variant)
+    }
+
+    /// The access control of the rule, or the global one if the request matches
+    /// no rule
+    pub fn access_control(&self, rule_id: Option<RuleId>) -> Option<Arc<AccessControl>> {
+        rule_id
+            .and_then(|rule_id| self.access_map.get(&rule_id))
+            .or(self.access.as_ref())
+            .cloned()
+    }
+
+    /// The policy of the rule and its negative cache, if it has one
+    fn negative_cache(&self, rule_id: RuleId) -> Option<(&str, &Arc<NegativeCache>)> {
+        let policy = &self.config.rules.get(rule_id)?.policy;
+        let negative = self.negative_map.get(policy)?;
+        Some((policy, negative))
+    }
+
+    /// Networks of reverse proxies whose `X-Forwarded-For` is trusted
+    pub fn trusted_proxies(&self) -> &[IpNet] {
+        &self.trusted_proxies
+    }
+
+    /// The outbound HTTP client of the rule
+    pub fn get_client(&self, rule_id: RuleId) -> &util::Client {
+        self.client_map.get(&rule_id).unwrap_or(&self.client)
+    }
+
     pub fn get_cache_for_cache_rule(&self, rule_id: RuleId) -> Option<Arc<RwLock<dyn Cache>>> {
         self.rule_map.get(&rule_id).map(|tuple| tuple.0.clone())
     }
@@ -423,6 +1368,29 @@ impl TaskManager {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::settings::{Retry, Rewrite};
+
+    #[test]
+    fn rule_key_space() {
+        let task = |url: &str| Task {
+            rule_id: 0,
+            url: url.to_string(),
+            mirrors: Vec::new(),
+            variant: None,
+        };
+        let prefix = upstream_key_prefix("https://pypi.org/simple/$1");
+        assert_eq!(prefix, "https/pypi.org/simple/");
+        assert!(task("https://pypi.org/simple/numpy/")
+            .to_key()
+            .starts_with(&prefix));
+        assert!(!task("https://files.pythonhosted.org/packages/a.whl")
+            .to_key()
+            .starts_with(&prefix));
+        assert_eq!(
+            upstream_key_prefix("http://example.com/index.html"),
+            task("http://example.com/index.html").to_key()
+        );
+    }
 
     #[test]
     fn rewrite_upstream() {
@@ -430,15 +1398,312 @@ mod test {
             Rewrite {
                 from: "flower".to_string(),
                 to: "vegetable".to_string(),
+                regex: false,
             },
             Rewrite {
                 from: "cat".to_string(),
                 to: "dog".to_string(),
+                regex: false,
             },
         ];
         assert_eq!(
-            TaskManager::rewrite_upstream("flower cat".to_string(), &rewrites),
-            "vegetable dog"
+            Rewriter::new(&rewrites).unwrap().rewrite(b"flower cat"),
+            b"vegetable dog"
+        );
+    }
+
+    async fn body_bytes(resp: warp::reply::Response) -> Bytes {
+        warp::hyper::body::to_bytes(resp.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn ranged_response() {
+        let data = || TaskResponse::BytesResponse(Bytes::from("0123456789"));
+        let resp = data().into_ranged_response(None, &ResponseHeaders::default(), None);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[ACCEPT_RANGES], "bytes");
+
+        let resp = data().into_ranged_response(
+            Some("bytes=2-4"),
+            &ResponseHeaders::default(),
+            Some("text/plain"),
         );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(body_bytes(resp).await, Bytes::from("234"));
+
+        let resp =
+            data().into_ranged_response(Some("bytes=0-0,-1"), &ResponseHeaders::default(), None);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+        let len: usize = resp.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(body_bytes(resp).await.len(), len);
+
+        let resp =
+            data().into_ranged_response(Some("bytes=10-"), &ResponseHeaders::default(), None);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[CONTENT_RANGE], "bytes */10");
+    }
+
+    #[tokio::test]
+    async fn replayed_headers() {
+        let headers = ResponseHeaders::decode(
+            "content-type: application/json\r\netag: \"abc\"\r\ncontent-encoding: gzip\r\n",
+        );
+        let data = || TaskResponse::StringResponse("{}".to_string());
+        let resp = data().into_ranged_response(None, &headers, None);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()["etag"], "\"abc\"");
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+
+        // rule options take precedence over upstream
+        let resp = data().into_ranged_response(None, &headers, Some("text/plain"));
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/plain");
+
+        let resp = data().into_ranged_response(Some("bytes=0-0"), &headers, None);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()["etag"], "\"abc\"");
+    }
+
+    #[tokio::test]
+    async fn ranged_response_unknown_length() {
+        let stream = stream::iter(vec![Ok(Bytes::from("0123456789"))]);
+        let data = TaskResponse::StreamResponse(Box::pin(stream), None);
+        let resp = data.into_ranged_response(Some("bytes=2-4"), &ResponseHeaders::default(), None);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(ACCEPT_RANGES).is_none());
+        assert_eq!(body_bytes(resp).await, Bytes::from("0123456789"));
+    }
+
+    #[tokio::test]
+    async fn retry_failed_upstreams() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use warp::Filter;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let route = warp::any().map(move || {
+            let status = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::OK,
+            };
+            warp::reply::with_status("ok", status)
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut tm = TaskManager::empty();
+        tm.config.retry = Some(Retry {
+            max_retries: 1,
+            initial_backoff: 1,
+            max_backoff: 1,
+        });
+        let task = Task {
+            rule_id: 0,
+            url: format!("http://{}/file", addr),
+            mirrors: Vec::new(),
+            variant: None,
+        };
+        let (res, _) = tm
+            .request_upstream(&task, false, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        tm.config.retry.as_mut().unwrap().max_retries = 0;
+        requests.store(0, Ordering::SeqCst);
+        let (res, _) = tm
+            .request_upstream(&task, false, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn head_fails_over_to_mirrors() {
+        use warp::Filter;
+
+        let primary = warp::any()
+            .map(|| warp::reply::with_status("unavailable", StatusCode::SERVICE_UNAVAILABLE));
+        let (primary_addr, server) = warp::serve(primary).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let mirror = warp::head().map(|| "ok");
+        let (mirror_addr, server) = warp::serve(mirror).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let tm = TaskManager::empty();
+        let task = Task {
+            rule_id: 0,
+            url: format!("http://{}/file", primary_addr),
+            mirrors: vec![format!("http://{}/file", mirror_addr)],
+            variant: None,
+        };
+        let (res, url) = tm
+            .request_upstream(&task, true, HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(url, task.mirrors[0]);
+    }
+
+    #[test]
+    fn invalid_reload_keeps_config() {
+        let mut settings = Settings::default();
+        settings.sled.metadata_path = "cache/test/reload/sled".to_string();
+        settings.storages = serde_json::from_str(r#"[{"name": "mem", "config": "Mem"}]"#).unwrap();
+        settings.policies = serde_json::from_str(
+            r#"[{"name": "lru", "type": "LRU", "metadata_db": "sled", "storage": "mem", "size": "1MB"}]"#,
+        )
+        .unwrap();
+        settings.rules = serde_json::from_str(
+            r#"[{"path": "a/", "policy": "lru", "upstream": "http://example.com/"}]"#,
+        )
+        .unwrap();
+        let mut tm = TaskManager::new(settings.clone());
+        tm.refresh_config(&settings).unwrap();
+        // the sled database of the policy is shared with the new state
+        tm.refresh_config(&settings).unwrap();
+        assert_eq!(tm.rule_map.len(), 1);
+
+        let mut invalid = settings.clone();
+        invalid.rules[0].size_limit = Some("lots".to_string());
+        invalid.rules.push(invalid.rules[0].clone());
+        assert!(matches!(
+            tm.refresh_config(&invalid),
+            Err(Error::ConfigInvalid(_))
+        ));
+        invalid = settings.clone();
+        invalid.rules[0].policy = "missing".to_string();
+        assert!(tm.refresh_config(&invalid).is_err());
+        assert_eq!(tm.rule_map.len(), 1);
+        assert_eq!(tm.config.rules[0].policy, "lru");
+        assert!(tm.cache_map.contains_key("lru"));
+    }
+
+    #[tokio::test]
+    async fn variants_of_forwarded_headers() {
+        use warp::Filter;
+
+        let route = warp::header::optional::<String>("accept").map(|accept: Option<String>| {
+            match accept.as_deref() {
+                Some("application/json") => "{}",
+                _ => "<html></html>",
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut settings = Settings::default();
+        settings.sled.metadata_path = "cache/test/variant/sled".to_string();
+        settings.storages = serde_json::from_str(r#"[{"name": "mem", "config": "Mem"}]"#).unwrap();
+        settings.policies = serde_json::from_str(
+            r#"[{"name": "lru", "type": "LRU", "metadata_db": "sled", "storage": "mem", "size": "1MB"}]"#,
+        )
+        .unwrap();
+        settings.rules = serde_json::from_value(serde_json::json!([{
+            "path": "a/",
+            "policy": "lru",
+            "upstream": format!("http://{}/", addr),
+            "forward_headers": ["accept"],
+        }]))
+        .unwrap();
+        let mut tm = TaskManager::new(settings.clone());
+        tm.refresh_config(&settings).unwrap();
+
+        let get = |accept: &'static str| {
+            let tm = tm.clone();
+            async move {
+                let mut client_headers = HeaderMap::new();
+                client_headers.insert("accept", HeaderValue::from_static(accept));
+                let task = Task {
+                    rule_id: 0,
+                    url: format!("http://{}/index", addr),
+                    mirrors: Vec::new(),
+                    variant: tm.variant(0, &client_headers),
+                };
+                let (resp, _) = tm.resolve_task(&task, &client_headers).await;
+                let (data, headers) = resp.unwrap();
+                body_bytes(data.into_ranged_response(None, &headers, None)).await
+            }
+        };
+        let (html, json) = tokio::join!(get("text/html"), get("application/json"));
+        assert_eq!(html, Bytes::from("<html></html>"));
+        assert_eq!(json, Bytes::from("{}"));
+        assert_eq!(get("application/json").await, Bytes::from("{}"));
+
+        let mut client_headers = HeaderMap::new();
+        assert_eq!(tm.variant(0, &client_headers), None);
+        client_headers.insert("accept", HeaderValue::from_static("text/html"));
+        assert_ne!(
+            tm.variant(0, &client_headers),
+            tm.variant(1, &client_headers)
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_large_indexes() {
+        let spool_dir = PathBuf::from("cache/test/index_spool");
+        std::fs::create_dir_all(&spool_dir).unwrap();
+        let index = |name: &str| {
+            let download = InflightDownload::new(inflight::new_spool_path(&spool_dir));
+            let page = format!(
+                r#"<a href="/packages/{}.tar.gz#md5=d41d8cd98f00b204e9800998ecf8427e">{}</a>"#,
+                name, name
+            );
+            let chunks: Vec<Result<Bytes>> = vec![Ok(page.into())];
+            download.start(stream::iter(chunks), None, ResponseHeaders::default());
+            download
+        };
+        let url = "https://pypi.org/simple/large/";
+        let download = index("large");
+        download.finished().await;
+        let digests = Some((DigestSource::PyPI, 16));
+        TaskManager::check_integrity(url, digests, &download)
+            .await
+            .unwrap();
+        assert!(integrity::expected_digest("https://pypi.org/packages/large.tar.gz").is_none());
+
+        let url = "https://pypi.org/simple/small/";
+        let download = index("small");
+        download.finished().await;
+        let digests = Some((DigestSource::PyPI, 1024));
+        TaskManager::check_integrity(url, digests, &download)
+            .await
+            .unwrap();
+        assert!(integrity::expected_digest("https://pypi.org/packages/small.tar.gz").is_some());
+    }
+
+    #[test]
+    fn forwarded_and_configured_headers() {
+        let mut tm = TaskManager::empty();
+        let mut configured = HeaderMap::new();
+        configured.insert("user-agent", HeaderValue::from_static("mirror"));
+        configured.insert("x-token", HeaderValue::from_static("secret"));
+        let forward = vec![
+            HeaderName::from_static("accept"),
+            HeaderName::from_static("user-agent"),
+        ];
+        tm.header_map.insert(0, (configured, forward));
+        let mut client_headers = HeaderMap::new();
+        client_headers.insert("accept", HeaderValue::from_static("text/html"));
+        client_headers.insert("user-agent", HeaderValue::from_static("pip"));
+        client_headers.insert("cookie", HeaderValue::from_static("a=b"));
+
+        let headers = tm.upstream_headers(0, &client_headers);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["accept"], "text/html");
+        assert_eq!(headers["user-agent"], "mirror");
+        assert_eq!(headers["x-token"], "secret");
+        assert!(tm.upstream_headers(1, &client_headers).is_empty());
     }
 }
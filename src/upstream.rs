@@ -0,0 +1,192 @@
+//! Health of upstreams, for rules with several upstreams to fail over between.
+//!
+//! Upstreams are identified by their origin, e.g. `https://pypi.org`, so that
+//! rules sharing an upstream share its health. An upstream is marked unhealthy
+//! after consecutive failures, and probed in the background until it responds.
+//! Unhealthy upstreams are only tried after all healthy ones.
+use crate::metric;
+use crate::settings::{UpstreamHealth, UpstreamOrder};
+use crate::task::RuleId;
+use crate::util;
+
+use metrics::{gauge, increment_counter};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+struct State {
+    consecutive_failures: u32,
+    healthy: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 0,
+            healthy: true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Upstreams {
+    config: RwLock<UpstreamHealth>,
+    states: Mutex<HashMap<String, State>>,
+    /// Next upstream to start from, for round-robin rules
+    next: Mutex<HashMap<RuleId, usize>>,
+}
+
+/// Origin of an upstream URL, which labels its metrics
+pub fn origin(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => url.origin().ascii_serialization(),
+        Err(_) => url.to_string(),
+    }
+}
+
+impl Upstreams {
+    pub fn set_config(&self, config: UpstreamHealth) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn is_healthy(&self, url: &str) -> bool {
+        self.states
+            .lock()
+            .unwrap()
+            .get(&origin(url))
+            .is_none_or(|state| state.healthy)
+    }
+
+    /// Order the upstream URLs of a request to the rule. `urls` are in priority
+    /// order. Healthy upstreams come first.
+    pub fn order(&self, rule_id: RuleId, urls: &[String], order: UpstreamOrder) -> Vec<String> {
+        let start = match order {
+            UpstreamOrder::Priority => 0,
+            UpstreamOrder::RoundRobin => {
+                let mut next = self.next.lock().unwrap();
+                let next = next.entry(rule_id).or_default();
+                let start = *next % urls.len().max(1);
+                *next = start + 1;
+                start
+            }
+        };
+        let rotated = urls[start..].iter().chain(&urls[..start]);
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) =
+            rotated.cloned().partition(|url| self.is_healthy(url));
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+
+    pub fn report_success(&self, url: &str) {
+        let origin = origin(url);
+        increment_counter!(metric::CNT_UPSTREAM_REQUESTS, "upstream" => origin.clone());
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(origin.clone()).or_default();
+        state.consecutive_failures = 0;
+        state.healthy = true;
+        gauge!(metric::GAUGE_UPSTREAM_HEALTHY, 1.0, "upstream" => origin);
+    }
+
+    /// Report a failed request to `url`. The upstream is probed with `url` once
+    /// it is marked unhealthy.
+    pub fn report_failure(self: &Arc<Self>, url: &str, client: &util::Client) {
+        let origin = origin(url);
+        increment_counter!(metric::CNT_UPSTREAM_REQUESTS, "upstream" => origin.clone());
+        increment_counter!(metric::CNT_UPSTREAM_FAILURES, "upstream" => origin.clone());
+        let threshold = self.config.read().unwrap().failure_threshold;
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(origin.clone()).or_default();
+        state.consecutive_failures += 1;
+        if state.healthy && state.consecutive_failures >= threshold {
+            state.healthy = false;
+            warn!(
+                "upstream {} is unhealthy after {} consecutive failures",
+                origin, state.consecutive_failures
+            );
+            gauge!(metric::GAUGE_UPSTREAM_HEALTHY, 0.0, "upstream" => origin);
+            self.spawn_probe(url.to_string(), client.clone());
+        }
+    }
+
+    /// Probe an unhealthy upstream with HEAD requests until it responds without
+    /// a server error.
+    fn spawn_probe(self: &Arc<Self>, url: String, client: util::Client) {
+        let upstreams = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let interval = upstreams.config.read().unwrap().probe_interval;
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                if upstreams.is_healthy(&url) {
+                    // recovered by a request meanwhile
+                    return;
+                }
+                match client.request(&url, true, Default::default()).await {
+                    Ok(res) if !res.status().is_server_error() => {
+                        info!("upstream {} is healthy again", origin(&url));
+                        upstreams.report_success(&url);
+                        return;
+                    }
+                    Ok(res) => debug!("probe of {} failed: {}", url, res.status()),
+                    Err(e) => debug!("probe of {} failed: {}", url, e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn urls() -> Vec<String> {
+        vec![
+            "https://a.example/x".to_string(),
+            "https://b.example/x".to_string(),
+            "https://c.example/x".to_string(),
+        ]
+    }
+
+    #[test]
+    fn upstream_origin() {
+        assert_eq!(origin("https://pypi.org/simple/foo/"), "https://pypi.org");
+        assert_eq!(origin("http://localhost:8080/a"), "http://localhost:8080");
+    }
+
+    #[test]
+    fn round_robin() {
+        let upstreams = Upstreams::default();
+        let order =
+            |rule_id| upstreams.order(rule_id, &urls(), UpstreamOrder::RoundRobin)[0].clone();
+        assert_eq!(order(0), "https://a.example/x");
+        assert_eq!(order(0), "https://b.example/x");
+        assert_eq!(order(1), "https://a.example/x");
+        assert_eq!(order(0), "https://c.example/x");
+        assert_eq!(order(0), "https://a.example/x");
+        assert_eq!(upstreams.order(0, &urls(), UpstreamOrder::Priority), urls());
+    }
+
+    #[tokio::test]
+    async fn failover_to_healthy() {
+        let upstreams = Arc::new(Upstreams::default());
+        upstreams.set_config(UpstreamHealth {
+            failure_threshold: 2,
+            probe_interval: 3600,
+        });
+        let client = util::Client::default();
+        upstreams.report_failure("https://a.example/y", &client);
+        assert!(upstreams.is_healthy("https://a.example/x"));
+        upstreams.report_failure("https://a.example/y", &client);
+        assert!(!upstreams.is_healthy("https://a.example/x"));
+        // unhealthy upstreams are tried last
+        assert_eq!(
+            upstreams.order(0, &urls(), UpstreamOrder::Priority),
+            vec![
+                "https://b.example/x".to_string(),
+                "https://c.example/x".to_string(),
+                "https://a.example/x".to_string(),
+            ]
+        );
+        upstreams.report_success("https://a.example/z");
+        assert_eq!(upstreams.order(0, &urls(), UpstreamOrder::Priority), urls());
+    }
+}
@@ -1,10 +1,17 @@
 use crate::error::Error;
 use crate::error::Result;
 use crate::metric;
+use crate::settings;
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
 use metrics::increment_counter;
-use reqwest::ClientBuilder;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, ClientBuilder, Proxy};
 use sled::IVec;
 use std::convert::TryInto;
+use std::pin::Pin;
+use std::time::Duration;
 
 pub fn now() -> i64 {
     chrono::offset::Local::now().timestamp()
@@ -14,26 +21,190 @@ pub fn now_nanos() -> i64 {
     chrono::offset::Local::now().timestamp_nanos_opt().unwrap()
 }
 
-pub async fn make_request(url: &str, head: bool) -> Result<reqwest::Response> {
-    increment_counter!(metric::CNT_OUT_REQUESTS);
-    let client = ClientBuilder::new().build().unwrap();
-    let req = if !head {
-        client.get(url)
-    } else {
-        client.head(url)
-    };
-    let resp = req.send().await;
-    match resp {
-        Ok(res) => {
-            debug!("outbound request: {:?} {:?}", res.status(), res.headers());
-            increment_counter!(metric::CNT_OUT_REQUESTS_SUCCESS);
-            Ok(res)
+/// Default `User-Agent` of outbound requests
+const USER_AGENT: &str = concat!("mirror-cache/", env!("CARGO_PKG_VERSION"));
+
+/// Client request headers which must not be forwarded to upstream, as they are
+/// handled by the mirror itself
+const UNFORWARDABLE_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "range",
+    "if-range",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "if-unmodified-since",
+];
+
+/// Resolve the configured headers of requests to upstream. Values with secrets
+/// are marked sensitive.
+pub fn request_headers(headers: &[settings::RequestHeader]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let invalid = |reason: String| {
+            Error::ConfigInvalid(format!("invalid header {}: {}", header.name, reason))
+        };
+        let name =
+            HeaderName::from_bytes(header.name.as_bytes()).map_err(|e| invalid(e.to_string()))?;
+        let secret = match (&header.env, &header.file) {
+            (Some(_), Some(_)) => return Err(invalid("both env and file are set".to_string())),
+            (Some(var), None) => Some(
+                std::env::var(var)
+                    .map_err(|e| invalid(format!("environment variable {}: {}", var, e)))?,
+            ),
+            (None, Some(path)) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| invalid(format!("file {}: {}", path, e)))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string(),
+            ),
+            (None, None) => None,
+        };
+        let template = header.value.as_deref().unwrap_or("{secret}");
+        let mut value = match &secret {
+            Some(secret) => HeaderValue::from_str(&template.replace("{secret}", secret)),
+            None => HeaderValue::from_str(template),
+        }
+        .map_err(|e| invalid(e.to_string()))?;
+        value.set_sensitive(secret.is_some());
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// Parse the names of client request headers forwarded to upstream.
+pub fn forwarded_header_names(names: &[String]) -> Result<Vec<HeaderName>> {
+    names
+        .iter()
+        .map(|name| {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| Error::ConfigInvalid(format!("invalid header {}: {}", name, e)))?;
+            if UNFORWARDABLE_HEADERS.contains(&header.as_str()) {
+                return Err(Error::ConfigInvalid(format!(
+                    "header {} cannot be forwarded",
+                    name
+                )));
+            }
+            Ok(header)
+        })
+        .collect()
+}
+
+/// An outbound HTTP client. Clones share the same connection pool.
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    /// Maximum time to wait for the response headers or the next chunk of the body
+    read_timeout: Option<Duration>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new(&settings::HttpClient::default()).unwrap()
+    }
+}
+
+impl Client {
+    pub fn new(config: &settings::HttpClient) -> Result<Self> {
+        let mut builder = ClientBuilder::new().user_agent(
+            config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| USER_AGENT.to_string()),
+        );
+        if let Some(secs) = config.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(max) = config.max_idle_connections {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(max) = config.max_redirects {
+            builder = builder.redirect(match max {
+                0 => Policy::none(),
+                max => Policy::limited(max),
+            });
+        }
+        if let Some(proxy) = &config.proxy {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| Error::ConfigInvalid(format!("invalid proxy {}: {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &config.ca_bundle {
+            let pem = std::fs::read(path)?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| Error::ConfigInvalid(format!("invalid CA bundle {}: {}", path, e)))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
         }
-        Err(e) => {
-            increment_counter!(metric::CNT_OUT_REQUESTS_FAILURE);
-            Err(Error::RequestError(e))
+        Ok(Self {
+            inner: builder.build().map_err(Error::RequestError)?,
+            read_timeout: config.read_timeout.map(Duration::from_secs),
+        })
+    }
+
+    /// Send a GET or HEAD request to `url` with additional `headers`, e.g. the
+    /// validators of a conditional request.
+    pub async fn request(
+        &self,
+        url: &str,
+        head: bool,
+        headers: HeaderMap,
+    ) -> Result<reqwest::Response> {
+        increment_counter!(metric::CNT_OUT_REQUESTS);
+        let req = if !head {
+            self.inner.get(url)
+        } else {
+            self.inner.head(url)
+        };
+        let req = req.headers(headers).send();
+        let resp = match self.read_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, req).await {
+                Ok(resp) => resp.map_err(Error::RequestError),
+                Err(_) => Err(Error::OtherError(format!("timed out waiting for {}", url))),
+            },
+            None => req.await.map_err(Error::RequestError),
+        };
+        match resp {
+            Ok(res) => {
+                debug!("outbound request: {:?} {:?}", res.status(), res.headers());
+                increment_counter!(metric::CNT_OUT_REQUESTS_SUCCESS);
+                Ok(res)
+            }
+            Err(e) => {
+                increment_counter!(metric::CNT_OUT_REQUESTS_FAILURE);
+                Err(e)
+            }
         }
     }
+
+    /// The body of a response, which fails if no data arrives in the read timeout.
+    pub fn body_stream(
+        &self,
+        res: reqwest::Response,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>> {
+        let stream = res.bytes_stream().map(|x| x.map_err(Error::RequestError));
+        let timeout = match self.read_timeout {
+            Some(timeout) => timeout,
+            None => return Box::pin(stream),
+        };
+        Box::pin(stream::unfold(Some(stream), move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout(timeout, stream.next()).await {
+                Ok(Some(item)) => Some((item, Some(stream))),
+                Ok(None) => None,
+                Err(_) => Some((
+                    Err(Error::OtherError(
+                        "timed out reading response body".to_string(),
+                    )),
+                    None,
+                )),
+            }
+        }))
+    }
 }
 
 pub fn sleep_ms(ms: u64) {
@@ -66,4 +237,84 @@ mod tests {
         let n: u64 = 233;
         assert_eq!(ivec_to_u64(&(&u64_to_array(n)).into()), n);
     }
+
+    #[tokio::test]
+    async fn body_read_timeout() {
+        let client = Client::new(&settings::HttpClient {
+            read_timeout: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let body = stream::iter(vec![Ok::<_, std::io::Error>("a")]).chain(stream::pending());
+        let res = warp::http::Response::new(reqwest::Body::wrap_stream(body));
+        let mut stream = client.body_stream(res.into());
+        assert_eq!(stream.next().await.unwrap().unwrap(), "a");
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn invalid_client_config() {
+        let config = settings::HttpClient {
+            proxy: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(Client::new(&config).is_err());
+        let config = settings::HttpClient {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(Client::new(&config).is_err());
+    }
+
+    #[test]
+    fn socks_proxy_client() {
+        for proxy in ["socks5://127.0.0.1:1080", "socks5h://127.0.0.1:1080"] {
+            let config = settings::HttpClient {
+                proxy: Some(proxy.to_string()),
+                ..Default::default()
+            };
+            assert!(Client::new(&config).is_ok());
+        }
+    }
+
+    #[test]
+    fn resolve_request_headers() {
+        let path = std::env::temp_dir().join("mirror-cache-test-secret");
+        std::fs::write(&path, "s3cret\n").unwrap();
+        std::env::set_var("MIRROR_CACHE_TEST_TOKEN", "t0ken");
+        let header = |name: &str, value: Option<&str>, env: Option<&str>, file: Option<&str>| {
+            settings::RequestHeader {
+                name: name.to_string(),
+                value: value.map(String::from),
+                env: env.map(String::from),
+                file: file.map(String::from),
+            }
+        };
+        let headers = request_headers(&[
+            header("X-Plain", Some("plain"), None, None),
+            header(
+                "Authorization",
+                Some("Bearer {secret}"),
+                Some("MIRROR_CACHE_TEST_TOKEN"),
+                None,
+            ),
+            header("X-Api-Key", None, None, path.to_str()),
+        ])
+        .unwrap();
+        assert_eq!(headers["x-plain"], "plain");
+        assert_eq!(headers["authorization"], "Bearer t0ken");
+        assert!(headers["authorization"].is_sensitive());
+        assert_eq!(headers["x-api-key"], "s3cret");
+        assert!(request_headers(&[header(
+            "X-Missing",
+            None,
+            Some("MIRROR_CACHE_NO_SUCH_VAR"),
+            None
+        )])
+        .is_err());
+
+        assert!(forwarded_header_names(&["Accept".to_string()]).is_ok());
+        assert!(forwarded_header_names(&["Range".to_string()]).is_err());
+    }
 }